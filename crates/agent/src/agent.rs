@@ -1,33 +1,55 @@
 use backupforge_common::{
-    types::{BackupJob, BackupSource, BackupStats, Snapshot},
+    types::{BackupJob, BackupSource, BackupStats, RetentionPolicy, Snapshot, SnapshotId},
     Error, Result,
 };
-use backupforge_core::{BackupConfig, BackupEngine};
+use backupforge_core::{
+    check::ProblemKind, repository::CONFIG_KEY, BackupConfig, BackupEngine, CheckOptions,
+    CheckReport, LockKind, PruneReport, RepairOptions, RepairReport, Repository, RepositoryLock,
+    RestoreOptions, RetentionDecision, SnapshotManifest,
+};
+use chrono::Duration;
 use backupforge_storage::{StorageConfig, StorageManager};
+use std::future::Future;
 use std::path::Path;
 use std::sync::Arc;
 
-use crate::{FilesystemBackup, ProxmoxBackup, ProxmoxConfig, SshBackup};
+use crate::{FilesystemBackup, SshBackup};
 
 /// Main backup agent that coordinates all backup operations
 pub struct BackupAgent {
     engine: Arc<BackupEngine>,
     storage: Arc<StorageManager>,
+    repository: Repository,
     fs_backup: FilesystemBackup,
     ssh_backup: SshBackup,
 }
 
 impl BackupAgent {
-    pub async fn new(backup_config: BackupConfig, storage_config: StorageConfig) -> Result<Self> {
-        let engine = Arc::new(BackupEngine::new(backup_config));
+    pub async fn new(
+        mut backup_config: BackupConfig,
+        storage_config: StorageConfig,
+    ) -> Result<Self> {
         let storage = Arc::new(StorageManager::from_config(storage_config).await?);
 
-        let fs_backup = FilesystemBackup::new(engine.clone(), storage.clone());
+        // Refuse to touch anything that is not an initialized, compatible repository
+        let repository = Repository::open(storage.backend()).await?;
+        repository.validate(&backup_config)?;
+
+        // Chunk boundaries must match the repository or deduplication breaks
+        backup_config.chunking_strategy = repository.config().chunking.clone();
+        // Likewise chunk IDs, which are keyed in encrypted repositories
+        backup_config.chunk_id_key =
+            repository.chunk_id_key(backup_config.encryption_key.as_ref());
+
+        let engine = Arc::new(BackupEngine::open(backup_config, repository.storage()).await?);
+
+        let fs_backup = FilesystemBackup::new(engine.clone());
         let ssh_backup = SshBackup::new(engine.clone(), storage.clone());
 
         Ok(Self {
             engine,
             storage,
+            repository,
             fs_backup,
             ssh_backup,
         })
@@ -35,12 +57,21 @@ impl BackupAgent {
 
     /// Execute a backup job
     pub async fn run_job(&self, job: &BackupJob) -> Result<Snapshot> {
+        self.locked(LockKind::Shared, "backup", self.backup_source(job))
+            .await
+    }
+
+    async fn backup_source(&self, job: &BackupJob) -> Result<Snapshot> {
         tracing::info!("Running backup job: {}", job.name);
 
         match &job.source {
-            BackupSource::LocalPath { path, excludes } => {
+            BackupSource::LocalPath {
+                path,
+                excludes,
+                exclude_options,
+            } => {
                 self.fs_backup
-                    .backup_directory(Path::new(path), excludes)
+                    .backup_directory(Path::new(path), excludes, exclude_options)
                     .await
             }
 
@@ -77,27 +108,160 @@ impl BackupAgent {
                     "Proxmox LXC backup not fully implemented".to_string(),
                 ))
             }
+
+            other => Err(Error::Unknown(format!(
+                "Backup source not supported by the agent yet: {:?}",
+                other
+            ))),
         }
     }
 
-    /// Get backup statistics
+    /// Statistics of the most recent backup
     pub async fn get_stats(&self) -> Result<BackupStats> {
-        let dedup_stats = self.engine.dedup_stats()?;
-        let storage_stats = self.storage.stats().await?;
-
-        Ok(BackupStats {
-            total_files: 0, // Would track this
-            total_bytes: storage_stats.total_bytes,
-            new_chunks: 0,
-            reused_chunks: 0,
-            compressed_bytes: 0,
-            duration_seconds: 0,
-        })
+        let snapshots = self.engine.list_snapshots().await?;
+
+        Ok(snapshots
+            .into_iter()
+            .rev()
+            .find_map(|s| s.stats)
+            .unwrap_or_default())
+    }
+
+    /// List all snapshots in the repository, oldest first
+    pub async fn list_snapshots(&self) -> Result<Vec<Snapshot>> {
+        self.engine.list_snapshots().await
+    }
+
+    /// Resolve "latest", a full snapshot ID or a unique ID prefix
+    pub async fn find_snapshot(&self, id: &str) -> Result<Snapshot> {
+        self.engine.manifests().find(id).await
+    }
+
+    /// Load a snapshot's manifest
+    pub async fn load_snapshot(&self, id: &SnapshotId) -> Result<SnapshotManifest> {
+        self.engine.load_snapshot(id).await
     }
 
     /// Restore a snapshot
-    pub async fn restore_snapshot(&self, snapshot: &Snapshot, target_path: &Path) -> Result<()> {
-        self.fs_backup.restore_snapshot(snapshot, target_path).await
+    pub async fn restore_snapshot(
+        &self,
+        id: &SnapshotId,
+        target_path: &Path,
+        options: &RestoreOptions,
+    ) -> Result<()> {
+        self.locked(LockKind::Shared, "restore", async {
+            let manifest = self.engine.load_snapshot(id).await?;
+            self.fs_backup
+                .restore_snapshot(&manifest, target_path, options)
+                .await
+        })
+        .await
+    }
+
+    /// Remove the snapshots `policy` does not keep, per source path or only
+    /// for `source_path`; a dry run only reports the decisions
+    pub async fn forget(
+        &self,
+        policy: &RetentionPolicy,
+        source_path: Option<&str>,
+        dry_run: bool,
+    ) -> Result<Vec<RetentionDecision>> {
+        self.locked(
+            LockKind::Exclusive,
+            "forget",
+            self.engine.forget(policy, source_path, dry_run),
+        )
+        .await
+    }
+
+    /// Apply a job's retention policy to the snapshots of its source
+    pub async fn forget_job(&self, job: &BackupJob, dry_run: bool) -> Result<Vec<RetentionDecision>> {
+        match &job.source {
+            BackupSource::LocalPath { path, .. } => {
+                self.forget(&job.retention_policy(), Some(path), dry_run)
+                    .await
+            }
+            other => Err(Error::Unknown(format!(
+                "Retention not supported for backup source yet: {:?}",
+                other
+            ))),
+        }
+    }
+
+    /// Remove one snapshot, leaving its chunks to the next prune
+    pub async fn delete_snapshot(&self, id: &SnapshotId) -> Result<()> {
+        self.locked(LockKind::Exclusive, "forget", self.engine.delete_snapshot(id))
+            .await
+    }
+
+    /// Delete chunks no snapshot references that are older than `grace_period`
+    ///
+    /// Holds an exclusive lock, so no backup is running meanwhile; agents of
+    /// other processes reload their indexes once they next take a lock, so
+    /// they store the deleted chunks again instead of reusing them.
+    pub async fn prune(&self, grace_period: Duration, dry_run: bool) -> Result<PruneReport> {
+        self.locked(
+            LockKind::Exclusive,
+            "prune",
+            self.engine.prune(grace_period, dry_run),
+        )
+        .await
+    }
+
+    /// Check the repository config, snapshot manifests and referenced chunks
+    pub async fn check(&self, options: &CheckOptions) -> Result<CheckReport> {
+        let config_problems = self.repository.check_config().await;
+        let mut report = self
+            .locked(LockKind::Shared, "check", self.engine.check(options))
+            .await?;
+
+        let found = std::mem::take(&mut report.problems);
+        for problem in config_problems {
+            report.add_problem(ProblemKind::Config, CONFIG_KEY, problem);
+        }
+        report.problems.extend(found);
+
+        Ok(report)
+    }
+
+    /// Rebuild the storage index and salvage snapshots that lost chunks
+    ///
+    /// Holds an exclusive lock unless it is a dry run, since indexes and
+    /// manifests are rewritten.
+    pub async fn repair(&self, options: &RepairOptions) -> Result<RepairReport> {
+        let kind = if options.dry_run {
+            LockKind::Shared
+        } else {
+            LockKind::Exclusive
+        };
+        self.locked(kind, "repair", self.engine.repair(options))
+            .await
+    }
+
+    /// Run `task` holding a repository lock of `kind`, aborting it if the
+    /// lock is lost
+    async fn locked<T>(
+        &self,
+        kind: LockKind,
+        operation: &str,
+        task: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let lock = RepositoryLock::acquire(self.repository.storage(), kind, operation).await?;
+        let run = async {
+            // Other processes may have changed the repository while it was unlocked
+            self.engine.reload().await?;
+            task.await
+        };
+        // Going on without the lock would let conflicting operations start
+        let result = tokio::select! {
+            result = run => result,
+            lost = lock.lost() => Err(lost),
+        };
+        let released = lock.release().await;
+
+        let value = result?;
+        released?;
+        Ok(value)
     }
 
     /// Get the backup engine
@@ -109,11 +273,18 @@ impl BackupAgent {
     pub fn storage(&self) -> Arc<StorageManager> {
         self.storage.clone()
     }
+
+    /// Get the repository
+    pub fn repository(&self) -> &Repository {
+        &self.repository
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use backupforge_common::types::ExcludeOptions;
+    use backupforge_core::{ChunkingStrategy, CompressionAlgorithm, ReadData, RepositoryConfig};
     use tempfile::TempDir;
     use tokio::fs;
 
@@ -126,6 +297,20 @@ mod tests {
             path: temp_dir.path().to_string_lossy().to_string(),
         };
 
+        // An uninitialized repository is refused
+        let result = BackupAgent::new(backup_config.clone(), storage_config.clone()).await;
+        assert!(matches!(result, Err(Error::RepositoryNotInitialized(_))));
+
+        let storage = StorageManager::from_config(storage_config.clone())
+            .await
+            .unwrap();
+        let repo_config = RepositoryConfig::new(
+            ChunkingStrategy::default(),
+            CompressionAlgorithm::default(),
+            None,
+        );
+        Repository::init(storage.backend(), repo_config).await.unwrap();
+
         let agent = BackupAgent::new(backup_config, storage_config)
             .await
             .unwrap();
@@ -133,4 +318,73 @@ mod tests {
         let stats = agent.get_stats().await.unwrap();
         assert_eq!(stats.total_bytes, 0);
     }
+
+    #[tokio::test]
+    async fn test_backup_after_prune_by_another_agent() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage_config = StorageConfig::Local {
+            path: temp_dir.path().join("repo").to_string_lossy().to_string(),
+        };
+        let storage = StorageManager::from_config(storage_config.clone())
+            .await
+            .unwrap();
+        let repo_config = RepositoryConfig::new(
+            ChunkingStrategy::default(),
+            CompressionAlgorithm::default(),
+            None,
+        );
+        Repository::init(storage.backend(), repo_config).await.unwrap();
+
+        let source = temp_dir.path().join("src");
+        fs::create_dir(&source).await.unwrap();
+        fs::write(source.join("file.txt"), "contents both backups share")
+            .await
+            .unwrap();
+        let job = BackupJob {
+            id: uuid::Uuid::new_v4(),
+            name: "test".to_string(),
+            source: BackupSource::LocalPath {
+                path: source.to_string_lossy().to_string(),
+                excludes: Vec::new(),
+                exclude_options: ExcludeOptions::default(),
+            },
+            destination: String::new(),
+            schedule: None,
+            retention_days: 30,
+            enabled: true,
+            encryption_enabled: false,
+            compression_level: 3,
+            retention: None,
+        };
+
+        // Both agents are open before the other one prunes, as two
+        // processes would be
+        let agent = BackupAgent::new(BackupConfig::default(), storage_config.clone())
+            .await
+            .unwrap();
+        let other = BackupAgent::new(BackupConfig::default(), storage_config)
+            .await
+            .unwrap();
+        agent.run_job(&job).await.unwrap();
+
+        let snapshot = other.run_job(&job).await.unwrap();
+        for s in other.list_snapshots().await.unwrap() {
+            other.delete_snapshot(&s.id).await.unwrap();
+        }
+        let report = other.prune(Duration::zero(), false).await.unwrap();
+        assert!(report.sweep.removed_chunks > 0);
+
+        // The chunks the first agent still knows of are gone, so it must
+        // store them again
+        let snapshot_after = agent.run_job(&job).await.unwrap();
+        assert_ne!(snapshot_after.id, snapshot.id);
+        let report = agent
+            .check(&CheckOptions {
+                read_data: ReadData::All,
+                snapshots: Vec::new(),
+            })
+            .await
+            .unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+    }
 }
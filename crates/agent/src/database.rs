@@ -205,9 +205,10 @@ impl DatabaseBackup {
 
         let dump_path = format!("/tmp/mongo_{}_{}", database, chrono::Utc::now().timestamp());
 
+        let port_str = port.to_string();
         let mut args = vec![
             "--host", host,
-            "--port", &port.to_string(),
+            "--port", &port_str,
             "--db", database,
             "--out", &dump_path,
         ];
@@ -255,7 +256,8 @@ impl DatabaseBackup {
         // Redis backup is typically done by copying the RDB or AOF file
         // Or using BGSAVE command
 
-        let mut args = vec!["-h", host, "-p", &port.to_string(), "BGSAVE"];
+        let port_str = port.to_string();
+        let mut args = vec!["-h", host, "-p", &port_str, "BGSAVE"];
 
         let password_str;
         if let Some(pass) = password {
@@ -174,13 +174,14 @@ pub struct ContainerInfo {
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_docker_backup_creation() {
+    #[tokio::test]
+    async fn test_docker_backup_creation() {
         let config = DockerConfig { docker_host: None };
-        let engine = Arc::new(BackupEngine::new(Default::default()));
         let storage_config = backupforge_storage::StorageConfig::Local {
             path: "/tmp/test".to_string(),
         };
+        let storage = Arc::new(StorageManager::from_config(storage_config).await.unwrap());
+        let engine = Arc::new(BackupEngine::new(Default::default(), storage.backend()));
         // Would need async runtime to properly test
     }
 }
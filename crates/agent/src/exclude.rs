@@ -0,0 +1,191 @@
+use backupforge_common::{types::ExcludeOptions, Error, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::{DirEntry, Walk, WalkBuilder};
+use std::path::Path;
+
+/// Per-directory file of exclude patterns, applying to its directory and below
+pub const IGNORE_FILE: &str = ".backupforge-ignore";
+
+/// Marks a directory as a cache, see https://bford.info/cachedir/
+const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Walk `source_path` without following symlinks, leaving out what the
+/// patterns and options exclude
+///
+/// Patterns have gitignore semantics: a leading `/` anchors a pattern to the
+/// source directory, `**` matches any number of directories, a trailing `/`
+/// matches only directories and `!` re-includes what an earlier pattern
+/// excluded. An excluded directory is not descended into, so nothing below it
+/// can be re-included.
+pub fn walker(source_path: &Path, excludes: &[String], options: &ExcludeOptions) -> Result<Walk> {
+    let patterns = compile(source_path, excludes, &options.exclude_files)?;
+    let exclude_caches = options.exclude_caches;
+
+    let mut builder = WalkBuilder::new(source_path);
+    builder
+        .standard_filters(false)
+        .follow_links(false)
+        .same_file_system(options.one_file_system)
+        .max_filesize(options.exclude_larger_than)
+        .add_custom_ignore_filename(IGNORE_FILE)
+        .filter_entry(move |entry| !is_excluded(entry, &patterns, exclude_caches));
+
+    Ok(builder.build())
+}
+
+/// Build the matcher for the command line patterns and exclude files
+fn compile(source_path: &Path, excludes: &[String], exclude_files: &[String]) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(source_path);
+
+    for file in exclude_files {
+        if let Some(e) = builder.add(file) {
+            return Err(Error::InvalidConfig(format!(
+                "Invalid exclude file {}: {}",
+                file, e
+            )));
+        }
+    }
+    for pattern in excludes {
+        builder.add_line(None, pattern).map_err(|e| {
+            Error::InvalidConfig(format!("Invalid exclude pattern {:?}: {}", pattern, e))
+        })?;
+    }
+
+    builder
+        .build()
+        .map_err(|e| Error::InvalidConfig(format!("Invalid exclude patterns: {}", e)))
+}
+
+fn is_excluded(entry: &DirEntry, patterns: &Gitignore, exclude_caches: bool) -> bool {
+    // The source itself is always backed up
+    if entry.depth() == 0 {
+        return false;
+    }
+
+    let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+    if patterns.matched(entry.path(), is_dir).is_ignore() {
+        return true;
+    }
+
+    exclude_caches && is_dir && is_cache(entry.path())
+}
+
+/// Whether `dir` holds a cache directory tag with a valid signature
+fn is_cache(dir: &Path) -> bool {
+    std::fs::read(dir.join(CACHEDIR_TAG))
+        .map(|tag| tag.starts_with(CACHEDIR_SIGNATURE))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn walk(source: &Path, excludes: &[&str], options: &ExcludeOptions) -> Vec<String> {
+        let excludes: Vec<String> = excludes.iter().map(|s| s.to_string()).collect();
+        let mut paths: Vec<String> = walker(source, &excludes, options)
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.depth() > 0)
+            .map(|entry| {
+                let relative = entry.path().strip_prefix(source).unwrap();
+                relative.to_string_lossy().replace('\\', "/")
+            })
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    fn tree(temp_dir: &TempDir, files: &[&str]) -> std::path::PathBuf {
+        let source = temp_dir.path().join("source");
+        for file in files {
+            let path = source.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"data").unwrap();
+        }
+        source
+    }
+
+    #[test]
+    fn test_gitignore_patterns() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = tree(
+            &temp_dir,
+            &[
+                "tmp/a",
+                "templates/b",
+                "logs/x.log",
+                "logs/keep.log",
+                "deep/er/x.log",
+                "build/out",
+                "src/build",
+            ],
+        );
+        let options = ExcludeOptions::default();
+
+        // Not a substring match: "templates" stays
+        assert_eq!(
+            walk(&source, &["tmp"], &options),
+            [
+                "build", "build/out", "deep", "deep/er", "deep/er/x.log", "logs",
+                "logs/keep.log", "logs/x.log", "src", "src/build", "templates", "templates/b",
+            ]
+        );
+
+        // Anchored, recursive, negated and directory-only patterns
+        assert_eq!(
+            walk(
+                &source,
+                &["**/*.log", "!logs/keep.log", "build/", "/tmp", "/templates"],
+                &options
+            ),
+            ["deep", "deep/er", "logs", "logs/keep.log", "src", "src/build"]
+        );
+    }
+
+    #[test]
+    fn test_ignore_files_and_caches() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = tree(&temp_dir, &["a/skip.txt", "a/keep.txt", "b/c.txt", "cache/x"]);
+        fs::write(source.join("a").join(IGNORE_FILE), "skip.txt\n").unwrap();
+        fs::write(source.join("cache").join(CACHEDIR_TAG), CACHEDIR_SIGNATURE).unwrap();
+        let exclude_file = temp_dir.path().join("excludes");
+        fs::write(&exclude_file, "# comment\nb/\n").unwrap();
+
+        let options = ExcludeOptions {
+            exclude_files: vec![exclude_file.to_string_lossy().to_string()],
+            exclude_caches: true,
+            ..ExcludeOptions::default()
+        };
+        assert_eq!(
+            walk(&source, &[], &options),
+            ["a", "a/.backupforge-ignore", "a/keep.txt"]
+        );
+    }
+
+    #[test]
+    fn test_exclude_larger_than() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = tree(&temp_dir, &["small"]);
+        fs::write(source.join("large"), vec![0u8; 1000]).unwrap();
+
+        let options = ExcludeOptions {
+            exclude_larger_than: Some(100),
+            ..ExcludeOptions::default()
+        };
+        assert_eq!(walk(&source, &[], &options), ["small"]);
+    }
+
+    #[test]
+    fn test_invalid_exclude_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let options = ExcludeOptions {
+            exclude_files: vec!["/nonexistent/excludes".to_string()],
+            ..ExcludeOptions::default()
+        };
+        assert!(walker(temp_dir.path(), &[], &options).is_err());
+    }
+}
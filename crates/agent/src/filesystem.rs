@@ -1,61 +1,105 @@
 use backupforge_common::{
-    types::{BackupStats, FileMetadata, Snapshot},
+    types::{BackupStats, ExcludeOptions, FileMetadata, NodeKind, Snapshot},
     Error, Result,
 };
-use backupforge_core::BackupEngine;
-use backupforge_storage::StorageManager;
+use backupforge_core::{metadata, BackupEngine, OrderedTasks, RestoreOptions, SnapshotManifest};
 use chrono::Utc;
-use std::path::{Path, PathBuf};
+use std::collections::{hash_map::Entry, HashMap};
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 use tokio::fs;
 use tokio::io::AsyncReadExt;
-use walkdir::WalkDir;
+
+use crate::exclude;
 
 /// Filesystem backup handler
 pub struct FilesystemBackup {
     engine: Arc<BackupEngine>,
-    storage: Arc<StorageManager>,
 }
 
 impl FilesystemBackup {
-    pub fn new(engine: Arc<BackupEngine>, storage: Arc<StorageManager>) -> Self {
-        Self { engine, storage }
+    pub fn new(engine: Arc<BackupEngine>) -> Self {
+        Self { engine }
     }
 
-    /// Backup a directory recursively
+    /// Backup a directory recursively, leaving out what `excludes` and
+    /// `options` exclude
     pub async fn backup_directory(
         &self,
         source_path: &Path,
         excludes: &[String],
+        options: &ExcludeOptions,
     ) -> Result<Snapshot> {
+        let walker = exclude::walker(source_path, excludes, options)?;
+        self.engine.begin_run();
+        let source = source_path.to_string_lossy().to_string();
+        let parent = self.load_parent(&source).await?;
+        let previous_files: Arc<HashMap<String, FileMetadata>> = Arc::new(
+            parent
+                .as_ref()
+                .map(|manifest| {
+                    manifest
+                        .files
+                        .iter()
+                        .map(|f| (f.path.clone(), f.clone()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        );
+
         let mut file_metadatas = Vec::new();
-        let mut total_files = 0u64;
-        let mut total_bytes = 0u64;
+        let mut in_flight = OrderedTasks::new();
+        let file_workers = self.engine.config().file_workers.max(1);
+        let mut hard_links: HashMap<(u64, u64), PathBuf> = HashMap::new();
+        let mut skipped = HashMap::new();
 
         // Walk directory
-        for entry in WalkDir::new(source_path)
-            .follow_links(false)
-            .into_iter()
-            .filter_entry(|e| !self.should_exclude(e.path(), excludes))
-        {
+        for entry in walker {
             let entry = entry.map_err(|e| Error::Io(std::io::Error::other(e)))?;
             let path = entry.path();
 
-            // Skip if not a file
-            if !path.is_file() {
+            // The source directory itself becomes the restore target
+            if entry.depth() == 0 && entry.file_type().is_some_and(|t| t.is_dir()) {
                 continue;
             }
 
-            match self.backup_file(path).await {
-                Ok(metadata) => {
-                    total_bytes += metadata.size;
-                    total_files += 1;
-                    file_metadatas.push(metadata);
-                }
-                Err(e) => {
-                    tracing::warn!("Failed to backup {}: {}", path.display(), e);
+            // Later names of a file with several links refer to the first
+            let stat = entry
+                .metadata()
+                .map_err(|e| Error::Io(std::io::Error::other(e)))?;
+            let link_target = metadata::hard_link_key(&stat).and_then(|key| {
+                match hard_links.entry(key) {
+                    Entry::Occupied(first) => Some(first.get().clone()),
+                    Entry::Vacant(slot) => {
+                        slot.insert(path.to_path_buf());
+                        None
+                    }
                 }
+            });
+
+            // Files are read concurrently; the engine bounds the chunks in flight
+            if in_flight.len() >= file_workers {
+                self.collect(&mut in_flight, &mut file_metadatas, &mut skipped)
+                    .await?;
             }
+            let engine = self.engine.clone();
+            let previous_files = previous_files.clone();
+            let path = path.to_path_buf();
+            in_flight.push(tokio::spawn(async move {
+                let result = match link_target {
+                    Some(target) => engine.backup_hard_link(&path, &target).await,
+                    None => {
+                        let previous = previous_files.get(path.to_string_lossy().as_ref());
+                        engine.backup_file_with_parent(&path, previous).await
+                    }
+                };
+                Ok((path, result))
+            }));
+        }
+
+        while !in_flight.is_empty() {
+            self.collect(&mut in_flight, &mut file_metadatas, &mut skipped)
+                .await?;
         }
 
         // Create snapshot
@@ -63,59 +107,234 @@ impl FilesystemBackup {
             .engine
             .create_snapshot(
                 format!("backup-{}", Utc::now().format("%Y%m%d-%H%M%S")),
-                source_path.to_string_lossy().to_string(),
+                source,
                 file_metadatas,
+                parent.map(|manifest| manifest.snapshot.id),
             )
             .await?;
 
         Ok(snapshot)
     }
 
-    /// Backup a single file
-    pub async fn backup_file(&self, path: &Path) -> Result<FileMetadata> {
-        self.engine.backup_file(path).await
+    /// The most recent snapshot of the same source, whose files can be reused
+    async fn load_parent(&self, source: &str) -> Result<Option<SnapshotManifest>> {
+        let parent = self
+            .engine
+            .list_snapshots()
+            .await?
+            .into_iter()
+            .rfind(|s| s.source_path == source);
+
+        match parent {
+            Some(snapshot) => {
+                tracing::info!("Using parent snapshot {}", snapshot.id.0);
+                Ok(Some(self.engine.load_snapshot(&snapshot.id).await?))
+            }
+            None => Ok(None),
+        }
     }
 
-    /// Check if path should be excluded
-    fn should_exclude(&self, path: &Path, excludes: &[String]) -> bool {
-        let path_str = path.to_string_lossy();
+    /// Wait for the oldest file in flight; a file that fails is skipped
+    ///
+    /// `skipped` maps the paths of skipped files to the later name of the
+    /// same file, if any, that was backed up with contents in their place.
+    /// Names linking to a skipped file would have nothing to restore from.
+    async fn collect(
+        &self,
+        in_flight: &mut OrderedTasks<(PathBuf, Result<FileMetadata>)>,
+        file_metadatas: &mut Vec<FileMetadata>,
+        skipped: &mut HashMap<String, Option<String>>,
+    ) -> Result<()> {
+        let Some(task) = in_flight.next().await else {
+            return Ok(());
+        };
+        let (path, result) = task?;
 
-        for exclude_pattern in excludes {
-            if path_str.contains(exclude_pattern) {
-                return true;
+        let result = match result {
+            Ok(FileMetadata {
+                kind: NodeKind::HardLink { target },
+                ..
+            }) if skipped.contains_key(&target) => match skipped[&target].clone() {
+                Some(replacement) => self
+                    .engine
+                    .backup_hard_link(&path, Path::new(&replacement))
+                    .await,
+                None => {
+                    let result = self.engine.backup_file(&path).await;
+                    if result.is_ok() {
+                        skipped.insert(target, Some(path.to_string_lossy().to_string()));
+                    }
+                    result
+                }
+            },
+            result => result,
+        };
+
+        match result {
+            Ok(metadata) => file_metadatas.push(metadata),
+            Err(e) => {
+                tracing::warn!("Failed to backup {}: {}", path.display(), e);
+                skipped.insert(path.to_string_lossy().to_string(), None);
             }
         }
 
-        false
+        Ok(())
+    }
+
+    /// Backup a single file
+    pub async fn backup_file(&self, path: &Path) -> Result<FileMetadata> {
+        self.engine.backup_file(path).await
     }
 
-    /// Restore a snapshot to a directory
+    /// Restore a snapshot's files to a directory, with their metadata
     pub async fn restore_snapshot(
         &self,
-        snapshot: &Snapshot,
+        manifest: &SnapshotManifest,
         target_path: &Path,
+        options: &RestoreOptions,
     ) -> Result<()> {
         // Create target directory
         fs::create_dir_all(target_path).await?;
 
-        // Restore each file (simplified - would need actual implementation)
-        for chunk_id in &snapshot.chunk_ids {
-            // Fetch chunk from storage
-            let _chunk_data = self.storage.get_chunk(chunk_id).await?;
+        let source_root = Path::new(&manifest.snapshot.source_path);
+        let mut directories = Vec::new();
+        for file in &manifest.files {
+            let restore_path = Self::restore_path(source_root, &file.path, target_path)?;
+
+            match &file.kind {
+                NodeKind::Directory => {
+                    Self::create_dirs(target_path, &restore_path).await?;
+                    directories.push((restore_path, file));
+                    continue;
+                }
+                NodeKind::File => {
+                    Self::make_room(target_path, &restore_path).await?;
+                    self.engine.restore_file(file, &restore_path).await?;
+                }
+                // Shares the metadata already applied to the first name
+                NodeKind::HardLink { target } => {
+                    let original = Self::restore_path(source_root, target, target_path)?;
+                    Self::make_room(target_path, &restore_path).await?;
+                    fs::hard_link(&original, &restore_path).await?;
+                    continue;
+                }
+                NodeKind::Symlink { target } => {
+                    Self::make_room(target_path, &restore_path).await?;
+                    if !Self::create_symlink(target, &restore_path).await? {
+                        continue;
+                    }
+                }
+                NodeKind::CharDevice { .. }
+                | NodeKind::BlockDevice { .. }
+                | NodeKind::Fifo
+                | NodeKind::Socket => {
+                    Self::make_room(target_path, &restore_path).await?;
+                    if !metadata::create_node(&restore_path, file)? {
+                        continue;
+                    }
+                }
+            }
 
-            // Decompress and decrypt would happen here via engine
-            // Then write to target path
+            metadata::apply(&restore_path, file, options)?;
+        }
+
+        // Restoring their contents changes directory times, so set them last,
+        // deepest first in case a parent loses write permission
+        for (path, directory) in directories.iter().rev() {
+            metadata::apply(path, directory, options)?;
         }
 
         Ok(())
     }
+
+    /// Create the parent of an entry below `root` and remove what is
+    /// already there, so the entry is not written through a symlink an
+    /// earlier restore left in its place
+    async fn make_room(root: &Path, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            Self::create_dirs(root, parent).await?;
+        }
+
+        match fs::remove_file(path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Create `dir` and its parents below `root`, replacing whatever else is
+    /// in the way so that no symlink leads outside `root`
+    async fn create_dirs(root: &Path, dir: &Path) -> Result<()> {
+        let relative = dir.strip_prefix(root).map_err(|_| {
+            Error::PermissionDenied(format!(
+                "Refusing to restore path outside target: {}",
+                dir.display()
+            ))
+        })?;
+
+        let mut current = root.to_path_buf();
+        for component in relative.components() {
+            current.push(component);
+            match fs::symlink_metadata(&current).await {
+                Ok(metadata) if metadata.is_dir() => continue,
+                Ok(_) => fs::remove_file(&current).await?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            fs::create_dir(&current).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a symlink; returns `false` where symlinks are not supported
+    #[cfg(unix)]
+    async fn create_symlink(target: &str, path: &Path) -> Result<bool> {
+        fs::symlink(target, path).await?;
+        Ok(true)
+    }
+
+    #[cfg(not(unix))]
+    async fn create_symlink(target: &str, path: &Path) -> Result<bool> {
+        tracing::warn!("Skipping symlink {} -> {}", path.display(), target);
+        Ok(false)
+    }
+
+    /// Map a backed-up path to its location under the restore target
+    fn restore_path(source_root: &Path, path: &str, target_path: &Path) -> Result<PathBuf> {
+        let original = Path::new(path);
+        let relative = match original.strip_prefix(source_root) {
+            // A single-file backup has the file itself as its source root
+            Ok(relative) if relative.as_os_str().is_empty() => {
+                original.file_name().map(PathBuf::from).unwrap_or_default()
+            }
+            Ok(relative) => relative.to_path_buf(),
+            // Fall back to the path with its root stripped
+            Err(_) => original
+                .components()
+                .skip_while(|c| !matches!(c, Component::Normal(_)))
+                .collect(),
+        };
+
+        // Never let a manifest entry escape the restore target
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(Error::PermissionDenied(format!(
+                "Refusing to restore path outside target: {}",
+                path
+            )));
+        }
+
+        Ok(target_path.join(relative))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use backupforge_core::BackupConfig;
-    use backupforge_storage::StorageConfig;
+    use backupforge_storage::{StorageConfig, StorageManager};
     use tempfile::TempDir;
 
     #[tokio::test]
@@ -132,18 +351,316 @@ mod tests {
             .await
             .unwrap();
 
+        let storage_config = StorageConfig::Local {
+            path: storage_path.to_string_lossy().to_string(),
+        };
+        let storage = Arc::new(StorageManager::from_config(storage_config).await.unwrap());
+
         let config = BackupConfig::default();
-        let engine = Arc::new(BackupEngine::new(config));
+        let engine = Arc::new(BackupEngine::new(config, storage.backend()));
+
+        let fs_backup = FilesystemBackup::new(engine);
+
+        let snapshot = fs_backup
+            .backup_directory(&source, &[], &ExcludeOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(snapshot.file_count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_restore_snapshot_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source");
+        let target = temp_dir.path().join("target");
+        let storage_path = temp_dir.path().join("storage");
+
+        fs::create_dir_all(source.join("nested")).await.unwrap();
+        let large: Vec<u8> = (0..3_000_000u32).map(|i| (i * 7 % 256) as u8).collect();
+        fs::write(source.join("small.txt"), b"hello").await.unwrap();
+        fs::write(source.join("nested/large.bin"), &large).await.unwrap();
+        fs::write(source.join("empty"), b"").await.unwrap();
 
         let storage_config = StorageConfig::Local {
             path: storage_path.to_string_lossy().to_string(),
         };
         let storage = Arc::new(StorageManager::from_config(storage_config).await.unwrap());
+        let engine = Arc::new(BackupEngine::new(BackupConfig::default(), storage.backend()));
+        let fs_backup = FilesystemBackup::new(engine.clone());
+
+        let snapshot = fs_backup
+            .backup_directory(&source, &[], &ExcludeOptions::default())
+            .await
+            .unwrap();
+        let manifest = engine.load_snapshot(&snapshot.id).await.unwrap();
+        assert_eq!(manifest.files.len(), 4);
 
-        let fs_backup = FilesystemBackup::new(engine, storage);
+        fs_backup
+            .restore_snapshot(&manifest, &target, &RestoreOptions::default())
+            .await
+            .unwrap();
 
-        let snapshot = fs_backup.backup_directory(&source, &[]).await.unwrap();
+        assert_eq!(fs::read(target.join("small.txt")).await.unwrap(), b"hello");
+        assert_eq!(fs::read(target.join("nested/large.bin")).await.unwrap(), large);
+        assert!(fs::read(target.join("empty")).await.unwrap().is_empty());
+    }
 
-        assert_eq!(snapshot.file_count, 2);
+    #[tokio::test]
+    async fn test_incremental_backup_reuses_unchanged_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source");
+        let storage_path = temp_dir.path().join("storage");
+
+        fs::create_dir_all(&source).await.unwrap();
+        fs::write(source.join("same.txt"), b"unchanged").await.unwrap();
+        fs::write(source.join("edited.txt"), b"before").await.unwrap();
+
+        let storage_config = StorageConfig::Local {
+            path: storage_path.to_string_lossy().to_string(),
+        };
+        let storage = Arc::new(StorageManager::from_config(storage_config).await.unwrap());
+        let engine = Arc::new(BackupEngine::new(BackupConfig::default(), storage.backend()));
+        let fs_backup = FilesystemBackup::new(engine.clone());
+
+        let first = fs_backup
+            .backup_directory(&source, &[], &ExcludeOptions::default())
+            .await
+            .unwrap();
+        assert!(first.parent_snapshot.is_none());
+
+        fs::write(source.join("edited.txt"), b"after, and longer")
+            .await
+            .unwrap();
+        let second = fs_backup
+            .backup_directory(&source, &[], &ExcludeOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(second.parent_snapshot, Some(first.id.clone()));
+
+        let stats = second.stats.clone().unwrap();
+        assert_eq!(stats.total_files, 2);
+        assert_eq!(stats.changed_files, 1);
+        assert_eq!(stats.unchanged_files, 1);
+        assert_eq!(stats.total_bytes, 26);
+        assert_eq!(stats.new_chunks, 1);
+        assert_eq!(stats.new_bytes, 17);
+        assert_eq!(stats.reused_chunks, 1);
+
+        // A reused entry keeps the parent's chunks; references are counted again
+        let manifest = engine.load_snapshot(&second.id).await.unwrap();
+        let same = manifest
+            .files
+            .iter()
+            .find(|f| f.path.ends_with("same.txt"))
+            .unwrap();
+        for chunk_id in &same.chunk_ids {
+            assert_eq!(engine.dedup_store().index().get_ref_count(chunk_id), 2);
+        }
+
+        let target = temp_dir.path().join("target");
+        fs_backup
+            .restore_snapshot(&manifest, &target, &RestoreOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(fs::read(target.join("same.txt")).await.unwrap(), b"unchanged");
+        assert_eq!(
+            fs::read(target.join("edited.txt")).await.unwrap(),
+            b"after, and longer"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_restore_special_entries() {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source");
+        let target = temp_dir.path().join("target");
+        let storage_path = temp_dir.path().join("storage");
+
+        fs::create_dir_all(source.join("empty_dir")).await.unwrap();
+        fs::write(source.join("file.txt"), b"linked").await.unwrap();
+        fs::hard_link(source.join("file.txt"), source.join("hard.txt"))
+            .await
+            .unwrap();
+        fs::symlink("file.txt", source.join("link")).await.unwrap();
+        fs::symlink("/nonexistent", source.join("dangling"))
+            .await
+            .unwrap();
+        let mkfifo = std::process::Command::new("mkfifo")
+            .arg(source.join("fifo"))
+            .status()
+            .unwrap();
+        assert!(mkfifo.success());
+
+        let storage_config = StorageConfig::Local {
+            path: storage_path.to_string_lossy().to_string(),
+        };
+        let storage = Arc::new(StorageManager::from_config(storage_config).await.unwrap());
+        let engine = Arc::new(BackupEngine::new(BackupConfig::default(), storage.backend()));
+        let fs_backup = FilesystemBackup::new(engine.clone());
+
+        let snapshot = fs_backup
+            .backup_directory(&source, &[], &ExcludeOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(snapshot.file_count, 6);
+        // The second name of the file adds no contents
+        assert_eq!(snapshot.total_size, 6);
+
+        let manifest = engine.load_snapshot(&snapshot.id).await.unwrap();
+        fs_backup
+            .restore_snapshot(&manifest, &target, &RestoreOptions::default())
+            .await
+            .unwrap();
+
+        assert!(fs::metadata(target.join("empty_dir")).await.unwrap().is_dir());
+        assert_eq!(fs::read(target.join("hard.txt")).await.unwrap(), b"linked");
+        assert_eq!(
+            fs::metadata(target.join("hard.txt")).await.unwrap().ino(),
+            fs::metadata(target.join("file.txt")).await.unwrap().ino()
+        );
+        assert_eq!(
+            fs::read_link(target.join("link")).await.unwrap(),
+            Path::new("file.txt")
+        );
+        assert_eq!(
+            fs::read_link(target.join("dangling")).await.unwrap(),
+            Path::new("/nonexistent")
+        );
+        assert!(fs::symlink_metadata(target.join("fifo"))
+            .await
+            .unwrap()
+            .file_type()
+            .is_fifo());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hard_link_whose_first_name_fails() {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source");
+        let target = temp_dir.path().join("target");
+        fs::create_dir_all(&source).await.unwrap();
+        fs::write(source.join("a.txt"), b"linked").await.unwrap();
+        for name in ["b.txt", "c.txt"] {
+            fs::hard_link(source.join("a.txt"), source.join(name))
+                .await
+                .unwrap();
+        }
+
+        let storage_config = StorageConfig::Local {
+            path: temp_dir.path().join("storage").to_string_lossy().to_string(),
+        };
+        let storage = Arc::new(StorageManager::from_config(storage_config).await.unwrap());
+        let engine = Arc::new(BackupEngine::new(BackupConfig::default(), storage.backend()));
+        let fs_backup = FilesystemBackup::new(engine.clone());
+
+        // The walk recorded the later names as links to a.txt, which then
+        // failed to read
+        let mut in_flight = OrderedTasks::new();
+        let first = source.join("a.txt");
+        in_flight.push(tokio::spawn({
+            let first = first.clone();
+            async move {
+                let err = Error::Io(std::io::Error::other("unreadable"));
+                Ok((first, Err(err)))
+            }
+        }));
+        for name in ["b.txt", "c.txt"] {
+            let engine = engine.clone();
+            let (path, first) = (source.join(name), first.clone());
+            in_flight.push(tokio::spawn(async move {
+                let result = engine.backup_hard_link(&path, &first).await;
+                Ok((path, result))
+            }));
+        }
+        let mut files = Vec::new();
+        let mut skipped = HashMap::new();
+        while !in_flight.is_empty() {
+            fs_backup
+                .collect(&mut in_flight, &mut files, &mut skipped)
+                .await
+                .unwrap();
+        }
+
+        // b.txt carries the contents in place of a.txt, c.txt links to it
+        let b_path = source.join("b.txt").to_string_lossy().to_string();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].kind, NodeKind::File);
+        assert_eq!(files[0].size, 6);
+        assert_eq!(files[1].kind, NodeKind::HardLink { target: b_path });
+
+        let snapshot = engine
+            .create_snapshot(
+                "test".to_string(),
+                source.to_string_lossy().to_string(),
+                files,
+                None,
+            )
+            .await
+            .unwrap();
+        let manifest = engine.load_snapshot(&snapshot.id).await.unwrap();
+        fs_backup
+            .restore_snapshot(&manifest, &target, &RestoreOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(fs::read(target.join("c.txt")).await.unwrap(), b"linked");
+        assert_eq!(
+            fs::metadata(target.join("c.txt")).await.unwrap().ino(),
+            fs::metadata(target.join("b.txt")).await.unwrap().ino()
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_restore_does_not_follow_symlinks_in_target() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source");
+        let target = temp_dir.path().join("target");
+        let outside = temp_dir.path().join("outside");
+        fs::create_dir_all(source.join("dir")).await.unwrap();
+        fs::write(source.join("dir/inner.txt"), b"inner").await.unwrap();
+        fs::write(source.join("top.txt"), b"top").await.unwrap();
+
+        let storage_config = StorageConfig::Local {
+            path: temp_dir.path().join("storage").to_string_lossy().to_string(),
+        };
+        let storage = Arc::new(StorageManager::from_config(storage_config).await.unwrap());
+        let engine = Arc::new(BackupEngine::new(BackupConfig::default(), storage.backend()));
+        let fs_backup = FilesystemBackup::new(engine.clone());
+        let snapshot = fs_backup
+            .backup_directory(&source, &[], &ExcludeOptions::default())
+            .await
+            .unwrap();
+
+        // An earlier restore left symlinks where the directory and a file go
+        fs::create_dir_all(&outside).await.unwrap();
+        fs::write(outside.join("top.txt"), b"untouched").await.unwrap();
+        fs::create_dir_all(&target).await.unwrap();
+        fs::symlink(&outside, target.join("dir")).await.unwrap();
+        fs::symlink(outside.join("top.txt"), target.join("top.txt"))
+            .await
+            .unwrap();
+
+        let manifest = engine.load_snapshot(&snapshot.id).await.unwrap();
+        fs_backup
+            .restore_snapshot(&manifest, &target, &RestoreOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(fs::read(target.join("dir/inner.txt")).await.unwrap(), b"inner");
+        assert_eq!(fs::read(target.join("top.txt")).await.unwrap(), b"top");
+        assert!(!fs::symlink_metadata(target.join("dir"))
+            .await
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert!(fs::metadata(outside.join("inner.txt")).await.is_err());
+        assert_eq!(fs::read(outside.join("top.txt")).await.unwrap(), b"untouched");
     }
 }
@@ -1,4 +1,5 @@
 pub mod filesystem;
+pub mod exclude;
 pub mod ssh;
 pub mod proxmox;
 pub mod docker;
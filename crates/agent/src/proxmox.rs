@@ -133,8 +133,8 @@ pub mod mcp_integration {
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_proxmox_backup_creation() {
+    #[tokio::test]
+    async fn test_proxmox_backup_creation() {
         let config = ProxmoxConfig {
             host: "pve.example.com".to_string(),
             port: 8006,
@@ -144,10 +144,11 @@ mod tests {
             verify_ssl: false,
         };
 
-        let engine = Arc::new(BackupEngine::new(Default::default()));
         let storage_config = backupforge_storage::StorageConfig::Local {
             path: "/tmp/test".to_string(),
         };
+        let storage = Arc::new(StorageManager::from_config(storage_config).await.unwrap());
+        let engine = Arc::new(BackupEngine::new(Default::default(), storage.backend()));
 
         // Just test creation
         // Real tests would need a Proxmox instance
@@ -111,12 +111,13 @@ impl SshBackup {
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_ssh_backup_creation() {
-        let engine = Arc::new(BackupEngine::new(Default::default()));
+    #[tokio::test]
+    async fn test_ssh_backup_creation() {
         let storage_config = backupforge_storage::StorageConfig::Local {
             path: "/tmp/test".to_string(),
         };
+        let storage = Arc::new(StorageManager::from_config(storage_config).await.unwrap());
+        let engine = Arc::new(BackupEngine::new(Default::default(), storage.backend()));
 
         // Note: actual SSH tests would require a test SSH server
         // This just tests that we can create the struct
@@ -1,12 +1,22 @@
 use backupforge_agent::BackupAgent;
-use backupforge_common::types::{BackupJob, BackupSource};
-use backupforge_core::{BackupConfig, ChunkingStrategy, CompressionAlgorithm, EncryptionKey};
-use backupforge_storage::StorageConfig;
+use backupforge_common::types::{
+    BackupJob, BackupSource, BackupStats, ExcludeOptions, RetentionPolicy,
+};
+use backupforge_core::{
+    lock, BackupConfig, CheckOptions, ChunkingStrategy, CompressionAlgorithm, Credential,
+    EncryptionKey, LockKind, ReadData, RepairOptions, Repository, RepositoryConfig,
+    RepositoryLock, RestoreOptions,
+};
+use backupforge_storage::{StorageConfig, StorageManager};
 use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tracing_subscriber;
 use uuid::Uuid;
 
+mod password;
+
+use password::PasswordOptions;
+
 #[derive(Parser)]
 #[command(name = "backupforge")]
 #[command(about = "Open-source backup and disaster recovery solution", long_about = None)]
@@ -17,6 +27,9 @@ struct Cli {
     /// Enable verbose logging
     #[arg(short, long, global = true)]
     verbose: bool,
+
+    #[command(flatten)]
+    password: PasswordOptions,
 }
 
 #[derive(Subcommand)]
@@ -31,17 +44,37 @@ enum Commands {
         #[arg(short = 'd', long)]
         storage: PathBuf,
 
-        /// Exclude patterns
+        /// Exclude files matching a gitignore-style pattern
         #[arg(short, long)]
         exclude: Vec<String>,
 
-        /// Enable encryption (will prompt for password)
-        #[arg(short = 'e', long)]
-        encrypt: bool,
+        /// Read exclude patterns from a file, one per line
+        #[arg(long)]
+        exclude_file: Vec<PathBuf>,
+
+        /// Skip directories containing a CACHEDIR.TAG
+        #[arg(long)]
+        exclude_caches: bool,
+
+        /// Skip files larger than this size, such as 500M or 2G
+        #[arg(long, value_parser = parse_size)]
+        exclude_larger_than: Option<u64>,
+
+        /// Do not cross into other file systems
+        #[arg(short = 'x', long)]
+        one_file_system: bool,
+
+        /// Zstd compression level (1-22), overriding the repository default
+        #[arg(short, long)]
+        compression: Option<i32>,
+
+        /// Chunks compressed and encrypted in parallel (default: number of CPUs)
+        #[arg(long)]
+        workers: Option<usize>,
 
-        /// Compression level (0-22 for zstd)
-        #[arg(short, long, default_value = "3")]
-        compression: i32,
+        /// Chunk uploads in flight at once
+        #[arg(long)]
+        uploads: Option<usize>,
     },
 
     /// Restore a backup
@@ -57,6 +90,10 @@ enum Commands {
         /// Snapshot ID to restore
         #[arg(short, long)]
         snapshot: Option<String>,
+
+        /// Do not restore file owners and groups
+        #[arg(long)]
+        no_owner: bool,
     },
 
     /// List snapshots
@@ -66,6 +103,125 @@ enum Commands {
         storage: PathBuf,
     },
 
+    /// Remove snapshots according to a retention policy
+    Forget {
+        /// Storage path
+        #[arg(short = 'd', long)]
+        storage: PathBuf,
+
+        /// Keep the newest N snapshots
+        #[arg(long)]
+        keep_last: Option<u32>,
+
+        /// Keep the newest snapshot of each of the last N hours
+        #[arg(long)]
+        keep_hourly: Option<u32>,
+
+        /// Keep the newest snapshot of each of the last N days
+        #[arg(long)]
+        keep_daily: Option<u32>,
+
+        /// Keep the newest snapshot of each of the last N weeks
+        #[arg(long)]
+        keep_weekly: Option<u32>,
+
+        /// Keep the newest snapshot of each of the last N months
+        #[arg(long)]
+        keep_monthly: Option<u32>,
+
+        /// Keep the newest snapshot of each of the last N years
+        #[arg(long)]
+        keep_yearly: Option<u32>,
+
+        /// Keep snapshots this recent relative to the newest, such as 36h, 14d or 1y6m
+        #[arg(long, value_parser = parse_hours)]
+        keep_within: Option<u64>,
+
+        /// Keep snapshots with this tag
+        #[arg(long)]
+        keep_tag: Vec<String>,
+
+        /// Only consider snapshots of this source path
+        #[arg(short, long)]
+        source: Option<PathBuf>,
+
+        /// Show what would be removed without removing anything
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+
+    /// Delete chunks that no snapshot references
+    Prune {
+        /// Storage path
+        #[arg(short = 'd', long)]
+        storage: PathBuf,
+
+        /// Leave data written this recently, such as by backups still running
+        #[arg(long, value_parser = parse_hours, default_value = "24h")]
+        grace_period: u64,
+
+        /// Show what would be deleted without deleting anything
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+
+    /// Check the repository for missing or damaged data
+    Check {
+        /// Storage path
+        #[arg(short = 'd', long)]
+        storage: PathBuf,
+
+        /// Download and verify every referenced chunk
+        #[arg(long, conflicts_with = "read_data_subset")]
+        read_data: bool,
+
+        /// Download and verify a random share of the chunks, such as 10%
+        #[arg(long, value_parser = parse_percent)]
+        read_data_subset: Option<f64>,
+
+        /// Only check these snapshots (ID, prefix or "latest")
+        #[arg(short, long)]
+        snapshot: Vec<String>,
+
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Rebuild indexes and drop files whose data is lost from their snapshots
+    Repair {
+        /// Storage path
+        #[arg(short = 'd', long)]
+        storage: PathBuf,
+
+        /// Download and verify every referenced chunk to find damaged ones too
+        #[arg(long)]
+        read_data: bool,
+
+        /// Upload lost chunks again from source files unchanged since the backup
+        #[arg(long)]
+        reupload: bool,
+
+        /// Show what would be repaired without changing anything
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Remove locks left behind by processes that stopped
+    Unlock {
+        /// Storage path
+        #[arg(short = 'd', long)]
+        storage: PathBuf,
+
+        /// Also remove locks that may still be held by running processes
+        #[arg(long)]
+        remove_all: bool,
+    },
+
     /// Show storage statistics
     Stats {
         /// Storage path
@@ -79,9 +235,19 @@ enum Commands {
         #[arg(short = 'd', long)]
         storage: PathBuf,
 
-        /// Enable encryption
+        /// Encrypt the repository with a passphrase
         #[arg(short, long)]
         encrypt: bool,
+
+        /// Target pack file size in MiB, 0 to store chunks as separate objects
+        #[arg(long, value_parser = parse_pack_size, default_value = "16")]
+        pack_size: u64,
+    },
+
+    /// Manage the key slots of an encrypted repository
+    Key {
+        #[command(subcommand)]
+        command: KeyCommands,
     },
 
     /// Run backup server/daemon
@@ -96,6 +262,60 @@ enum Commands {
     },
 }
 
+#[derive(Subcommand)]
+enum KeyCommands {
+    /// List key slots
+    List {
+        /// Storage path
+        #[arg(short = 'd', long)]
+        storage: PathBuf,
+    },
+
+    /// Add a passphrase or key file slot
+    Add {
+        /// Storage path
+        #[arg(short = 'd', long)]
+        storage: PathBuf,
+
+        /// Label shown by `key list`
+        #[arg(short, long, default_value = "")]
+        label: String,
+
+        /// Protect the slot with a new random key file written to this path
+        #[arg(long, conflicts_with = "new_password_file")]
+        new_key_file: Option<PathBuf>,
+
+        /// Read the new passphrase from a file instead of prompting
+        #[arg(long)]
+        new_password_file: Option<PathBuf>,
+    },
+
+    /// Remove a key slot
+    Remove {
+        /// Storage path
+        #[arg(short = 'd', long)]
+        storage: PathBuf,
+
+        /// Slot ID or unique prefix
+        id: String,
+    },
+
+    /// Change the passphrase of a slot
+    Passwd {
+        /// Storage path
+        #[arg(short = 'd', long)]
+        storage: PathBuf,
+
+        /// Slot to change, defaulting to the one used to unlock
+        #[arg(long)]
+        id: Option<String>,
+
+        /// Read the new passphrase from a file instead of prompting
+        #[arg(long)]
+        new_password_file: Option<PathBuf>,
+    },
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
@@ -117,28 +337,36 @@ async fn main() -> anyhow::Result<()> {
             source,
             storage,
             exclude,
-            encrypt,
+            exclude_file,
+            exclude_caches,
+            exclude_larger_than,
+            one_file_system,
             compression,
+            workers,
+            uploads,
         } => {
             println!("🚀 Starting backup...");
             println!("Source: {}", source.display());
             println!("Storage: {}", storage.display());
 
-            let mut backup_config = BackupConfig::default();
-            backup_config.compression = CompressionAlgorithm::Zstd(compression);
+            let (storage_config, mut backup_config) =
+                open_repository(&storage, &cli.password).await?;
 
-            if encrypt {
-                // In production, would prompt for password securely
-                let password = "test_password"; // PLACEHOLDER
-                let salt = b"salt123456789012";
-                let key = EncryptionKey::from_password(password, salt)?;
-                backup_config.encryption_key = Some(key);
-                println!("🔒 Encryption enabled");
+            // Chunks record their own compression, so any repository can switch to zstd
+            if let Some(level) = compression {
+                backup_config.compression = CompressionAlgorithm::Zstd(level);
+            }
+            if let Some(workers) = workers {
+                backup_config.cpu_workers = workers;
+            }
+            if let Some(uploads) = uploads {
+                backup_config.upload_workers = uploads;
             }
 
-            let storage_config = StorageConfig::Local {
-                path: storage.to_string_lossy().to_string(),
-            };
+            let encrypted = backup_config.encryption_key.is_some();
+            if encrypted {
+                println!("🔒 Encryption enabled");
+            }
 
             let agent = BackupAgent::new(backup_config, storage_config).await?;
 
@@ -148,76 +376,375 @@ async fn main() -> anyhow::Result<()> {
                 source: BackupSource::LocalPath {
                     path: source.to_string_lossy().to_string(),
                     excludes: exclude,
+                    exclude_options: ExcludeOptions {
+                        exclude_files: exclude_file
+                            .iter()
+                            .map(|path| path.to_string_lossy().to_string())
+                            .collect(),
+                        exclude_caches,
+                        one_file_system,
+                        exclude_larger_than,
+                    },
                 },
                 destination: storage.to_string_lossy().to_string(),
                 schedule: None,
                 retention_days: 30,
                 enabled: true,
-                encryption_enabled: encrypt,
-                compression_level: compression as u8,
+                encryption_enabled: encrypted,
+                compression_level: compression.unwrap_or(3) as u8,
+                retention: None,
             };
 
             let snapshot = agent.run_job(&job).await?;
 
             println!("✅ Backup completed!");
             println!("Snapshot ID: {}", snapshot.id.0);
+            if let Some(parent) = &snapshot.parent_snapshot {
+                println!("Parent: {}", parent.0);
+            }
             println!("Files: {}", snapshot.file_count);
             println!("Size: {} bytes", snapshot.total_size);
-            println!(
-                "Compressed: {} bytes ({:.1}%)",
-                snapshot.compressed_size,
-                (snapshot.compressed_size as f64 / snapshot.total_size as f64) * 100.0
-            );
+            if let Some(stats) = &snapshot.stats {
+                print_backup_stats(stats);
+            }
         }
 
         Commands::Restore {
             storage,
             target,
             snapshot,
+            no_owner,
         } => {
             println!("🔄 Starting restore...");
             println!("Storage: {}", storage.display());
             println!("Target: {}", target.display());
 
-            // Would implement restore logic
-            println!("⚠️  Restore functionality coming soon!");
+            let (storage_config, backup_config) = open_repository(&storage, &cli.password).await?;
+            let agent = BackupAgent::new(backup_config, storage_config).await?;
+            let snapshot = agent
+                .find_snapshot(snapshot.as_deref().unwrap_or("latest"))
+                .await?;
+
+            println!("Snapshot: {} ({})", snapshot.id.0, snapshot.name);
+            let options = RestoreOptions {
+                restore_ownership: !no_owner,
+            };
+            agent
+                .restore_snapshot(&snapshot.id, &target, &options)
+                .await?;
+
+            println!("✅ Restore completed!");
+            println!("Files: {}", snapshot.file_count);
+            println!("Size: {} bytes", snapshot.total_size);
         }
 
         Commands::List { storage } => {
             println!("📋 Listing snapshots from: {}", storage.display());
 
-            // Would list snapshots from storage
-            println!("⚠️  List functionality coming soon!");
+            let (storage_config, backup_config) = open_repository(&storage, &cli.password).await?;
+            let agent = BackupAgent::new(backup_config, storage_config).await?;
+            let snapshots = agent.list_snapshots().await?;
+
+            if snapshots.is_empty() {
+                println!("No snapshots found");
+            }
+
+            for snapshot in snapshots {
+                println!(
+                    "{}  {}  {}  {} files  {} bytes  {}",
+                    snapshot.id.0,
+                    snapshot.created_at.format("%Y-%m-%d %H:%M:%S"),
+                    snapshot.name,
+                    snapshot.file_count,
+                    snapshot.total_size,
+                    snapshot.source_path
+                );
+            }
         }
 
-        Commands::Stats { storage } => {
-            println!("📊 Storage statistics for: {}", storage.display());
+        Commands::Forget {
+            storage,
+            keep_last,
+            keep_hourly,
+            keep_daily,
+            keep_weekly,
+            keep_monthly,
+            keep_yearly,
+            keep_within,
+            keep_tag,
+            source,
+            dry_run,
+        } => {
+            let policy = RetentionPolicy {
+                keep_last,
+                keep_hourly,
+                keep_daily,
+                keep_weekly,
+                keep_monthly,
+                keep_yearly,
+                keep_within_hours: keep_within,
+                keep_tags: keep_tag,
+            };
+            if policy.is_empty() {
+                anyhow::bail!("Specify at least one --keep-* rule");
+            }
 
-            let storage_config = StorageConfig::Local {
-                path: storage.to_string_lossy().to_string(),
+            let (storage_config, backup_config) = open_repository(&storage, &cli.password).await?;
+            let agent = BackupAgent::new(backup_config, storage_config).await?;
+            let source = source.map(|path| path.to_string_lossy().to_string());
+            let decisions = agent.forget(&policy, source.as_deref(), dry_run).await?;
+
+            for decision in &decisions {
+                let snapshot = &decision.snapshot;
+                println!(
+                    "{}  {}  {}  {}  {}",
+                    if decision.keep() { "keep  " } else { "remove" },
+                    snapshot.id.0,
+                    snapshot.created_at.format("%Y-%m-%d %H:%M:%S"),
+                    snapshot.source_path,
+                    decision.reasons.join(", ")
+                );
+            }
+
+            let removed = decisions.iter().filter(|d| !d.keep()).count();
+            if dry_run {
+                println!("Dry run: {} of {} snapshots would be removed", removed, decisions.len());
+            } else {
+                println!("✅ Removed {} of {} snapshots", removed, decisions.len());
+            }
+        }
+
+        Commands::Prune {
+            storage,
+            grace_period,
+            dry_run,
+        } => {
+            let (storage_config, backup_config) = open_repository(&storage, &cli.password).await?;
+            let agent = BackupAgent::new(backup_config, storage_config).await?;
+            let Some(grace_period) = i64::try_from(grace_period)
+                .ok()
+                .and_then(chrono::Duration::try_hours)
+            else {
+                anyhow::bail!("Grace period of {} hours is too long", grace_period);
             };
+            let report = agent.prune(grace_period, dry_run).await?;
+            let sweep = &report.sweep;
 
-            let agent = BackupAgent::new(BackupConfig::default(), storage_config).await?;
-            let stats = agent.get_stats().await?;
+            println!(
+                "{} snapshots reference {} chunks",
+                report.snapshots, report.referenced_chunks
+            );
+            if sweep.recent_chunks > 0 {
+                println!(
+                    "{} unreferenced chunks are within the grace period",
+                    sweep.recent_chunks
+                );
+            }
+            if dry_run {
+                println!(
+                    "Dry run: {} chunks would be removed, {} packs repacked, {} bytes reclaimed",
+                    sweep.removed_chunks, sweep.repacked_packs, sweep.reclaimed_bytes
+                );
+            } else {
+                println!(
+                    "✅ Removed {} chunks, repacked {} packs, reclaimed {} bytes",
+                    sweep.removed_chunks, sweep.repacked_packs, sweep.reclaimed_bytes
+                );
+            }
+        }
 
-            println!("Total bytes: {}", stats.total_bytes);
+        Commands::Check {
+            storage,
+            read_data,
+            read_data_subset,
+            snapshot,
+            json,
+        } => {
+            let (storage_config, backup_config) = open_repository(&storage, &cli.password).await?;
+            let agent = BackupAgent::new(backup_config, storage_config).await?;
+
+            let mut snapshots = Vec::with_capacity(snapshot.len());
+            for id in &snapshot {
+                snapshots.push(agent.find_snapshot(id).await?.id);
+            }
+            let read_data = match read_data_subset {
+                Some(percent) => ReadData::Subset(percent),
+                None if read_data => ReadData::All,
+                None => ReadData::None,
+            };
+            let report = agent.check(&CheckOptions { read_data, snapshots }).await?;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!(
+                    "Checked {} snapshots referencing {} chunks",
+                    report.snapshots, report.referenced_chunks
+                );
+                if report.chunks_read > 0 {
+                    println!(
+                        "Read {} chunks ({} bytes)",
+                        report.chunks_read, report.bytes_read
+                    );
+                }
+                for problem in &report.problems {
+                    println!(
+                        "{}  {}  {}",
+                        problem.kind.as_str(),
+                        problem.subject,
+                        problem.message
+                    );
+                }
+            }
+
+            if !report.is_ok() {
+                anyhow::bail!("Repository check found {} problems", report.problems.len());
+            }
+            if !json {
+                println!("✅ No problems found");
+            }
+        }
+
+        Commands::Repair {
+            storage,
+            read_data,
+            reupload,
+            dry_run,
+            json,
+        } => {
+            let (storage_config, backup_config) = open_repository(&storage, &cli.password).await?;
+            let agent = BackupAgent::new(backup_config, storage_config).await?;
+            let report = agent
+                .repair(&RepairOptions {
+                    read_data,
+                    reupload,
+                    dry_run,
+                })
+                .await?;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                let index = &report.index;
+                println!(
+                    "Indexed {} packs holding {} chunks, and {} loose chunks",
+                    index.packs, index.packed_chunks, index.loose_chunks
+                );
+                if index.damaged_packs > 0 {
+                    println!("⚠️  {} packs are damaged beyond use", index.damaged_packs);
+                }
+                println!(
+                    "Found {} missing and {} damaged chunks",
+                    report.missing_chunks, report.damaged_chunks
+                );
+                for key in &report.removed_manifests {
+                    println!("removed    {}  unreadable manifest", key);
+                }
+                for file in &report.files {
+                    println!(
+                        "{:<10} {}  {}",
+                        if file.recovered { "recovered" } else { "dropped" },
+                        file.snapshot.0,
+                        file.path
+                    );
+                }
+
+                let dropped = report.dropped_files().count();
+                if dry_run {
+                    println!(
+                        "Dry run: {} snapshots would be repaired, {} files dropped",
+                        report.repaired_snapshots, dropped
+                    );
+                } else {
+                    println!(
+                        "✅ Repaired {} snapshots, dropped {} files",
+                        report.repaired_snapshots, dropped
+                    );
+                }
+            }
         }
 
-        Commands::Init { storage, encrypt } => {
+        Commands::Unlock {
+            storage,
+            remove_all,
+        } => {
+            let (_, repository) = load_repository(&storage).await?;
+            let storage = repository.storage();
+
+            for info in lock::remove_locks(storage.as_ref(), remove_all).await? {
+                println!(
+                    "Removed {} lock of {} (PID {} on {}, since {})",
+                    info.kind.as_str(),
+                    info.operation,
+                    info.pid,
+                    info.hostname,
+                    info.created_at.format("%Y-%m-%d %H:%M:%S")
+                );
+            }
+            let remaining = lock::list_locks(storage.as_ref()).await?;
+            if !remaining.is_empty() {
+                println!(
+                    "{} locks are held by running processes, use --remove-all to remove them anyway",
+                    remaining.len()
+                );
+            }
+        }
+
+        Commands::Stats { storage } => {
+            println!("📊 Storage statistics for: {}", storage.display());
+
+            let (storage_config, backup_config) = open_repository(&storage, &cli.password).await?;
+            let agent = BackupAgent::new(backup_config, storage_config).await?;
+            let storage_stats = agent.storage().stats().await?;
+            println!("Stored objects: {}", storage_stats.total_chunks);
+            println!("Total bytes: {}", storage_stats.total_bytes);
+
+            let snapshots = agent.list_snapshots().await?;
+            println!("Snapshots: {}", snapshots.len());
+            if let Some(stats) = snapshots.iter().rev().find_map(|s| s.stats.as_ref()) {
+                println!("\nLast backup:");
+                print_backup_stats(stats);
+            }
+        }
+
+        Commands::Init {
+            storage,
+            encrypt,
+            pack_size,
+        } => {
             println!("🎯 Initializing backup repository...");
             println!("Storage: {}", storage.display());
 
             // Create storage directory
             tokio::fs::create_dir_all(&storage).await?;
 
+            let encryption = if encrypt {
+                // A random master key encrypts the data; the passphrase only wraps it
+                let passphrase = cli.password.read_new()?;
+                Some(EncryptionKey::generate().wrap(&passphrase)?)
+            } else {
+                None
+            };
+
+            let manager = StorageManager::from_config(local_storage_config(&storage)).await?;
+            let mut config = RepositoryConfig::new(
+                ChunkingStrategy::default(),
+                CompressionAlgorithm::default(),
+                encryption,
+            );
+            config.pack_size = pack_size;
+            let repository = Repository::init(manager.backend(), config).await?;
+
             if encrypt {
-                println!("🔐 Encryption will be enabled for this repository");
-                // Would save encryption config
+                println!("🔐 Encryption enabled for this repository");
             }
 
             println!("✅ Repository initialized!");
+            println!("Repository ID: {}", repository.config().id);
         }
 
+        Commands::Key { command } => run_key_command(command, &cli.password).await?,
+
         Commands::Server { config, port } => {
             println!("🌐 Starting BackupForge server...");
             println!("Config: {}", config.display());
@@ -231,3 +758,247 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Parse a duration such as 36h, 14d, 2w or 1y6m into hours; a month is
+/// 30 days and a year 365
+fn parse_hours(value: &str) -> Result<u64, String> {
+    let mut hours = 0u64;
+    let mut number = String::new();
+
+    for c in value.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c {
+            'h' => 1,
+            'd' => 24,
+            'w' => 24 * 7,
+            'm' => 24 * 30,
+            'y' => 24 * 365,
+            _ => return Err(format!("unknown duration unit {:?}", c)),
+        };
+        let count: u64 = number
+            .parse()
+            .map_err(|_| format!("invalid duration {:?}", value))?;
+        hours = count
+            .checked_mul(unit)
+            .and_then(|h| hours.checked_add(h))
+            .ok_or_else(|| format!("duration {:?} is too long", value))?;
+        number.clear();
+    }
+
+    if !number.is_empty() || hours == 0 {
+        return Err(format!("invalid duration {:?}, expected for example 14d", value));
+    }
+    Ok(hours)
+}
+
+/// Parse a percentage such as 10% or 2.5%
+fn parse_percent(value: &str) -> Result<f64, String> {
+    let number = value.trim().trim_end_matches('%');
+    match number.parse::<f64>() {
+        Ok(percent) if percent > 0.0 && percent <= 100.0 => Ok(percent),
+        _ => Err(format!("invalid percentage {:?}, expected for example 10%", value)),
+    }
+}
+
+/// Parse a byte count with an optional K, M, G or T suffix (powers of 1024)
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, shift) = match value.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let shift = match c.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(format!("unknown size suffix {:?}", c)),
+            };
+            (&value[..i], shift)
+        }
+        _ => (value, 0),
+    };
+
+    let number: u64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size {:?}", value))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size {:?} is too large", value))
+}
+
+/// Parse a pack size in MiB into bytes
+fn parse_pack_size(value: &str) -> Result<u64, String> {
+    let mib: u64 = value
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid pack size {:?}", value))?;
+    mib.checked_mul(1024 * 1024)
+        .ok_or_else(|| format!("pack size {:?} is too large", value))
+}
+
+/// Storage config for a repository on the local filesystem
+fn local_storage_config(storage: &Path) -> StorageConfig {
+    StorageConfig::Local {
+        path: storage.to_string_lossy().to_string(),
+    }
+}
+
+/// Open an initialized repository, unlocking it if it is encrypted
+async fn open_repository(
+    storage: &Path,
+    password: &PasswordOptions,
+) -> anyhow::Result<(StorageConfig, BackupConfig)> {
+    let (storage_config, repository) = load_repository(storage).await?;
+
+    let key = if repository.is_encrypted() {
+        Some(repository.unlock(&password.credential()?)?)
+    } else {
+        None
+    };
+
+    Ok((storage_config, repository.backup_config(key)))
+}
+
+async fn load_repository(storage: &Path) -> anyhow::Result<(StorageConfig, Repository)> {
+    if !storage.exists() {
+        anyhow::bail!("No repository at {}", storage.display());
+    }
+
+    let storage_config = local_storage_config(storage);
+    let manager = StorageManager::from_config(storage_config.clone()).await?;
+    let repository = Repository::open(manager.backend()).await?;
+
+    Ok((storage_config, repository))
+}
+
+/// Key slot management; every subcommand first proves access to the master key
+async fn run_key_command(command: KeyCommands, password: &PasswordOptions) -> anyhow::Result<()> {
+    let storage = match &command {
+        KeyCommands::List { storage }
+        | KeyCommands::Add { storage, .. }
+        | KeyCommands::Remove { storage, .. }
+        | KeyCommands::Passwd { storage, .. } => storage,
+    };
+
+    let (_, mut repository) = load_repository(storage).await?;
+
+    // Key changes rewrite the config, so they must not overlap; the config
+    // is read again once no other change can be in progress
+    let lock = match command {
+        KeyCommands::List { .. } => None,
+        _ => {
+            let lock =
+                RepositoryLock::acquire(repository.storage(), LockKind::Exclusive, "key").await?;
+            repository = load_repository(storage).await?.1;
+            Some(lock)
+        }
+    };
+
+    let result = async {
+        if !repository.is_encrypted() {
+            anyhow::bail!("Repository is not encrypted, it has no keys");
+        }
+        let (current, master) = repository
+            .unlock_slot(&password.credential()?)
+            .map(|(slot, key)| (slot.id.clone(), key))?;
+
+        apply_key_command(command, &mut repository, &current, &master).await
+    }
+    .await;
+    if let Some(lock) = lock {
+        lock.release().await?;
+    }
+    result
+}
+
+async fn apply_key_command(
+    command: KeyCommands,
+    repository: &mut Repository,
+    current: &str,
+    master: &EncryptionKey,
+) -> anyhow::Result<()> {
+    match command {
+        KeyCommands::List { .. } => {
+            for slot in repository.key_slots() {
+                println!(
+                    "{} {}  {:<10}  {}  {}",
+                    if slot.id == current { "*" } else { " " },
+                    slot.id,
+                    slot.kind(),
+                    slot.created_at.format("%Y-%m-%d %H:%M:%S"),
+                    slot.label
+                );
+            }
+        }
+
+        KeyCommands::Add {
+            label,
+            new_key_file,
+            new_password_file,
+            ..
+        } => {
+            let credential = match new_key_file {
+                Some(path) => Credential::KeyFile(password::create_key_file(&path)?),
+                None => Credential::Passphrase(password::read_new_from(
+                    new_password_file.as_deref(),
+                )?),
+            };
+
+            let slot = repository.add_key(master, &credential, &label).await?;
+            println!("✅ Added {} key slot {}", slot.kind(), slot.id);
+        }
+
+        KeyCommands::Remove { id, .. } => {
+            let id = repository.key_slot(&id)?.id.clone();
+            if id == current {
+                anyhow::bail!("Refusing to remove the key slot used to unlock the repository");
+            }
+
+            let slot = repository.remove_key(&id).await?;
+            println!("✅ Removed key slot {}", slot.id);
+        }
+
+        KeyCommands::Passwd {
+            id,
+            new_password_file,
+            ..
+        } => {
+            let slot = repository.key_slot(id.as_deref().unwrap_or(current))?;
+            if !slot.key.is_passphrase() {
+                anyhow::bail!("Key slot {} is a key file slot, add a new one instead", slot.id);
+            }
+            let id = slot.id.clone();
+
+            let passphrase = password::read_new_from(new_password_file.as_deref())?;
+
+            repository
+                .change_key(&id, master, &Credential::Passphrase(passphrase))
+                .await?;
+            println!("✅ Changed passphrase of key slot {}", id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the statistics a backup run recorded on its snapshot
+fn print_backup_stats(stats: &BackupStats) {
+    println!("Changed files: {} ({} unchanged)", stats.changed_files, stats.unchanged_files);
+    println!("New chunks: {} ({} reused)", stats.new_chunks, stats.reused_chunks);
+    println!(
+        "New data: {} bytes, {} compressed ({:.1}%)",
+        stats.new_bytes,
+        stats.compressed_bytes,
+        if stats.new_bytes > 0 {
+            stats.compressed_bytes as f64 / stats.new_bytes as f64 * 100.0
+        } else {
+            0.0
+        }
+    );
+    println!("Stored: {} bytes", stats.stored_bytes);
+    println!("Duration: {}s", stats.duration_seconds);
+}
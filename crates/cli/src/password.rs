@@ -0,0 +1,211 @@
+use anyhow::{anyhow, bail, Context, Result};
+use backupforge_core::{Credential, EncryptionKey};
+use clap::Args;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Environment variable holding the repository passphrase itself
+const PASSWORD_ENV: &str = "BACKUPFORGE_PASSWORD";
+
+/// Where the repository passphrase comes from
+///
+/// Sources are tried in order: `--password-file`, `--password-command`,
+/// `BACKUPFORGE_PASSWORD`, then an interactive prompt. `--key-file` unlocks
+/// with a key file slot instead.
+#[derive(Debug, Clone, Default, Args)]
+pub struct PasswordOptions {
+    /// Unlock the repository with a key file instead of a passphrase
+    #[arg(long, global = true, env = "BACKUPFORGE_KEY_FILE")]
+    pub key_file: Option<PathBuf>,
+
+    /// Read the repository passphrase from a file
+    #[arg(long, global = true, env = "BACKUPFORGE_PASSWORD_FILE")]
+    pub password_file: Option<PathBuf>,
+
+    /// Run a command and use its output as the repository passphrase
+    #[arg(long, global = true, env = "BACKUPFORGE_PASSWORD_COMMAND")]
+    pub password_command: Option<String>,
+}
+
+impl PasswordOptions {
+    /// Credential to unlock an existing repository
+    pub fn credential(&self) -> Result<Credential> {
+        match self.key_file {
+            Some(ref path) => read_key_file(path).map(Credential::KeyFile),
+            None => self.read().map(Credential::Passphrase),
+        }
+    }
+
+    /// Passphrase to unlock an existing key
+    pub fn read(&self) -> Result<String> {
+        match self.non_interactive()? {
+            Some(passphrase) => Ok(passphrase),
+            None => prompt("Enter repository passphrase: "),
+        }
+    }
+
+    /// Passphrase for a new key, confirmed when typed interactively
+    pub fn read_new(&self) -> Result<String> {
+        confirm_new(self.non_interactive()?)
+    }
+
+    fn non_interactive(&self) -> Result<Option<String>> {
+        if let Some(ref path) = self.password_file {
+            return read_password_file(path).map(Some);
+        }
+
+        if let Some(ref command) = self.password_command {
+            return run_password_command(command).map(Some);
+        }
+
+        Ok(std::env::var(PASSWORD_ENV).ok())
+    }
+}
+
+/// Passphrase for a new key slot, read from `file` or a confirmed prompt
+///
+/// The usual sources already hold the passphrase that unlocks the
+/// repository, so a new one must come from elsewhere.
+pub fn read_new_from(file: Option<&Path>) -> Result<String> {
+    let passphrase = match file {
+        Some(path) => Some(read_password_file(path)?),
+        None => None,
+    };
+
+    confirm_new(passphrase)
+}
+
+fn confirm_new(passphrase: Option<String>) -> Result<String> {
+    let passphrase = match passphrase {
+        Some(passphrase) => passphrase,
+        None => {
+            let passphrase = prompt("Enter new passphrase: ")?;
+            if prompt("Confirm new passphrase: ")? != passphrase {
+                bail!("Passphrases do not match");
+            }
+            passphrase
+        }
+    };
+
+    if passphrase.is_empty() {
+        bail!("Passphrase must not be empty");
+    }
+
+    Ok(passphrase)
+}
+
+/// Read a raw 32-byte key file
+pub fn read_key_file(path: &Path) -> Result<EncryptionKey> {
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("Failed to read key file {}", path.display()))?;
+    EncryptionKey::from_bytes(&bytes)
+        .with_context(|| format!("Invalid key file {}", path.display()))
+}
+
+/// Write a fresh random key file, readable only by its owner; never overwrites
+pub fn create_key_file(path: &Path) -> Result<EncryptionKey> {
+    let key = EncryptionKey::generate();
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed to create key file {}", path.display()))?;
+    file.write_all(key.as_bytes())?;
+    file.sync_all()?;
+
+    Ok(key)
+}
+
+fn read_password_file(path: &Path) -> Result<String> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read password file {}", path.display()))?;
+    Ok(strip_newline(contents))
+}
+
+/// Prompt on the terminal without echoing input
+fn prompt(message: &str) -> Result<String> {
+    if !std::io::stdin().is_terminal() {
+        bail!(
+            "No passphrase available: use --password-file, --password-command or {}",
+            PASSWORD_ENV
+        );
+    }
+
+    rpassword::prompt_password(message).context("Failed to read passphrase")
+}
+
+fn run_password_command(command: &str) -> Result<String> {
+    let output = if cfg!(windows) {
+        Command::new("cmd").args(["/C", command]).output()
+    } else {
+        Command::new("sh").args(["-c", command]).output()
+    }
+    .with_context(|| format!("Failed to run password command `{}`", command))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Password command `{}` exited with {}",
+            command,
+            output.status
+        ));
+    }
+
+    let stdout =
+        String::from_utf8(output.stdout).context("Password command output is not UTF-8")?;
+    Ok(strip_newline(stdout))
+}
+
+/// Drop one trailing line ending, as left by `echo` or an editor
+fn strip_newline(mut s: String) -> String {
+    if s.ends_with('\n') {
+        s.pop();
+        if s.ends_with('\r') {
+            s.pop();
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_file_strips_one_newline() {
+        let dir = std::env::temp_dir().join(format!("backupforge-pw-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("password");
+        std::fs::write(&path, "secret \r\n").unwrap();
+
+        let options = PasswordOptions {
+            password_file: Some(path.clone()),
+            ..Default::default()
+        };
+        assert_eq!(options.read().unwrap(), "secret ");
+        assert_eq!(read_new_from(Some(&path)).unwrap(), "secret ");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_key_file_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("backupforge-key-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("repo.key");
+
+        let key = create_key_file(&path).unwrap();
+        assert_eq!(read_key_file(&path).unwrap().as_bytes(), key.as_bytes());
+        // An existing key file is never overwritten
+        assert!(create_key_file(&path).is_err());
+
+        std::fs::write(&path, b"too short").unwrap();
+        assert!(read_key_file(&path).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
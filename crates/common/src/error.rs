@@ -22,9 +22,21 @@ pub enum Error {
     #[error("Chunk not found: {0}")]
     ChunkNotFound(String),
 
+    #[error("Metadata not found: {0}")]
+    MetadataNotFound(String),
+
     #[error("Snapshot not found: {0}")]
     SnapshotNotFound(String),
 
+    #[error("Data corruption detected: {0}")]
+    Corruption(String),
+
+    #[error("Repository not initialized: {0}")]
+    RepositoryNotInitialized(String),
+
+    #[error("Repository is locked: {0}")]
+    RepositoryLocked(String),
+
     #[error("Invalid configuration: {0}")]
     InvalidConfig(String),
 
@@ -5,6 +5,12 @@ pub fn hash_data(data: &[u8]) -> Vec<u8> {
     Hasher::new().update(data).finalize().as_bytes().to_vec()
 }
 
+/// Hash data with BLAKE3 in keyed mode, so the result reveals nothing
+/// about the content without the key
+pub fn hash_data_keyed(key: &[u8; 32], data: &[u8]) -> Vec<u8> {
+    blake3::keyed_hash(key, data).as_bytes().to_vec()
+}
+
 /// Hash data and return as hex string
 pub fn hash_data_hex(data: &[u8]) -> String {
     hex::encode(hash_data(data))
@@ -28,4 +34,13 @@ mod tests {
         let hash2 = hash_data(b"data2");
         assert_ne!(hash1, hash2);
     }
+
+    #[test]
+    fn test_keyed_hash() {
+        let data = b"test data";
+        let keyed = hash_data_keyed(&[7u8; 32], data);
+        assert_eq!(keyed, hash_data_keyed(&[7u8; 32], data));
+        assert_ne!(keyed, hash_data_keyed(&[8u8; 32], data));
+        assert_ne!(keyed, hash_data(data));
+    }
 }
@@ -0,0 +1,13 @@
+//! Serialize byte strings as hex in JSON metadata, for use with
+//! `#[serde(with = "backupforge_common::hex_bytes")]`
+
+use serde::{Deserialize, Deserializer, Serializer};
+
+pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(bytes))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    hex::decode(s).map_err(serde::de::Error::custom)
+}
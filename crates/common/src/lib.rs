@@ -1,5 +1,6 @@
 pub mod types;
 pub mod error;
 pub mod hash;
+pub mod hex_bytes;
 
 pub use error::{Error, Result};
@@ -50,6 +50,9 @@ pub struct Snapshot {
     pub chunk_ids: Vec<ChunkId>,
     pub parent_snapshot: Option<SnapshotId>,
     pub tags: Vec<String>,
+    /// What the run that created this snapshot did; absent on older snapshots
+    #[serde(default)]
+    pub stats: Option<BackupStats>,
 }
 
 /// File metadata in a backup
@@ -61,6 +64,89 @@ pub struct FileMetadata {
     pub permissions: u32,
     pub is_directory: bool,
     pub chunk_ids: Vec<ChunkId>,
+    /// What kind of entry this is; only regular files have chunks
+    #[serde(default)]
+    pub kind: NodeKind,
+    /// Unallocated ranges of a sparse file, in order; chunks hold only the
+    /// data between them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub holes: Vec<FileExtent>,
+    /// Inode number, on platforms that have one
+    #[serde(default)]
+    pub inode: Option<u64>,
+    /// Last status change time, on platforms that record one
+    #[serde(default)]
+    pub ctime: Option<DateTime<Utc>>,
+    /// Ownership, mode, access time, xattrs and ACLs on Unix
+    #[serde(default)]
+    pub unix: Option<UnixMetadata>,
+}
+
+impl FileMetadata {
+    /// Whether a file stat'ed as `other` can reuse this entry's chunks without
+    /// being read again
+    pub fn is_unchanged(&self, other: &FileMetadata) -> bool {
+        self.path == other.path
+            && self.is_directory == other.is_directory
+            && self.kind == other.kind
+            && self.size == other.size
+            && self.modified == other.modified
+            && self.inode == other.inode
+            && self.ctime == other.ctime
+    }
+}
+
+/// Type of a filesystem entry in a snapshot
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NodeKind {
+    #[default]
+    File,
+    Directory,
+    Symlink { target: String },
+    /// Another name for a file earlier in the snapshot, whose path is `target`
+    HardLink { target: String },
+    CharDevice { rdev: u64 },
+    BlockDevice { rdev: u64 },
+    Fifo,
+    Socket,
+}
+
+/// A byte range of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileExtent {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// POSIX metadata of a file, re-applied on restore
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnixMetadata {
+    /// Full `st_mode`, including the file type bits
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Owner name, preferred over `uid` when it exists on the restoring host
+    pub user: Option<String>,
+    /// Group name, preferred over `gid` when it exists on the restoring host
+    pub group: Option<String>,
+    pub accessed: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub xattrs: Vec<ExtendedAttribute>,
+    /// POSIX access ACL entries in `getfacl -n` form, such as "user:1000:r-x"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub acl_access: Vec<String>,
+    /// Default ACL entries of a directory, in the same form
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub acl_default: Vec<String>,
+}
+
+/// One extended attribute; values are binary and stored as hex
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtendedAttribute {
+    pub name: String,
+    #[serde(with = "crate::hex_bytes")]
+    pub value: Vec<u8>,
 }
 
 /// Backup job configuration
@@ -75,13 +161,67 @@ pub struct BackupJob {
     pub enabled: bool,
     pub encryption_enabled: bool,
     pub compression_level: u8,
+    /// Which snapshots `forget` keeps; without one, those of the last
+    /// `retention_days` days
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
+}
+
+impl BackupJob {
+    /// The policy applied to this job's snapshots
+    pub fn retention_policy(&self) -> RetentionPolicy {
+        self.retention.clone().unwrap_or_else(|| RetentionPolicy {
+            keep_within_hours: Some(u64::from(self.retention_days) * 24),
+            ..RetentionPolicy::default()
+        })
+    }
+}
+
+/// Which snapshots of a source to keep, grandfather-father-son style
+///
+/// A snapshot is kept if any rule keeps it. The periodic rules keep the newest
+/// snapshot of each of the last N hours, days, ISO weeks, months or years
+/// that have one, in UTC.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    #[serde(default)]
+    pub keep_last: Option<u32>,
+    #[serde(default)]
+    pub keep_hourly: Option<u32>,
+    #[serde(default)]
+    pub keep_daily: Option<u32>,
+    #[serde(default)]
+    pub keep_weekly: Option<u32>,
+    #[serde(default)]
+    pub keep_monthly: Option<u32>,
+    #[serde(default)]
+    pub keep_yearly: Option<u32>,
+    /// Keep every snapshot made this many hours before the newest one or later
+    #[serde(default)]
+    pub keep_within_hours: Option<u64>,
+    /// Keep every snapshot with any of these tags
+    #[serde(default)]
+    pub keep_tags: Vec<String>,
+}
+
+impl RetentionPolicy {
+    /// Whether no rule is set, so the policy would keep nothing
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
 /// Source of backup data
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum BackupSource {
-    LocalPath { path: String, excludes: Vec<String> },
+    LocalPath {
+        path: String,
+        /// Gitignore-style patterns, matched relative to `path`
+        excludes: Vec<String>,
+        #[serde(default)]
+        exclude_options: ExcludeOptions,
+    },
     RemoteSSH { host: String, port: u16, user: String, path: String },
     ProxmoxVM { node: String, vmid: String },
     LXC { node: String, ctid: String },
@@ -96,14 +236,50 @@ pub enum BackupSource {
     GCPVM { instance_name: String, zone: String, project_id: String },
 }
 
-/// Backup statistics
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Exclusions of a local path backup beyond its patterns
+///
+/// `.backupforge-ignore` files are always honoured, with the same syntax as
+/// the patterns, for the directory they are in and below it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExcludeOptions {
+    /// Files of further patterns, one per line
+    #[serde(default)]
+    pub exclude_files: Vec<String>,
+    /// Skip directories tagged as caches with a `CACHEDIR.TAG`
+    #[serde(default)]
+    pub exclude_caches: bool,
+    /// Do not descend into other mounted file systems
+    #[serde(default)]
+    pub one_file_system: bool,
+    /// Skip files larger than this many bytes
+    #[serde(default)]
+    pub exclude_larger_than: Option<u64>,
+}
+
+/// Statistics of one backup run
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct BackupStats {
+    /// Files in the snapshot
     pub total_files: u64,
+    /// Files read and chunked
+    #[serde(default)]
+    pub changed_files: u64,
+    /// Files whose chunks were reused from the parent snapshot without reading
+    #[serde(default)]
+    pub unchanged_files: u64,
+    /// Size of every file in the snapshot
     pub total_bytes: u64,
     pub new_chunks: u64,
+    /// Chunks already in the repository
     pub reused_chunks: u64,
+    /// Size of the new chunks before compression
+    #[serde(default)]
+    pub new_bytes: u64,
+    /// Size of the new chunks after compression
     pub compressed_bytes: u64,
+    /// Bytes written to storage, including chunk headers and encryption overhead
+    #[serde(default)]
+    pub stored_bytes: u64,
     pub duration_seconds: u64,
 }
 
@@ -0,0 +1,379 @@
+use backupforge_common::{
+    types::{ChunkId, NodeKind, SnapshotId},
+    Result,
+};
+use backupforge_storage::StorageBackend;
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::{
+    engine::ChunkCodec,
+    manifest::{SnapshotManifest, SNAPSHOTS_PREFIX},
+    pipeline::OrderedTasks,
+    sparse,
+};
+
+/// How much chunk data a check downloads and verifies
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ReadData {
+    /// Only check that referenced chunks exist
+    #[default]
+    None,
+    All,
+    /// A random share of the referenced chunks, in percent
+    Subset(f64),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CheckOptions {
+    pub read_data: ReadData,
+    /// Snapshots to check; empty checks every snapshot
+    pub snapshots: Vec<SnapshotId>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    Config,
+    Manifest,
+    MissingChunk,
+    DamagedChunk,
+}
+
+impl ProblemKind {
+    /// Name as it appears in the JSON report
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProblemKind::Config => "config",
+            ProblemKind::Manifest => "manifest",
+            ProblemKind::MissingChunk => "missing_chunk",
+            ProblemKind::DamagedChunk => "damaged_chunk",
+        }
+    }
+}
+
+/// One thing found wrong with the repository
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckProblem {
+    pub kind: ProblemKind,
+    /// Metadata key, snapshot ID or chunk ID the problem is about
+    pub subject: String,
+    pub message: String,
+}
+
+/// Outcome of a repository check
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CheckReport {
+    pub snapshots: usize,
+    pub referenced_chunks: usize,
+    /// Chunks downloaded and verified against their IDs
+    pub chunks_read: usize,
+    pub bytes_read: u64,
+    pub problems: Vec<CheckProblem>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn add_problem(&mut self, kind: ProblemKind, subject: &str, message: impl ToString) {
+        self.problems.push(CheckProblem {
+            kind,
+            subject: subject.to_string(),
+            message: message.to_string(),
+        });
+    }
+}
+
+/// Check manifests, then that the chunks they reference exist, then read
+/// back the chunks `options` asks for with `workers` downloads in flight
+///
+/// Problems are collected in the report; only failing to list the
+/// repository is an error.
+pub(crate) async fn run(
+    storage: Arc<dyn StorageBackend>,
+    codec: Arc<ChunkCodec>,
+    workers: usize,
+    options: &CheckOptions,
+) -> Result<CheckReport> {
+    let mut report = CheckReport::default();
+
+    let keys = if options.snapshots.is_empty() {
+        storage.list_metadata(SNAPSHOTS_PREFIX).await?
+    } else {
+        options.snapshots.iter().map(SnapshotManifest::key).collect()
+    };
+
+    // Each chunk with the first snapshot found referencing it
+    let mut references: HashMap<ChunkId, SnapshotId> = HashMap::new();
+    for key in keys {
+        let Some(manifest) = load_manifest(storage.as_ref(), &key, &mut report).await else {
+            continue;
+        };
+        report.snapshots += 1;
+
+        let id = manifest.snapshot.id.clone();
+        for file in &manifest.files {
+            let data_bytes = file.size.saturating_sub(sparse::hole_bytes(&file.holes));
+            if file.kind == NodeKind::File && data_bytes > 0 && file.chunk_ids.is_empty() {
+                report.add_problem(
+                    ProblemKind::Manifest,
+                    &id.0.to_string(),
+                    format!("{} has {} bytes of data but no chunks", file.path, data_bytes),
+                );
+            }
+            for chunk_id in &file.chunk_ids {
+                references
+                    .entry(chunk_id.clone())
+                    .or_insert_with(|| id.clone());
+            }
+        }
+    }
+    report.referenced_chunks = references.len();
+
+    let mut stored: HashSet<ChunkId> = storage.list_chunks().await?.into_iter().collect();
+    // An index may still list chunks whose pack was deleted
+    for chunk_id in storage.lost_chunks().await? {
+        stored.remove(&chunk_id);
+    }
+    let mut present = Vec::with_capacity(references.len());
+    let mut missing: Vec<(&ChunkId, &SnapshotId)> = Vec::new();
+    for (chunk_id, snapshot) in &references {
+        if stored.contains(chunk_id) {
+            present.push(chunk_id.clone());
+        } else {
+            missing.push((chunk_id, snapshot));
+        }
+    }
+    missing.sort_by(|a, b| a.0 .0.cmp(&b.0 .0));
+    for (chunk_id, snapshot) in missing {
+        report.add_problem(
+            ProblemKind::MissingChunk,
+            &chunk_id.0,
+            format!("Referenced by snapshot {} but not stored", snapshot.0),
+        );
+    }
+
+    let selected = match options.read_data {
+        ReadData::None => Vec::new(),
+        ReadData::All => present,
+        ReadData::Subset(percent) => {
+            let count = (present.len() as f64 * percent.clamp(0.0, 100.0) / 100.0).ceil();
+            present.shuffle(&mut rand::thread_rng());
+            present.truncate(count as usize);
+            present
+        }
+    };
+    read_chunks(storage, codec, workers.max(1), selected, &mut report).await?;
+
+    Ok(report)
+}
+
+/// Load and sanity check one manifest, recording why it is unusable
+async fn load_manifest(
+    storage: &dyn StorageBackend,
+    key: &str,
+    report: &mut CheckReport,
+) -> Option<SnapshotManifest> {
+    let data = match storage.get_metadata(key).await {
+        Ok(data) => data,
+        Err(e) => {
+            report.add_problem(ProblemKind::Manifest, key, e);
+            return None;
+        }
+    };
+
+    let manifest: SnapshotManifest = match serde_json::from_slice(&data) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            report.add_problem(ProblemKind::Manifest, key, format!("Unreadable: {}", e));
+            return None;
+        }
+    };
+
+    if SnapshotManifest::key(&manifest.snapshot.id) != key {
+        report.add_problem(
+            ProblemKind::Manifest,
+            key,
+            format!("Holds snapshot {}", manifest.snapshot.id.0),
+        );
+    }
+
+    Some(manifest)
+}
+
+/// Download, decrypt, decompress and re-hash `chunks`
+async fn read_chunks(
+    storage: Arc<dyn StorageBackend>,
+    codec: Arc<ChunkCodec>,
+    workers: usize,
+    chunks: Vec<ChunkId>,
+    report: &mut CheckReport,
+) -> Result<()> {
+    let mut tasks = OrderedTasks::new();
+    let mut chunks = chunks.into_iter();
+
+    loop {
+        while tasks.len() < workers {
+            let Some(chunk_id) = chunks.next() else {
+                break;
+            };
+            let storage = storage.clone();
+            let codec = codec.clone();
+            tasks.push(tokio::spawn(async move {
+                let outcome = match storage.get_chunk(&chunk_id).await {
+                    Ok(stored) => {
+                        let len = stored.len() as u64;
+                        codec.decode(&chunk_id, stored).map(|_| len)
+                    }
+                    Err(e) => Err(e),
+                };
+                Ok((chunk_id, outcome))
+            }));
+        }
+
+        let Some(result) = tasks.next().await else {
+            return Ok(());
+        };
+        let (chunk_id, outcome) = result?;
+        match outcome {
+            Ok(len) => {
+                report.chunks_read += 1;
+                report.bytes_read += len;
+            }
+            Err(e) => report.add_problem(ProblemKind::DamagedChunk, &chunk_id.0, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{BackupConfig, BackupEngine};
+    use backupforge_storage::{packed::DEFAULT_PACK_SIZE, LocalStorage, PackedStorage};
+    use tempfile::TempDir;
+
+    /// An engine on local storage with one snapshot of two files
+    async fn repository(temp_dir: &TempDir) -> (BackupEngine, Arc<dyn StorageBackend>) {
+        let storage: Arc<dyn StorageBackend> =
+            Arc::new(LocalStorage::new(temp_dir.path().join("repo")).await.unwrap());
+        let engine = BackupEngine::new(BackupConfig::default(), storage.clone());
+
+        let mut files = Vec::new();
+        for (name, content) in [("a.txt", "first file"), ("b.txt", "second file")] {
+            let path = temp_dir.path().join(name);
+            tokio::fs::write(&path, content).await.unwrap();
+            files.push(engine.backup_file(&path).await.unwrap());
+        }
+        engine
+            .create_snapshot("test".to_string(), "/src".to_string(), files, None)
+            .await
+            .unwrap();
+
+        (engine, storage)
+    }
+
+    fn kinds(report: &CheckReport) -> Vec<ProblemKind> {
+        report.problems.iter().map(|p| p.kind).collect()
+    }
+
+    #[tokio::test]
+    async fn test_healthy_repository() {
+        let temp_dir = TempDir::new().unwrap();
+        let (engine, _) = repository(&temp_dir).await;
+
+        let options = CheckOptions {
+            read_data: ReadData::All,
+            ..CheckOptions::default()
+        };
+        let report = engine.check(&options).await.unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.snapshots, 1);
+        assert_eq!(report.referenced_chunks, 2);
+        assert_eq!(report.chunks_read, 2);
+
+        let options = CheckOptions {
+            read_data: ReadData::Subset(50.0),
+            ..CheckOptions::default()
+        };
+        assert_eq!(engine.check(&options).await.unwrap().chunks_read, 1);
+    }
+
+    #[tokio::test]
+    async fn test_missing_and_damaged_chunks() {
+        let temp_dir = TempDir::new().unwrap();
+        let (engine, storage) = repository(&temp_dir).await;
+
+        let mut chunks = storage.list_chunks().await.unwrap();
+        chunks.sort_by(|a, b| a.0.cmp(&b.0));
+        storage.delete_chunk(&chunks[0]).await.unwrap();
+        let mut stored = storage.get_chunk(&chunks[1]).await.unwrap();
+        let last = stored.len() - 1;
+        stored[last] ^= 0xff;
+        storage.put_chunk(&chunks[1], stored).await.unwrap();
+
+        // Existence only notices the missing chunk
+        let report = engine.check(&CheckOptions::default()).await.unwrap();
+        assert_eq!(kinds(&report), [ProblemKind::MissingChunk]);
+        assert_eq!(report.problems[0].subject, chunks[0].0);
+
+        let options = CheckOptions {
+            read_data: ReadData::All,
+            ..CheckOptions::default()
+        };
+        let report = engine.check(&options).await.unwrap();
+        assert_eq!(
+            kinds(&report),
+            [ProblemKind::MissingChunk, ProblemKind::DamagedChunk]
+        );
+        assert_eq!(report.problems[1].subject, chunks[1].0);
+        assert_eq!(report.chunks_read, 0);
+    }
+
+    #[tokio::test]
+    async fn test_deleted_pack() {
+        let temp_dir = TempDir::new().unwrap();
+        let inner: Arc<dyn StorageBackend> =
+            Arc::new(LocalStorage::new(temp_dir.path().join("repo")).await.unwrap());
+        let storage: Arc<dyn StorageBackend> =
+            Arc::new(PackedStorage::open(inner.clone(), DEFAULT_PACK_SIZE).await.unwrap());
+        let engine = BackupEngine::new(BackupConfig::default(), storage);
+
+        let path = temp_dir.path().join("a.txt");
+        tokio::fs::write(&path, "packed file").await.unwrap();
+        let file = engine.backup_file(&path).await.unwrap();
+        engine
+            .create_snapshot("test".to_string(), "/src".to_string(), vec![file.clone()], None)
+            .await
+            .unwrap();
+
+        let packs = inner.list_chunks().await.unwrap();
+        assert_eq!(packs.len(), 1);
+        inner.delete_chunk(&packs[0]).await.unwrap();
+
+        let report = engine.check(&CheckOptions::default()).await.unwrap();
+        assert_eq!(kinds(&report), [ProblemKind::MissingChunk]);
+        assert_eq!(report.problems[0].subject, file.chunk_ids[0].0);
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        let (engine, storage) = repository(&temp_dir).await;
+
+        let key = format!("{}{}", SNAPSHOTS_PREFIX, SnapshotId::new().0);
+        storage.put_metadata(&key, b"{ not json".to_vec()).await.unwrap();
+
+        let report = engine.check(&CheckOptions::default()).await.unwrap();
+        assert_eq!(kinds(&report), [ProblemKind::Manifest]);
+        assert_eq!(report.problems[0].subject, key);
+        assert_eq!(report.snapshots, 1);
+
+        // The report is meant for machines too
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["problems"][0]["kind"], "manifest");
+    }
+}
@@ -1,12 +1,18 @@
-use backupforge_common::{hash::hash_data, types::{Chunk, ChunkId}, Result, Error};
+use backupforge_common::{
+    hash::{hash_data, hash_data_keyed},
+    types::{Chunk, ChunkId},
+    Error, Result,
+};
 use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 /// Chunking strategy for breaking data into chunks
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ChunkingStrategy {
     /// Fixed-size chunks (simple but less efficient dedup)
     Fixed { size: usize },
-    /// Content-Defined Chunking (CDC) using rolling hash (better dedup)
+    /// Content-Defined Chunking with FastCDC (better dedup)
     ContentDefined {
         min_size: usize,
         avg_size: usize,
@@ -24,118 +30,250 @@ impl Default for ChunkingStrategy {
     }
 }
 
+/// Largest chunk a chunk header can record the length of
+pub const MAX_CHUNK_SIZE: usize = u32::MAX as usize;
+
+impl ChunkingStrategy {
+    /// Check that the sizes can cut chunks: non-zero, at most
+    /// `MAX_CHUNK_SIZE`, and for content-defined chunking ordered
+    /// `min_size <= avg_size <= max_size`
+    pub fn validate(&self) -> Result<()> {
+        match *self {
+            Self::Fixed { size: 0 } => Err(Error::InvalidConfig(
+                "Fixed chunk size must not be zero".to_string(),
+            )),
+            Self::Fixed { size } if size > MAX_CHUNK_SIZE => Err(Error::InvalidConfig(format!(
+                "Fixed chunk size {} exceeds the maximum of {}",
+                size, MAX_CHUNK_SIZE
+            ))),
+            Self::ContentDefined {
+                min_size,
+                avg_size,
+                max_size,
+            } if min_size == 0 || min_size > avg_size || avg_size > max_size => {
+                Err(Error::InvalidConfig(format!(
+                    "Chunk sizes must satisfy 0 < min <= avg <= max, got {}/{}/{}",
+                    min_size, avg_size, max_size
+                )))
+            }
+            Self::ContentDefined { max_size, .. } if max_size > MAX_CHUNK_SIZE => {
+                Err(Error::InvalidConfig(format!(
+                    "Maximum chunk size {} exceeds the maximum of {}",
+                    max_size, MAX_CHUNK_SIZE
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How chunk IDs are derived from chunk contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ChunkIdHash {
+    /// Plain BLAKE3 of the plaintext
+    #[default]
+    Blake3,
+    /// BLAKE3 keyed with a secret derived from the master key, so stored
+    /// object names cannot be matched against known content
+    Blake3Keyed,
+}
+
+/// Bits added to / removed from the average mask by normalized chunking
+const NORMALIZATION_LEVEL: u32 = 2;
+
+/// Random values mixed into the gear hash, one per byte value
+const GEAR: [u64; 256] = gear_table();
+
+/// Fill the gear table with splitmix64 output; any fixed random table works,
+/// but it must never change or every chunk boundary moves
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6261_636b_7570_6667; // "backupfg"
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Mask of the top `bits` bits; in a gear hash these depend on the most bytes
+fn high_bits_mask(bits: u32) -> u64 {
+    !0u64 << (64 - bits.min(63))
+}
+
 /// Chunks data for storage and deduplication
 pub struct Chunker {
     strategy: ChunkingStrategy,
+    id_key: Option<[u8; 32]>,
 }
 
 impl Chunker {
     pub fn new(strategy: ChunkingStrategy) -> Self {
-        Self { strategy }
+        Self {
+            strategy,
+            id_key: None,
+        }
+    }
+
+    /// Derive chunk IDs with BLAKE3 keyed by `id_key` instead of plain BLAKE3
+    pub fn with_id_key(mut self, id_key: Option<[u8; 32]>) -> Self {
+        self.id_key = id_key;
+        self
+    }
+
+    /// Hash chunk contents the way chunk IDs are derived
+    pub fn hash(&self, data: &[u8]) -> Vec<u8> {
+        match self.id_key {
+            Some(ref key) => hash_data_keyed(key, data),
+            None => hash_data(data),
+        }
     }
 
     /// Split data into chunks based on the chunking strategy
     pub fn chunk_data(&self, data: &[u8]) -> Result<Vec<Chunk>> {
-        match &self.strategy {
-            ChunkingStrategy::Fixed { size } => self.chunk_fixed(data, *size),
+        let mut chunks = Vec::new();
+        let mut offset = 0;
+
+        while offset < data.len() {
+            let end = offset + self.cut_point(&data[offset..]);
+            chunks.push(self.make_chunk(data[offset..end].to_vec()));
+            offset = end;
+        }
+
+        Ok(chunks)
+    }
+
+    /// Chunk an async reader incrementally, buffering at most one maximum-size chunk
+    pub fn chunk_stream<R: AsyncRead + Unpin>(&self, reader: R) -> StreamChunker<'_, R> {
+        StreamChunker {
+            chunker: self,
+            reader,
+            buffer: Vec::new(),
+            eof: false,
+        }
+    }
+
+    /// Largest chunk this strategy can produce
+    pub fn max_chunk_size(&self) -> usize {
+        match self.strategy {
+            ChunkingStrategy::Fixed { size } => size,
+            ChunkingStrategy::ContentDefined { max_size, .. } => max_size,
+        }
+    }
+
+    /// Length of the chunk starting at `data[0]`
+    ///
+    /// `data` must hold at least `max_chunk_size` bytes unless it is the end of
+    /// the input, so the same boundaries are found however the input is split.
+    fn cut_point(&self, data: &[u8]) -> usize {
+        match self.strategy {
+            ChunkingStrategy::Fixed { size } => std::cmp::min(size, data.len()),
             ChunkingStrategy::ContentDefined {
                 min_size,
                 avg_size,
                 max_size,
-            } => self.chunk_cdc(data, *min_size, *avg_size, *max_size),
+            } => Self::cut_point_cdc(data, min_size, avg_size, max_size),
         }
     }
 
-    /// Fixed-size chunking
-    fn chunk_fixed(&self, data: &[u8], size: usize) -> Result<Vec<Chunk>> {
-        let mut chunks = Vec::new();
-        let mut offset = 0;
+    /// FastCDC boundary: gear rolling hash with normalized chunking
+    ///
+    /// Before `avg_size` a stricter mask makes cuts rarer and after it a looser
+    /// one makes them likelier, pulling chunk sizes towards the average. Only
+    /// the last 64 bytes influence the masked bits, so an edit shifts at most
+    /// the boundaries around it.
+    fn cut_point_cdc(data: &[u8], min_size: usize, avg_size: usize, max_size: usize) -> usize {
+        // If remaining data is smaller than min_size, it becomes the final chunk
+        if data.len() <= min_size {
+            return data.len();
+        }
 
-        while offset < data.len() {
-            let end = std::cmp::min(offset + size, data.len());
-            let chunk_data = data[offset..end].to_vec();
-            let hash = hash_data(&chunk_data);
+        let bits = avg_size.max(2).ilog2();
+        let mask_small = high_bits_mask(bits + NORMALIZATION_LEVEL);
+        let mask_large = high_bits_mask(bits.saturating_sub(NORMALIZATION_LEVEL).max(1));
 
-            chunks.push(Chunk {
-                id: ChunkId::from_hash(&hash),
-                size: chunk_data.len() as u64,
-                hash,
-                data: chunk_data,
-            });
+        let end = std::cmp::min(max_size, data.len());
+        let normal = avg_size.clamp(min_size, end);
 
-            offset = end;
+        let mut hash: u64 = 0;
+        for (i, &byte) in data.iter().enumerate().take(normal).skip(min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            if hash & mask_small == 0 {
+                return i + 1;
+            }
+        }
+        for (i, &byte) in data.iter().enumerate().take(end).skip(normal) {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            if hash & mask_large == 0 {
+                return i + 1;
+            }
         }
 
-        Ok(chunks)
+        // Force boundary at max_size
+        end
     }
 
-    /// Content-Defined Chunking using FastCDC algorithm
-    fn chunk_cdc(
-        &self,
-        data: &[u8],
-        min_size: usize,
-        avg_size: usize,
-        max_size: usize,
-    ) -> Result<Vec<Chunk>> {
-        let mut chunks = Vec::new();
-        let mut offset = 0;
+    /// Build a chunk from data cut at a boundary, hashing it into its ID
+    pub fn make_chunk(&self, data: Vec<u8>) -> Chunk {
+        let hash = self.hash(&data);
 
-        // Mask for rolling hash boundary detection
-        let mask = (avg_size - 1) as u64;
-        let data_len = data.len();
-
-        while offset < data_len {
-            let remaining = data_len - offset;
-
-            // If remaining data is smaller than min_size, create final chunk
-            if remaining <= min_size {
-                let chunk_data = data[offset..].to_vec();
-                let hash = hash_data(&chunk_data);
-
-                chunks.push(Chunk {
-                    id: ChunkId::from_hash(&hash),
-                    size: chunk_data.len() as u64,
-                    hash,
-                    data: chunk_data,
-                });
-                break;
-            }
+        Chunk {
+            id: ChunkId::from_hash(&hash),
+            size: data.len() as u64,
+            hash,
+            data,
+        }
+    }
+}
 
-            // Find chunk boundary using rolling hash
-            let mut chunk_end = offset + min_size;
-            let search_end = std::cmp::min(offset + max_size, data_len);
+/// Chunks produced one at a time from an async reader
+pub struct StreamChunker<'a, R> {
+    chunker: &'a Chunker,
+    reader: R,
+    buffer: Vec<u8>,
+    eof: bool,
+}
 
-            let mut hash: u64 = 0;
-            for i in (offset + min_size)..search_end {
-                // Simple rolling hash (Rabin fingerprint simplified)
-                hash = hash.wrapping_mul(31).wrapping_add(data[i] as u64);
+impl<R: AsyncRead + Unpin> StreamChunker<'_, R> {
+    /// Read until the next chunk boundary; `None` once the reader is exhausted
+    pub async fn next_chunk(&mut self) -> Result<Option<Chunk>> {
+        let block = self.next_block().await?;
+        Ok(block.map(|data| self.chunker.make_chunk(data)))
+    }
 
-                // Check for chunk boundary
-                if (hash & mask) == 0 {
-                    chunk_end = i + 1;
-                    break;
-                }
+    /// Like `next_chunk` but without hashing, so callers can hash elsewhere
+    pub async fn next_block(&mut self) -> Result<Option<Vec<u8>>> {
+        let window = self.chunker.max_chunk_size();
 
-                // Force boundary at max_size
-                if i == search_end - 1 {
-                    chunk_end = search_end;
+        // Boundaries are only final once a full window is buffered
+        while !self.eof && self.buffer.len() < window {
+            let filled = self.buffer.len();
+            self.buffer.resize(window, 0);
+            let read = self.reader.read(&mut self.buffer[filled..]).await;
+            let n = match read {
+                Ok(n) => n,
+                Err(e) => {
+                    self.buffer.truncate(filled);
+                    return Err(Error::Io(e));
                 }
-            }
-
-            let chunk_data = data[offset..chunk_end].to_vec();
-            let chunk_hash = hash_data(&chunk_data);
-
-            chunks.push(Chunk {
-                id: ChunkId::from_hash(&chunk_hash),
-                size: chunk_data.len() as u64,
-                hash: chunk_hash,
-                data: chunk_data,
-            });
+            };
+            self.buffer.truncate(filled + n);
+            self.eof = n == 0;
+        }
 
-            offset = chunk_end;
+        if self.buffer.is_empty() {
+            return Ok(None);
         }
 
-        Ok(chunks)
+        let len = self.chunker.cut_point(&self.buffer);
+        let rest = self.buffer.split_off(len);
+
+        Ok(Some(std::mem::replace(&mut self.buffer, rest)))
     }
 }
 
@@ -179,4 +317,139 @@ mod tests {
             assert_eq!(c1.id, c2.id);
         }
     }
+
+    #[tokio::test]
+    async fn test_stream_matches_in_memory_chunking() {
+        let data: Vec<u8> = (0..200_000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect();
+        let strategy = ChunkingStrategy::ContentDefined {
+            min_size: 1024,
+            avg_size: 4096,
+            max_size: 16384,
+        };
+        let chunker = Chunker::new(strategy);
+        let expected = chunker.chunk_data(&data).unwrap();
+
+        // A reader returning a few bytes at a time must not move any boundary
+        let (client, mut server) = tokio::io::duplex(777);
+        let writer_data = data.clone();
+        let writer = tokio::spawn(async move {
+            tokio::io::AsyncWriteExt::write_all(&mut server, &writer_data)
+                .await
+                .unwrap();
+        });
+
+        let mut stream = chunker.chunk_stream(client);
+        let mut chunks = Vec::new();
+        while let Some(chunk) = stream.next_chunk().await.unwrap() {
+            assert!(chunk.data.len() <= chunker.max_chunk_size());
+            chunks.push(chunk);
+        }
+        writer.await.unwrap();
+
+        assert!(chunks.len() > 10);
+        assert_eq!(chunks.len(), expected.len());
+        for (a, b) in chunks.iter().zip(&expected) {
+            assert_eq!(a.id, b.id);
+        }
+        let restored: Vec<u8> = chunks.into_iter().flat_map(|c| c.data).collect();
+        assert_eq!(restored, data);
+    }
+
+    /// Deterministic pseudo-random bytes without pulling in a seeded RNG
+    fn pseudo_random(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn small_cdc() -> Chunker {
+        Chunker::new(ChunkingStrategy::ContentDefined {
+            min_size: 2 * 1024,
+            avg_size: 8 * 1024,
+            max_size: 32 * 1024,
+        })
+    }
+
+    #[test]
+    fn test_cdc_chunk_sizes() {
+        let data = pseudo_random(4_000_000, 1);
+        let chunks = small_cdc().chunk_data(&data).unwrap();
+
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.size >= 2 * 1024 && chunk.size <= 32 * 1024);
+        }
+
+        // Normalized chunking keeps the mean close to avg_size
+        let mean = data.len() / chunks.len();
+        assert!((6 * 1024..12 * 1024).contains(&mean), "mean chunk size {}", mean);
+    }
+
+    #[test]
+    fn test_strategy_validation() {
+        assert!(ChunkingStrategy::default().validate().is_ok());
+        assert!(ChunkingStrategy::Fixed { size: 0 }.validate().is_err());
+
+        let cdc = |min_size, avg_size, max_size| ChunkingStrategy::ContentDefined {
+            min_size,
+            avg_size,
+            max_size,
+        };
+        assert!(cdc(1024, 1024, 1024).validate().is_ok());
+        assert!(cdc(0, 1024, 4096).validate().is_err());
+        assert!(cdc(2048, 1024, 4096).validate().is_err());
+        assert!(cdc(1024, 8192, 4096).validate().is_err());
+
+        // Chunk headers record lengths as u32
+        assert!(cdc(1024, 4096, MAX_CHUNK_SIZE).validate().is_ok());
+        if let Some(too_large) = MAX_CHUNK_SIZE.checked_add(1) {
+            let fixed = ChunkingStrategy::Fixed { size: too_large };
+            assert!(fixed.validate().is_err());
+            assert!(cdc(1024, 4096, too_large).validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_cdc_insertion_only_changes_nearby_chunks() {
+        let chunker = small_cdc();
+        let original = pseudo_random(2_000_000, 2);
+        let mut edited = original.clone();
+        edited.splice(1000..1000, b"inserted bytes".iter().copied());
+
+        let before = chunker.chunk_data(&original).unwrap();
+        let after = chunker.chunk_data(&edited).unwrap();
+
+        let known: std::collections::HashSet<_> = before.iter().map(|c| &c.id).collect();
+        let changed = after.iter().filter(|c| !known.contains(&c.id)).count();
+        assert!(before.len() > 100);
+        assert!(changed <= 2, "{} of {} chunks changed", changed, after.len());
+    }
+
+    #[test]
+    fn test_keyed_chunk_ids() {
+        let data = b"test data for chunking";
+        let strategy = ChunkingStrategy::Fixed { size: 10 };
+        let plain = Chunker::new(strategy.clone()).chunk_data(data).unwrap();
+        let keyed = Chunker::new(strategy.clone())
+            .with_id_key(Some([1u8; 32]))
+            .chunk_data(data)
+            .unwrap();
+        let other = Chunker::new(strategy)
+            .with_id_key(Some([2u8; 32]))
+            .chunk_data(data)
+            .unwrap();
+
+        for ((p, k), o) in plain.iter().zip(&keyed).zip(&other) {
+            assert_eq!(p.data, k.data);
+            assert_ne!(p.id, k.id);
+            assert_ne!(k.id, o.id);
+        }
+    }
 }
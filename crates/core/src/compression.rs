@@ -2,7 +2,7 @@ use backupforge_common::{Result, Error};
 use serde::{Deserialize, Serialize};
 
 /// Compression algorithms supported
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CompressionAlgorithm {
     None,
     Zstd(i32),  // Compression level (1-22)
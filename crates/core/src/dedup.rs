@@ -1,6 +1,17 @@
 use backupforge_common::{types::ChunkId, Result, Error};
+use backupforge_storage::StorageBackend;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use uuid::Uuid;
+
+use crate::manifest::ManifestStore;
+
+/// Metadata key prefix for the persistent dedup index journal
+pub const INDEX_PREFIX: &str = "index/";
+
+/// Number of journal segments after which they are folded into a checkpoint
+const MAX_INDEX_SEGMENTS: usize = 32;
 
 /// In-memory deduplication index
 /// Tracks which chunks have already been stored
@@ -60,6 +71,33 @@ impl DedupIndex {
         self.len() == 0
     }
 
+    /// Adjust a chunk's reference count by `delta`; chunks are present while
+    /// their count is positive
+    pub fn apply_delta(&self, chunk_id: &str, delta: i64) {
+        let mut chunks = self.chunks.write().unwrap();
+        let mut refs = self.chunk_refs.write().unwrap();
+
+        let count = refs.get(chunk_id).copied().unwrap_or(0) as i64 + delta;
+        if count > 0 {
+            refs.insert(chunk_id.to_string(), count as u64);
+            chunks.insert(chunk_id.to_string());
+        } else {
+            refs.remove(chunk_id);
+            chunks.remove(chunk_id);
+        }
+    }
+
+    /// Snapshot of all reference counts
+    pub fn ref_counts(&self) -> HashMap<String, u64> {
+        self.chunk_refs.read().unwrap().clone()
+    }
+
+    /// Drop every entry
+    pub fn clear(&self) {
+        self.chunks.write().unwrap().clear();
+        self.chunk_refs.write().unwrap().clear();
+    }
+
     /// Get reference count for a chunk
     pub fn get_ref_count(&self, chunk_id: &ChunkId) -> u64 {
         self.chunk_refs
@@ -77,15 +115,244 @@ impl Default for DedupIndex {
     }
 }
 
+/// One object of the persistent index journal
+///
+/// Segments hold reference count deltas, so they can be replayed in any order
+/// and concurrent writers never conflict. A checkpoint is a segment holding
+/// full counts that lists the segments it already includes.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct IndexSegment {
+    #[serde(default)]
+    supersedes: Vec<String>,
+    entries: HashMap<String, i64>,
+}
+
+/// Unflushed journal state
+#[derive(Default)]
+struct Journal {
+    pending: HashMap<String, i64>,
+    segments: Vec<String>,
+}
+
 /// Deduplication store that combines index and storage
+///
+/// When attached to a storage backend the index is journaled to the
+/// repository's metadata area, so reference counts survive across processes.
 pub struct DedupStore {
     index: DedupIndex,
+    storage: Option<Arc<dyn StorageBackend>>,
+    journal: Mutex<Journal>,
 }
 
 impl DedupStore {
     pub fn new() -> Self {
         Self {
             index: DedupIndex::new(),
+            storage: None,
+            journal: Mutex::new(Journal::default()),
+        }
+    }
+
+    /// Create an empty store that journals changes to `storage`
+    pub fn with_storage(storage: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            storage: Some(storage),
+            ..Self::new()
+        }
+    }
+
+    /// Open the persistent index, rebuilding it from the repository when it is
+    /// missing or corrupt
+    pub async fn open(storage: Arc<dyn StorageBackend>) -> Result<Self> {
+        let store = Self::with_storage(storage);
+        store.reload().await?;
+        Ok(store)
+    }
+
+    /// Load the persistent index again, picking up changes other processes
+    /// made since it was loaded, such as a prune; unflushed changes are kept
+    pub async fn reload(&self) -> Result<()> {
+        let storage = self.storage()?;
+        let keys = storage.list_metadata(INDEX_PREFIX).await?;
+
+        if keys.is_empty() {
+            if !storage.list_chunks().await?.is_empty() {
+                tracing::info!("Dedup index missing, rebuilding from repository");
+                return self.rebuild().await;
+            }
+            return self.replay(&keys).await;
+        }
+
+        if let Err(e) = self.replay(&keys).await {
+            tracing::warn!("Dedup index unreadable ({}), rebuilding from repository", e);
+            self.rebuild().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Load journal segments into the in-memory index, with unflushed
+    /// changes applied on top
+    async fn replay(&self, keys: &[String]) -> Result<()> {
+        let storage = self.storage()?;
+        let mut segments = Vec::with_capacity(keys.len());
+
+        for key in keys {
+            let data = storage.get_metadata(key).await?;
+            let segment: IndexSegment = serde_json::from_slice(&data).map_err(|e| {
+                Error::Deduplication(format!("Invalid index segment {}: {}", key, e))
+            })?;
+            segments.push((key.clone(), segment));
+        }
+
+        // Skip segments already folded into a checkpoint. A segment claimed by
+        // two checkpoints would be counted twice, so treat that as corruption.
+        let mut superseded = HashSet::new();
+        for (_, segment) in &segments {
+            for key in &segment.supersedes {
+                if !superseded.insert(key.clone()) {
+                    return Err(Error::Deduplication(format!(
+                        "Index segment {} is included by more than one checkpoint",
+                        key
+                    )));
+                }
+            }
+        }
+
+        // Sum deltas before applying them, since counts are clamped at zero
+        let mut totals: HashMap<&str, i64> = HashMap::new();
+        for (key, segment) in &segments {
+            if superseded.contains(key) {
+                continue;
+            }
+            for (chunk_id, delta) in &segment.entries {
+                *totals.entry(chunk_id).or_insert(0) += delta;
+            }
+        }
+
+        let mut journal = self.journal.lock().unwrap();
+        for (chunk_id, delta) in &journal.pending {
+            *totals.entry(chunk_id).or_insert(0) += delta;
+        }
+
+        self.index.clear();
+        for (chunk_id, total) in totals {
+            self.index.apply_delta(chunk_id, total);
+        }
+
+        journal.segments = keys.to_vec();
+        Ok(())
+    }
+
+    /// Rebuild the index from the repository: reference counts come from the
+    /// snapshot manifests, and only chunks present in storage are indexed so
+    /// missing ones are uploaded again by the next backup
+    ///
+    /// The checkpoint written replaces the journal segments present when the
+    /// rebuild started; segments written meanwhile by other processes stay.
+    pub async fn rebuild(&self) -> Result<()> {
+        let storage = self.storage()?;
+        let segments = storage.list_metadata(INDEX_PREFIX).await?;
+        let stored: HashSet<ChunkId> = storage.list_chunks().await?.into_iter().collect();
+        let manifests = ManifestStore::new(storage.clone());
+
+        let mut counts: HashMap<String, i64> = HashMap::new();
+        for snapshot in manifests.list().await? {
+            let manifest = manifests.load(&snapshot.id).await?;
+            for chunk_id in manifest.files.iter().flat_map(|f| &f.chunk_ids) {
+                if stored.contains(chunk_id) {
+                    *counts.entry(chunk_id.0.clone()).or_insert(0) += 1;
+                }
+            }
+        }
+
+        self.index.clear();
+        for (chunk_id, count) in &counts {
+            self.index.apply_delta(chunk_id, *count);
+        }
+
+        // Anything recorded so far is replaced by the rebuilt counts
+        {
+            let mut journal = self.journal.lock().unwrap();
+            journal.pending.clear();
+            journal.segments = segments;
+        }
+        self.checkpoint().await
+    }
+
+    /// Write unflushed reference count changes to the journal
+    pub async fn flush(&self) -> Result<()> {
+        let Some(storage) = self.storage.clone() else {
+            return Ok(());
+        };
+
+        let (key, segment) = {
+            let mut journal = self.journal.lock().unwrap();
+            if journal.pending.is_empty() {
+                return Ok(());
+            }
+            let segment = IndexSegment {
+                supersedes: Vec::new(),
+                entries: std::mem::take(&mut journal.pending),
+            };
+            (format!("{}{}", INDEX_PREFIX, Uuid::new_v4()), segment)
+        };
+
+        storage.put_metadata(&key, encode_segment(&segment)?).await?;
+
+        let segment_count = {
+            let mut journal = self.journal.lock().unwrap();
+            journal.segments.push(key);
+            journal.segments.len()
+        };
+
+        if segment_count > MAX_INDEX_SEGMENTS {
+            self.checkpoint().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Fold the whole index into a single checkpoint segment
+    ///
+    /// Only segments this store replayed or wrote are replaced, since those
+    /// are the ones its counts include. Segments other processes wrote since
+    /// stay, to be added on top of the checkpoint when the journal is replayed.
+    async fn checkpoint(&self) -> Result<()> {
+        let storage = self.storage()?;
+        let superseded = self.journal.lock().unwrap().segments.clone();
+
+        let segment = IndexSegment {
+            supersedes: superseded.clone(),
+            entries: self
+                .index
+                .ref_counts()
+                .into_iter()
+                .map(|(chunk_id, count)| (chunk_id, count as i64))
+                .collect(),
+        };
+        let key = format!("{}{}", INDEX_PREFIX, Uuid::new_v4());
+
+        // The checkpoint must be durable before the segments it replaces go
+        storage.put_metadata(&key, encode_segment(&segment)?).await?;
+        for old in &superseded {
+            storage.delete_metadata(old).await?;
+        }
+
+        self.journal.lock().unwrap().segments = vec![key];
+        Ok(())
+    }
+
+    fn storage(&self) -> Result<Arc<dyn StorageBackend>> {
+        self.storage
+            .clone()
+            .ok_or_else(|| Error::Deduplication("Dedup store has no storage".to_string()))
+    }
+
+    fn record(&self, chunk_id: &ChunkId, delta: i64) {
+        if self.storage.is_some() {
+            let mut journal = self.journal.lock().unwrap();
+            *journal.pending.entry(chunk_id.0.clone()).or_insert(0) += delta;
         }
     }
 
@@ -96,11 +363,15 @@ impl DedupStore {
 
     /// Register a new chunk
     pub fn register_chunk(&self, chunk_id: ChunkId) {
+        self.record(&chunk_id, 1);
         self.index.insert(chunk_id);
     }
 
     /// Unregister a chunk (for garbage collection)
     pub fn unregister_chunk(&self, chunk_id: &ChunkId) -> bool {
+        if self.index.get_ref_count(chunk_id) > 0 {
+            self.record(chunk_id, -1);
+        }
         self.index.remove(chunk_id)
     }
 
@@ -116,6 +387,11 @@ impl DedupStore {
     }
 }
 
+fn encode_segment(segment: &IndexSegment) -> Result<Vec<u8>> {
+    serde_json::to_vec(segment)
+        .map_err(|e| Error::Serialization(format!("Failed to encode index segment: {}", e)))
+}
+
 impl Default for DedupStore {
     fn default() -> Self {
         Self::new()
@@ -168,4 +444,98 @@ mod tests {
         let stats = store.stats();
         assert_eq!(stats.total_chunks, 1);
     }
+
+    async fn local_storage(temp_dir: &tempfile::TempDir) -> Arc<dyn StorageBackend> {
+        Arc::new(
+            backupforge_storage::LocalStorage::new(temp_dir.path())
+                .await
+                .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_dedup_store_persists_ref_counts() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let storage = local_storage(&temp_dir).await;
+        let chunk_a = ChunkId("aaaa".to_string());
+        let chunk_b = ChunkId("bbbb".to_string());
+
+        let store = DedupStore::open(storage.clone()).await.unwrap();
+        store.register_chunk(chunk_a.clone());
+        store.register_chunk(chunk_a.clone());
+        store.register_chunk(chunk_b.clone());
+        store.flush().await.unwrap();
+
+        store.unregister_chunk(&chunk_b);
+        store.flush().await.unwrap();
+
+        let reopened = DedupStore::open(storage).await.unwrap();
+        assert_eq!(reopened.index().get_ref_count(&chunk_a), 2);
+        assert!(!reopened.is_duplicate(&chunk_b));
+    }
+
+    #[tokio::test]
+    async fn test_dedup_store_checkpoints_journal() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let storage = local_storage(&temp_dir).await;
+        let chunk_id = ChunkId("cccc".to_string());
+
+        let store = DedupStore::open(storage.clone()).await.unwrap();
+        for _ in 0..=MAX_INDEX_SEGMENTS {
+            store.register_chunk(chunk_id.clone());
+            store.flush().await.unwrap();
+        }
+
+        assert_eq!(storage.list_metadata(INDEX_PREFIX).await.unwrap().len(), 1);
+
+        let reopened = DedupStore::open(storage).await.unwrap();
+        assert_eq!(
+            reopened.index().get_ref_count(&chunk_id),
+            MAX_INDEX_SEGMENTS as u64 + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_keeps_segments_of_other_stores() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let storage = local_storage(&temp_dir).await;
+        let chunk_id = ChunkId("eeee".to_string());
+
+        let first = DedupStore::open(storage.clone()).await.unwrap();
+        let second = DedupStore::open(storage.clone()).await.unwrap();
+        first.register_chunk(chunk_id.clone());
+        first.flush().await.unwrap();
+
+        // The second store never replayed the first one's segment
+        for i in 0..=MAX_INDEX_SEGMENTS {
+            second.register_chunk(ChunkId(format!("chunk-{}", i)));
+            second.flush().await.unwrap();
+        }
+        assert_eq!(storage.list_metadata(INDEX_PREFIX).await.unwrap().len(), 2);
+
+        let reopened = DedupStore::open(storage).await.unwrap();
+        assert_eq!(reopened.index().get_ref_count(&chunk_id), 1);
+        assert_eq!(reopened.index().len(), MAX_INDEX_SEGMENTS + 2);
+    }
+
+    #[tokio::test]
+    async fn test_dedup_store_rebuilds_corrupt_index() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let storage = local_storage(&temp_dir).await;
+        let chunk_id = ChunkId("dddd".to_string());
+
+        // A stored chunk that no snapshot references is garbage, not a duplicate
+        storage.put_chunk(&chunk_id, b"data".to_vec()).await.unwrap();
+        storage
+            .put_metadata("index/broken", b"{not json".to_vec())
+            .await
+            .unwrap();
+
+        let store = DedupStore::open(storage.clone()).await.unwrap();
+        assert!(!store.is_duplicate(&chunk_id));
+
+        let keys = storage.list_metadata(INDEX_PREFIX).await.unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_ne!(keys[0], "index/broken");
+    }
 }
@@ -2,13 +2,14 @@ use aes_gcm::{
     aead::{Aead, KeyInit, OsRng},
     Aes256Gcm, Nonce,
 };
-use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
 use argon2::password_hash::SaltString;
 use backupforge_common::{Error, Result};
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 
 const NONCE_SIZE: usize = 12; // 96 bits for GCM
+const SALT_SIZE: usize = 16;
 
 /// Encryption key for AES-256-GCM
 #[derive(Clone)]
@@ -61,6 +62,125 @@ impl EncryptionKey {
     pub fn as_bytes(&self) -> &[u8] {
         &self.key
     }
+
+    /// Secret keying chunk IDs, derived so it never needs storing separately
+    pub fn chunk_id_key(&self) -> [u8; 32] {
+        blake3::derive_key("backupforge 2026-10 chunk id key v1", &self.key)
+    }
+
+    /// Short public identifier of this key, recorded in chunk headers
+    pub fn key_id(&self) -> [u8; 8] {
+        let id = blake3::derive_key("backupforge 2026-10 key id v1", &self.key);
+        let mut out = [0u8; 8];
+        out.copy_from_slice(&id[..8]);
+        out
+    }
+
+    /// Encrypt this key under a key derived from `passphrase` with a fresh salt
+    pub fn wrap(&self, passphrase: &str) -> Result<WrappedKey> {
+        let kdf = KdfParams::generate();
+        let kek = kdf.derive(passphrase)?;
+        let ciphertext = Encryptor::new(kek).encrypt(&self.key)?;
+
+        Ok(WrappedKey {
+            kdf: Some(kdf),
+            ciphertext,
+        })
+    }
+
+    /// Encrypt this key directly under another key, e.g. one read from a key file
+    pub fn wrap_with_key(&self, kek: &EncryptionKey) -> Result<WrappedKey> {
+        let ciphertext = Encryptor::new(kek.clone()).encrypt(&self.key)?;
+
+        Ok(WrappedKey {
+            kdf: None,
+            ciphertext,
+        })
+    }
+}
+
+/// Argon2id parameters for deriving a key-encryption key from a passphrase
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    #[serde(with = "backupforge_common::hex_bytes")]
+    pub salt: Vec<u8>,
+}
+
+impl KdfParams {
+    /// Default Argon2id costs with a random salt
+    pub fn generate() -> Self {
+        let mut salt = vec![0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+            salt,
+        }
+    }
+
+    /// Derive a key from `passphrase` using these parameters
+    pub fn derive(&self, passphrase: &str) -> Result<EncryptionKey> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| Error::Encryption(format!("Invalid KDF parameters: {}", e)))?;
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+        let mut key = [0u8; 32];
+        argon2
+            .hash_password_into(passphrase.as_bytes(), &self.salt, &mut key)
+            .map_err(|e| Error::Encryption(format!("Key derivation failed: {}", e)))?;
+
+        Ok(EncryptionKey { key })
+    }
+}
+
+/// A master key encrypted under a passphrase-derived key, or under a raw key
+/// when `kdf` is absent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WrappedKey {
+    #[serde(default)]
+    pub kdf: Option<KdfParams>,
+    #[serde(with = "backupforge_common::hex_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+impl WrappedKey {
+    /// Whether this key is unlocked with a passphrase rather than a raw key
+    pub fn is_passphrase(&self) -> bool {
+        self.kdf.is_some()
+    }
+
+    /// Recover the master key; fails if the passphrase is wrong
+    pub fn unwrap_key(&self, passphrase: &str) -> Result<EncryptionKey> {
+        let kdf = self.kdf.as_ref().ok_or_else(|| {
+            Error::AuthenticationFailed("Key is not protected by a passphrase".to_string())
+        })?;
+
+        self.decrypt_with(kdf.derive(passphrase)?, "Wrong passphrase")
+    }
+
+    /// Recover the master key with a raw key-encryption key
+    pub fn unwrap_with_key(&self, kek: &EncryptionKey) -> Result<EncryptionKey> {
+        if self.kdf.is_some() {
+            return Err(Error::AuthenticationFailed(
+                "Key is protected by a passphrase".to_string(),
+            ));
+        }
+
+        self.decrypt_with(kek.clone(), "Wrong key file")
+    }
+
+    fn decrypt_with(&self, kek: EncryptionKey, failure: &str) -> Result<EncryptionKey> {
+        let key = Encryptor::new(kek)
+            .decrypt(&self.ciphertext)
+            .map_err(|_| Error::AuthenticationFailed(failure.to_string()))?;
+
+        EncryptionKey::from_bytes(&key)
+    }
 }
 
 /// Encryptor for backup data using AES-256-GCM
@@ -148,6 +268,36 @@ mod tests {
         assert_eq!(key.as_bytes(), key2.as_bytes());
     }
 
+    #[test]
+    fn test_wrap_unwrap_key() {
+        let key = EncryptionKey::generate();
+        let wrapped = key.wrap("correct horse").unwrap();
+
+        let unwrapped = wrapped.unwrap_key("correct horse").unwrap();
+        assert_eq!(key.as_bytes(), unwrapped.as_bytes());
+
+        let result = wrapped.unwrap_key("wrong");
+        assert!(matches!(result, Err(Error::AuthenticationFailed(_))));
+
+        // Each wrap uses its own salt
+        let again = key.wrap("correct horse").unwrap();
+        assert_ne!(wrapped.kdf.unwrap().salt, again.kdf.unwrap().salt);
+    }
+
+    #[test]
+    fn test_wrap_with_key() {
+        let key = EncryptionKey::generate();
+        let kek = EncryptionKey::generate();
+        let wrapped = key.wrap_with_key(&kek).unwrap();
+        assert!(!wrapped.is_passphrase());
+
+        let unwrapped = wrapped.unwrap_with_key(&kek).unwrap();
+        assert_eq!(key.as_bytes(), unwrapped.as_bytes());
+
+        assert!(wrapped.unwrap_with_key(&EncryptionKey::generate()).is_err());
+        assert!(wrapped.unwrap_key("passphrase").is_err());
+    }
+
     #[test]
     fn test_encryption_decryption() {
         let key = EncryptionKey::generate();
@@ -1,18 +1,34 @@
 use backupforge_common::{
-    types::{Chunk, ChunkId, Snapshot, SnapshotId, BackupStats, FileMetadata},
+    types::{
+        BackupStats, Chunk, ChunkId, FileMetadata, NodeKind, RetentionPolicy, Snapshot,
+        SnapshotId,
+    },
     Error, Result,
 };
+use backupforge_storage::{StorageBackend, SweepStats};
+use std::collections::{BTreeMap, HashSet};
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tokio::fs;
-use tokio::io::AsyncReadExt;
-use chrono::Utc;
+use tokio::io::AsyncRead;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::{self, JoinHandle};
+use chrono::{DateTime, Duration, Utc};
 
 use crate::{
+    check::{self, CheckOptions, CheckReport},
     chunker::{Chunker, ChunkingStrategy},
     compression::{Compressor, CompressionAlgorithm},
     dedup::DedupStore,
     encryption::{Encryptor, EncryptionKey},
+    envelope::ChunkHeader,
+    manifest::{ManifestStore, SnapshotManifest},
+    pipeline::OrderedTasks,
+    repair::{self, RepairOptions, RepairReport},
+    retention::{self, RetentionDecision},
+    sparse::{self, SparseWriter},
 };
 
 /// Configuration for the backup engine
@@ -21,6 +37,14 @@ pub struct BackupConfig {
     pub chunking_strategy: ChunkingStrategy,
     pub compression: CompressionAlgorithm,
     pub encryption_key: Option<EncryptionKey>,
+    /// Key for keyed chunk IDs; `None` derives IDs with plain BLAKE3
+    pub chunk_id_key: Option<[u8; 32]>,
+    /// Chunks hashed, compressed and encrypted in parallel
+    pub cpu_workers: usize,
+    /// Chunk uploads in flight at once
+    pub upload_workers: usize,
+    /// Files read in parallel by directory backups
+    pub file_workers: usize,
 }
 
 impl Default for BackupConfig {
@@ -29,69 +53,178 @@ impl Default for BackupConfig {
             chunking_strategy: ChunkingStrategy::default(),
             compression: CompressionAlgorithm::default(),
             encryption_key: None,
+            chunk_id_key: None,
+            cpu_workers: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4),
+            upload_workers: 8,
+            file_workers: 4,
         }
     }
 }
 
 /// Main backup engine that orchestrates chunking, compression, dedup, and encryption
+///
+/// Backups run as a pipeline: readers cut chunks, a bounded pool of blocking
+/// workers hashes, compresses and encrypts them, and a bounded set of uploads
+/// stores them. Chunks hold a slot from being cut until stored, so slow storage
+/// stalls the readers instead of buffering without limit.
 pub struct BackupEngine {
     config: BackupConfig,
-    chunker: Chunker,
-    compressor: Compressor,
-    encryptor: Option<Encryptor>,
+    codec: Arc<ChunkCodec>,
     dedup_store: Arc<DedupStore>,
+    storage: Arc<dyn StorageBackend>,
+    manifests: ManifestStore,
+    pipeline_depth: usize,
+    chunk_slots: Arc<Semaphore>,
+    cpu_slots: Arc<Semaphore>,
+    upload_slots: Arc<Semaphore>,
+    counters: Arc<RunCounters>,
 }
 
 impl BackupEngine {
-    pub fn new(config: BackupConfig) -> Self {
-        let chunker = Chunker::new(config.chunking_strategy.clone());
-        let compressor = Compressor::new(config.compression);
-        let encryptor = config
-            .encryption_key
-            .clone()
-            .map(Encryptor::new);
+    pub fn new(config: BackupConfig, storage: Arc<dyn StorageBackend>) -> Self {
+        let dedup_store = Arc::new(DedupStore::with_storage(storage.clone()));
+        Self::with_dedup_store(config, storage, dedup_store)
+    }
+
+    /// Create an engine whose dedup index is loaded from the repository
+    pub async fn open(config: BackupConfig, storage: Arc<dyn StorageBackend>) -> Result<Self> {
+        let dedup_store = Arc::new(DedupStore::open(storage.clone()).await?);
+        Ok(Self::with_dedup_store(config, storage, dedup_store))
+    }
+
+    fn with_dedup_store(
+        config: BackupConfig,
+        storage: Arc<dyn StorageBackend>,
+        dedup_store: Arc<DedupStore>,
+    ) -> Self {
+        let cpu_workers = config.cpu_workers.max(1);
+        let upload_workers = config.upload_workers.max(1);
+        let pipeline_depth = cpu_workers + upload_workers;
 
         Self {
+            codec: Arc::new(ChunkCodec::new(&config)),
+            dedup_store,
+            manifests: ManifestStore::new(storage.clone()),
+            storage,
+            pipeline_depth,
+            chunk_slots: Arc::new(Semaphore::new(pipeline_depth)),
+            cpu_slots: Arc::new(Semaphore::new(cpu_workers)),
+            upload_slots: Arc::new(Semaphore::new(upload_workers)),
+            counters: Arc::new(RunCounters::new()),
             config,
-            chunker,
-            compressor,
-            encryptor,
-            dedup_store: Arc::new(DedupStore::new()),
         }
     }
 
-    /// Process data: chunk -> compress -> encrypt -> deduplicate
+    /// Get the engine configuration
+    pub fn config(&self) -> &BackupConfig {
+        &self.config
+    }
+
+    /// Pick up changes other processes made to the repository since this
+    /// engine was opened, such as chunks a prune deleted
+    pub async fn reload(&self) -> Result<()> {
+        self.storage.reload_index().await?;
+        self.dedup_store.reload().await
+    }
+
+    /// Start counting a new run; `create_snapshot` reports everything since
+    ///
+    /// Counters are per engine, so runs sharing an engine must not overlap.
+    pub fn begin_run(&self) {
+        self.counters.reset();
+    }
+
+    /// Process data: chunk -> deduplicate -> compress -> encrypt -> store
     pub async fn process_data(&self, data: Vec<u8>) -> Result<Vec<ChunkId>> {
-        // Step 1: Chunk the data
-        let chunks = self.chunker.chunk_data(&data)?;
+        let (chunk_ids, _) = self.process_reader(data.as_slice()).await?;
+        Ok(chunk_ids)
+    }
+
+    /// Process everything read from `reader` with bounded memory, returning the
+    /// chunk IDs and the number of bytes read
+    pub async fn process_reader<R: AsyncRead + Unpin>(
+        &self,
+        reader: R,
+    ) -> Result<(Vec<ChunkId>, u64)> {
+        let mut stream = self.codec.chunker.chunk_stream(reader);
+        let mut in_flight = OrderedTasks::new();
         let mut chunk_ids = Vec::new();
+        let mut size = 0u64;
 
-        for mut chunk in chunks {
-            // Check if we already have this chunk
-            if self.dedup_store.is_duplicate(&chunk.id) {
-                chunk_ids.push(chunk.id.clone());
-                self.dedup_store.register_chunk(chunk.id);
-                continue;
+        loop {
+            // Backpressure: wait for a slot before reading the next chunk
+            let slot = acquire(&self.chunk_slots).await?;
+            let Some(data) = stream.next_block().await? else {
+                break;
+            };
+            size += data.len() as u64;
+            in_flight.push(self.spawn_store(data, slot));
+
+            // Keep the queue short; results are collected in order anyway
+            if in_flight.len() >= self.pipeline_depth {
+                if let Some(id) = in_flight.next().await {
+                    chunk_ids.push(id?);
+                }
             }
+        }
 
-            // Step 2: Compress
-            let compressed = self.compressor.compress(&chunk.data)?;
+        while let Some(id) = in_flight.next().await {
+            chunk_ids.push(id?);
+        }
 
-            // Step 3: Encrypt (if enabled)
-            let final_data = if let Some(ref encryptor) = self.encryptor {
-                encryptor.encrypt(&compressed)?
-            } else {
-                compressed
-            };
+        Ok((chunk_ids, size))
+    }
 
-            // Store the processed chunk (in real implementation, this would write to storage)
-            // For now, we just register it in the dedup store
-            chunk.data = final_data;
-            chunk_ids.push(chunk.id.clone());
-            self.dedup_store.register_chunk(chunk.id);
-        }
+    /// Hash, deduplicate, compress, encrypt and store one chunk on a task of its own
+    fn spawn_store(
+        &self,
+        data: Vec<u8>,
+        slot: OwnedSemaphorePermit,
+    ) -> JoinHandle<Result<ChunkId>> {
+        let codec = self.codec.clone();
+        let dedup_store = self.dedup_store.clone();
+        let storage = self.storage.clone();
+        let cpu_slots = self.cpu_slots.clone();
+        let upload_slots = self.upload_slots.clone();
+        let counters = self.counters.clone();
 
-        Ok(chunk_ids)
+        tokio::spawn(async move {
+            let _slot = slot;
+
+            let cpu = acquire(&cpu_slots).await?;
+            let dedup = dedup_store.clone();
+            let (chunk_id, encoded) = task::spawn_blocking(move || {
+                let _cpu = cpu;
+                let chunk = codec.chunker.make_chunk(data);
+
+                // Check if we already have this chunk
+                if dedup.is_duplicate(&chunk.id) {
+                    return Ok((chunk.id, None));
+                }
+
+                let (encoded, compressed_len) = codec.encode(&chunk)?;
+                Ok::<_, Error>((chunk.id, Some((encoded, chunk.size, compressed_len))))
+            })
+            .await
+            .map_err(|e| Error::Unknown(format!("Chunk worker failed: {}", e)))??;
+
+            // Store, and only register once the write has succeeded so a
+            // failed upload is retried on the next occurrence of this chunk
+            match encoded {
+                Some((encoded, size, compressed_len)) => {
+                    let _upload = acquire(&upload_slots).await?;
+                    let stored_len = encoded.len() as u64;
+                    storage.put_chunk(&chunk_id, encoded).await?;
+                    counters.new_chunk(size, compressed_len, stored_len);
+                }
+                None => counters.reused_chunks(1),
+            }
+            dedup_store.register_chunk(chunk_id.clone());
+
+            Ok(chunk_id)
+        })
     }
 
     /// Restore data from chunk IDs
@@ -99,73 +232,353 @@ impl BackupEngine {
         let mut result = Vec::new();
 
         for chunk_id in chunk_ids {
-            // In real implementation, fetch chunk from storage
-            // For now, this is a placeholder
-            // let chunk_data = storage.get_chunk(chunk_id).await?;
+            let data = self.load_chunk(chunk_id).await?;
+            result.extend_from_slice(&data);
+        }
 
-            // Decrypt if needed
-            // let decrypted = if let Some(ref encryptor) = self.encryptor {
-            //     encryptor.decrypt(&chunk_data)?
-            // } else {
-            //     chunk_data
-            // };
+        Ok(result)
+    }
 
-            // Decompress
-            // let decompressed = self.compressor.decompress(&decrypted)?;
+    /// Fetch a chunk from storage: fetch -> decrypt -> decompress -> verify
+    pub async fn load_chunk(&self, chunk_id: &ChunkId) -> Result<Vec<u8>> {
+        let stored = self.storage.get_chunk(chunk_id).await?;
+        self.codec.decode(chunk_id, stored)
+    }
 
-            // result.extend_from_slice(&decompressed);
+    /// Restore a single file to `target_path`, writing it chunk by chunk
+    ///
+    /// Nothing may exist at `target_path` yet, so a symlink left there is
+    /// never followed.
+    pub async fn restore_file(&self, file: &FileMetadata, target_path: &Path) -> Result<()> {
+        if let Some(parent) = target_path.parent() {
+            fs::create_dir_all(parent).await?;
         }
 
-        Ok(result)
+        let mut output = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(target_path)
+            .await?;
+        let mut writer = SparseWriter::new(&mut output, &file.holes);
+        let mut written = 0u64;
+
+        for chunk_id in &file.chunk_ids {
+            let data = self.load_chunk(chunk_id).await?;
+            writer.write(&data).await?;
+            written += data.len() as u64;
+        }
+
+        writer.finish(file.size).await?;
+        output.sync_all().await?;
+
+        // Holes are left unwritten
+        let expected = file.size.saturating_sub(sparse::hole_bytes(&file.holes));
+        if written != expected {
+            return Err(Error::Corruption(format!(
+                "Restored {} bytes for {} but expected {}",
+                written, file.path, expected
+            )));
+        }
+
+        Ok(())
     }
 
     /// Backup a file
     pub async fn backup_file(&self, file_path: &Path) -> Result<FileMetadata> {
-        let metadata = fs::metadata(file_path).await?;
-        let mut file = fs::File::open(file_path).await?;
+        self.backup_file_with_parent(file_path, None).await
+    }
+
+    /// Backup a file, reusing the chunks recorded for it in a parent snapshot
+    /// when its size, mtime, inode and ctime are unchanged
+    ///
+    /// Symlinks are recorded rather than followed, and entries other than
+    /// regular files are recorded without contents.
+    pub async fn backup_file_with_parent(
+        &self,
+        file_path: &Path,
+        previous: Option<&FileMetadata>,
+    ) -> Result<FileMetadata> {
+        let mut file_metadata = self.stat(file_path).await?;
+        if file_metadata.kind != NodeKind::File {
+            return Ok(file_metadata);
+        }
+
+        if let Some(previous) = previous {
+            // Chunks removed from the repository since force a full read
+            if previous.is_unchanged(&file_metadata)
+                && previous
+                    .chunk_ids
+                    .iter()
+                    .all(|id| self.dedup_store.is_duplicate(id))
+            {
+                for chunk_id in &previous.chunk_ids {
+                    self.dedup_store.register_chunk(chunk_id.clone());
+                }
+                self.counters.reused_chunks(previous.chunk_ids.len() as u64);
+                self.counters.unchanged_files.fetch_add(1, Ordering::Relaxed);
+                file_metadata.chunk_ids = previous.chunk_ids.clone();
+                file_metadata.holes = previous.holes.clone();
+                return Ok(file_metadata);
+            }
+        }
 
-        let mut data = Vec::new();
-        file.read_to_end(&mut data).await?;
+        let file = fs::File::open(file_path).await?;
+        let holes = {
+            let probe = file.try_clone().await?.into_std().await;
+            let size = file_metadata.size;
+            task::spawn_blocking(move || sparse::find_holes(&probe, size))
+                .await
+                .map_err(|e| Error::Unknown(format!("Metadata worker failed: {}", e)))??
+        };
 
-        let chunk_ids = self.process_data(data).await?;
+        if holes.is_empty() {
+            // Record what was actually read, in case the file changed since stat
+            let (chunk_ids, size) = self.process_reader(file).await?;
+            file_metadata.size = size;
+            file_metadata.chunk_ids = chunk_ids;
+        } else {
+            // Only the data between holes is read and stored
+            let extents = sparse::data_extents(&holes, file_metadata.size);
+            let expected = file_metadata.size - sparse::hole_bytes(&holes);
+            let reader = sparse::ExtentReader::new(file, extents);
+            let (chunk_ids, read) = self.process_reader(reader).await?;
+            if read != expected {
+                return Err(Error::Io(std::io::Error::other(format!(
+                    "{} shrank while being read",
+                    file_metadata.path
+                ))));
+            }
+            file_metadata.chunk_ids = chunk_ids;
+            file_metadata.holes = holes;
+        }
+        self.counters.changed_files.fetch_add(1, Ordering::Relaxed);
+
+        Ok(file_metadata)
+    }
+
+    /// Record another name of a file already backed up at `target`; its
+    /// contents are restored by linking to that file
+    pub async fn backup_hard_link(&self, file_path: &Path, target: &Path) -> Result<FileMetadata> {
+        let mut file_metadata = self.stat(file_path).await?;
+        file_metadata.kind = NodeKind::HardLink {
+            target: target.to_string_lossy().to_string(),
+        };
+        file_metadata.size = 0;
+        Ok(file_metadata)
+    }
+
+    /// Read the metadata of the entry at `file_path` without following symlinks
+    ///
+    /// Only regular files keep their size, since only they carry contents.
+    pub(crate) async fn stat(&self, file_path: &Path) -> Result<FileMetadata> {
+        let metadata = fs::symlink_metadata(file_path).await?;
+        let (inode, ctime) = file_identity(&metadata);
+
+        // Reading xattrs and looking up owner names may block
+        let (kind, unix) = {
+            let path = file_path.to_path_buf();
+            let metadata = metadata.clone();
+            task::spawn_blocking(move || {
+                let kind = crate::metadata::node_kind(&path, &metadata)?;
+                Ok::<_, Error>((kind, crate::metadata::capture(&path, &metadata)))
+            })
+            .await
+            .map_err(|e| Error::Unknown(format!("Metadata worker failed: {}", e)))??
+        };
 
         Ok(FileMetadata {
             path: file_path.to_string_lossy().to_string(),
-            size: metadata.len(),
+            size: if kind == NodeKind::File { metadata.len() } else { 0 },
             modified: metadata.modified()?.into(),
-            permissions: 0o644, // Simplified
-            is_directory: metadata.is_dir(),
-            chunk_ids,
+            permissions: unix.as_ref().map_or(0o644, |u| u.mode & 0o7777),
+            is_directory: kind == NodeKind::Directory,
+            chunk_ids: Vec::new(),
+            kind,
+            holes: Vec::new(),
+            inode,
+            ctime,
+            unix,
         })
     }
 
-    /// Create a snapshot
+    /// Create a snapshot and persist its manifest, with the statistics of
+    /// the run since `begin_run`
     pub async fn create_snapshot(
         &self,
         name: String,
         source_path: String,
         file_metadatas: Vec<FileMetadata>,
+        parent_snapshot: Option<SnapshotId>,
     ) -> Result<Snapshot> {
         let total_size: u64 = file_metadatas.iter().map(|f| f.size).sum();
         let chunk_ids: Vec<ChunkId> = file_metadatas
             .iter()
             .flat_map(|f| f.chunk_ids.clone())
             .collect();
+        let stats = self.counters.stats(file_metadatas.len() as u64, total_size);
 
-        Ok(Snapshot {
+        let snapshot = Snapshot {
             id: SnapshotId::new(),
             name,
             created_at: Utc::now(),
             source_path,
             total_size,
-            compressed_size: total_size, // Would be calculated properly
+            compressed_size: stats.compressed_bytes,
             file_count: file_metadatas.len() as u64,
             chunk_ids,
-            parent_snapshot: None,
+            parent_snapshot,
             tags: Vec::new(),
+            stats: Some(stats),
+        };
+
+        // Chunks and index references must be durable before a manifest relies on them
+        self.storage.flush().await?;
+        self.dedup_store.flush().await?;
+
+        let manifest = SnapshotManifest {
+            snapshot,
+            files: file_metadatas,
+        };
+        self.manifests.save(&manifest).await?;
+
+        Ok(manifest.snapshot)
+    }
+
+    /// Load the manifest of a snapshot
+    pub async fn load_snapshot(&self, id: &SnapshotId) -> Result<SnapshotManifest> {
+        self.manifests.load(id).await
+    }
+
+    /// List all snapshots in the repository, oldest first
+    pub async fn list_snapshots(&self) -> Result<Vec<Snapshot>> {
+        self.manifests.list().await
+    }
+
+    /// Apply `policy` to the snapshots of each source path, or only of
+    /// `source_path`, and remove those it does not keep unless `dry_run`
+    ///
+    /// Removed snapshots release their chunk references; the chunks stay in
+    /// the repository until pruned.
+    pub async fn forget(
+        &self,
+        policy: &RetentionPolicy,
+        source_path: Option<&str>,
+        dry_run: bool,
+    ) -> Result<Vec<RetentionDecision>> {
+        let mut sources: BTreeMap<String, Vec<Snapshot>> = BTreeMap::new();
+        for snapshot in self.list_snapshots().await? {
+            if source_path.is_none_or(|path| path == snapshot.source_path) {
+                sources
+                    .entry(snapshot.source_path.clone())
+                    .or_default()
+                    .push(snapshot);
+            }
+        }
+
+        let mut decisions = Vec::new();
+        for snapshots in sources.into_values() {
+            decisions.extend(retention::apply_policy(policy, snapshots)?);
+        }
+        if dry_run {
+            return Ok(decisions);
+        }
+
+        for decision in decisions.iter().filter(|d| !d.keep()) {
+            self.remove_snapshot(&decision.snapshot.id).await?;
+        }
+        self.dedup_store.flush().await?;
+
+        Ok(decisions)
+    }
+
+    /// Remove a snapshot and release its chunk references; the chunks stay
+    /// in the repository until pruned
+    pub async fn delete_snapshot(&self, id: &SnapshotId) -> Result<()> {
+        self.remove_snapshot(id).await?;
+        self.dedup_store.flush().await
+    }
+
+    async fn remove_snapshot(&self, id: &SnapshotId) -> Result<()> {
+        let manifest = self.load_snapshot(id).await?;
+
+        // Counts left too high by a crash only delay pruning, so the
+        // manifest goes before its references
+        self.manifests.delete(id).await?;
+        for chunk_id in manifest.files.iter().flat_map(|f| &f.chunk_ids) {
+            self.dedup_store.unregister_chunk(chunk_id);
+        }
+        tracing::info!("Removed snapshot {}", id.0);
+
+        Ok(())
+    }
+
+    /// Delete the chunks no snapshot references, or only count them if
+    /// `dry_run`
+    ///
+    /// Every manifest is read before anything is deleted, and one that cannot
+    /// be read stops the prune. Objects written within `grace_period` are left
+    /// alone, so chunks uploaded by backups still in progress survive until
+    /// their snapshots reference them. The dedup index is rebuilt afterwards
+    /// so it never names a deleted chunk, and temporary files abandoned by
+    /// interrupted writes are removed.
+    pub async fn prune(&self, grace_period: Duration, dry_run: bool) -> Result<PruneReport> {
+        let cutoff = Utc::now().checked_sub_signed(grace_period).ok_or_else(|| {
+            let hours = grace_period.num_hours();
+            Error::InvalidConfig(format!("Grace period of {} hours is too long", hours))
+        })?;
+
+        // Chunks this engine accepted must be stored before packs are swept
+        self.storage.flush().await?;
+
+        let snapshots = self.list_snapshots().await?;
+        let mut referenced = HashSet::new();
+        for snapshot in &snapshots {
+            let manifest = self.load_snapshot(&snapshot.id).await?;
+            referenced.extend(manifest.files.into_iter().flat_map(|f| f.chunk_ids));
+        }
+
+        let sweep = self.storage.sweep(&referenced, cutoff, dry_run).await?;
+
+        if !dry_run {
+            self.dedup_store.rebuild().await?;
+            self.storage.remove_stale_temp_files().await?;
+            tracing::info!(
+                "Pruned {} chunks, reclaiming {} bytes",
+                sweep.removed_chunks,
+                sweep.reclaimed_bytes
+            );
+        }
+
+        Ok(PruneReport {
+            snapshots: snapshots.len(),
+            referenced_chunks: referenced.len(),
+            sweep,
         })
     }
 
+    /// Verify manifests and the chunks they reference, reading back chunk
+    /// data as `options` asks
+    pub async fn check(&self, options: &CheckOptions) -> Result<CheckReport> {
+        check::run(
+            self.storage.clone(),
+            self.codec.clone(),
+            self.config.upload_workers,
+            options,
+        )
+        .await
+    }
+
+    /// Rebuild the storage index and rewrite the snapshots that reference
+    /// lost chunks, as `options` asks
+    pub async fn repair(&self, options: &RepairOptions) -> Result<RepairReport> {
+        repair::run(self, options).await
+    }
+
+    /// Get the manifest store
+    pub fn manifests(&self) -> &ManifestStore {
+        &self.manifests
+    }
+
     /// Get deduplication statistics
     pub fn dedup_stats(&self) -> backupforge_common::Result<crate::dedup::DedupStats> {
         Ok(self.dedup_store.stats())
@@ -175,16 +588,315 @@ impl BackupEngine {
     pub fn dedup_store(&self) -> Arc<DedupStore> {
         self.dedup_store.clone()
     }
+
+    /// Get the storage backend chunks are written to
+    pub fn storage(&self) -> Arc<dyn StorageBackend> {
+        self.storage.clone()
+    }
+}
+
+/// Outcome of a prune
+#[derive(Debug, Clone)]
+pub struct PruneReport {
+    /// Snapshots whose chunks were kept
+    pub snapshots: usize,
+    /// Distinct chunks those snapshots reference
+    pub referenced_chunks: usize,
+    pub sweep: SweepStats,
+}
+
+/// Counters of the current backup run, shared with chunk tasks
+struct RunCounters {
+    started: Mutex<Instant>,
+    changed_files: AtomicU64,
+    unchanged_files: AtomicU64,
+    new_chunks: AtomicU64,
+    reused_chunks: AtomicU64,
+    new_bytes: AtomicU64,
+    compressed_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+}
+
+impl RunCounters {
+    fn new() -> Self {
+        Self {
+            started: Mutex::new(Instant::now()),
+            changed_files: AtomicU64::new(0),
+            unchanged_files: AtomicU64::new(0),
+            new_chunks: AtomicU64::new(0),
+            reused_chunks: AtomicU64::new(0),
+            new_bytes: AtomicU64::new(0),
+            compressed_bytes: AtomicU64::new(0),
+            stored_bytes: AtomicU64::new(0),
+        }
+    }
+
+    fn reset(&self) {
+        *self.started.lock().unwrap() = Instant::now();
+        for counter in [
+            &self.changed_files,
+            &self.unchanged_files,
+            &self.new_chunks,
+            &self.reused_chunks,
+            &self.new_bytes,
+            &self.compressed_bytes,
+            &self.stored_bytes,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn new_chunk(&self, size: u64, compressed_len: u64, stored_len: u64) {
+        self.new_chunks.fetch_add(1, Ordering::Relaxed);
+        self.new_bytes.fetch_add(size, Ordering::Relaxed);
+        self.compressed_bytes.fetch_add(compressed_len, Ordering::Relaxed);
+        self.stored_bytes.fetch_add(stored_len, Ordering::Relaxed);
+    }
+
+    fn reused_chunks(&self, count: u64) {
+        self.reused_chunks.fetch_add(count, Ordering::Relaxed);
+    }
+
+    fn stats(&self, total_files: u64, total_bytes: u64) -> BackupStats {
+        BackupStats {
+            total_files,
+            changed_files: self.changed_files.load(Ordering::Relaxed),
+            unchanged_files: self.unchanged_files.load(Ordering::Relaxed),
+            total_bytes,
+            new_chunks: self.new_chunks.load(Ordering::Relaxed),
+            reused_chunks: self.reused_chunks.load(Ordering::Relaxed),
+            new_bytes: self.new_bytes.load(Ordering::Relaxed),
+            compressed_bytes: self.compressed_bytes.load(Ordering::Relaxed),
+            stored_bytes: self.stored_bytes.load(Ordering::Relaxed),
+            duration_seconds: self.started.lock().unwrap().elapsed().as_secs(),
+        }
+    }
+}
+
+/// Inode number and status change time, used to tell whether a file changed
+#[cfg(unix)]
+fn file_identity(metadata: &std::fs::Metadata) -> (Option<u64>, Option<DateTime<Utc>>) {
+    use std::os::unix::fs::MetadataExt;
+
+    let ctime = DateTime::from_timestamp(metadata.ctime(), metadata.ctime_nsec() as u32);
+    (Some(metadata.ino()), ctime)
+}
+
+#[cfg(not(unix))]
+fn file_identity(_metadata: &std::fs::Metadata) -> (Option<u64>, Option<DateTime<Utc>>) {
+    (None, None)
+}
+
+/// Converts chunks between plaintext and stored form; shared with blocking workers
+pub(crate) struct ChunkCodec {
+    chunker: Chunker,
+    compressor: Compressor,
+    compression: CompressionAlgorithm,
+    encryptor: Option<Encryptor>,
+    key_id: Option<[u8; 8]>,
+}
+
+impl ChunkCodec {
+    fn new(config: &BackupConfig) -> Self {
+        Self {
+            chunker: Chunker::new(config.chunking_strategy.clone())
+                .with_id_key(config.chunk_id_key),
+            compressor: Compressor::new(config.compression),
+            compression: config.compression,
+            encryptor: config.encryption_key.clone().map(Encryptor::new),
+            key_id: config.encryption_key.as_ref().map(EncryptionKey::key_id),
+        }
+    }
+
+    /// Compress, encrypt and prefix a chunk with its header
+    /// Returns the stored form and the compressed payload size
+    fn encode(&self, chunk: &Chunk) -> Result<(Vec<u8>, u64)> {
+        let compressed = self.compressor.compress(&chunk.data)?;
+        let compressed_len = compressed.len() as u64;
+
+        let payload = if let Some(ref encryptor) = self.encryptor {
+            encryptor.encrypt(&compressed)?
+        } else {
+            compressed
+        };
+
+        // Record how the payload was made so reads never depend on the
+        // settings in force at restore time
+        let header = ChunkHeader {
+            compression: self.compression,
+            key_id: self.key_id,
+            uncompressed_len: u32::try_from(chunk.data.len()).map_err(|_| {
+                Error::InvalidConfig(format!("Chunk of {} bytes is too large", chunk.size))
+            })?,
+        };
+
+        Ok((header.encode(&payload), compressed_len))
+    }
+
+    /// Decrypt, decompress and verify a stored chunk
+    pub(crate) fn decode(&self, chunk_id: &ChunkId, stored: Vec<u8>) -> Result<Vec<u8>> {
+        let (header, payload) = ChunkHeader::decode(&stored)?;
+        let data = self.open(chunk_id, &header, payload)?;
+
+        // The chunk ID is the hash of the plaintext, so it doubles as a checksum
+        if ChunkId::from_hash(&self.chunker.hash(&data)) != *chunk_id {
+            return Err(Error::Corruption(format!(
+                "Chunk {} does not match its content hash",
+                chunk_id.0
+            )));
+        }
+
+        Ok(data)
+    }
+
+    /// Decrypt and decompress a chunk payload as described by its header
+    fn open(&self, chunk_id: &ChunkId, header: &ChunkHeader, payload: &[u8]) -> Result<Vec<u8>> {
+        let compressed = match (header.key_id, &self.encryptor) {
+            (Some(key_id), Some(encryptor)) if Some(key_id) == self.key_id => {
+                encryptor.decrypt(payload)?
+            }
+            (Some(_), Some(_)) => {
+                return Err(Error::AuthenticationFailed(format!(
+                    "Chunk {} is encrypted with a different key",
+                    chunk_id.0
+                )))
+            }
+            (Some(_), None) => {
+                return Err(Error::AuthenticationFailed(format!(
+                    "Chunk {} is encrypted, a key is required",
+                    chunk_id.0
+                )))
+            }
+            // A plaintext chunk in an encrypted repository was not written by us
+            (None, Some(_)) => {
+                return Err(Error::Corruption(format!(
+                    "Chunk {} is not encrypted",
+                    chunk_id.0
+                )))
+            }
+            (None, None) => payload.to_vec(),
+        };
+
+        let data = Compressor::new(header.compression).decompress(&compressed)?;
+        if data.len() != header.uncompressed_len as usize {
+            return Err(Error::Corruption(format!(
+                "Chunk {} decompressed to {} bytes, header says {}",
+                chunk_id.0,
+                data.len(),
+                header.uncompressed_len
+            )));
+        }
+
+        Ok(data)
+    }
+}
+
+/// Wait for a pipeline slot; semaphores are never closed, so failure is a bug
+async fn acquire(slots: &Arc<Semaphore>) -> Result<OwnedSemaphorePermit> {
+    slots
+        .clone()
+        .acquire_owned()
+        .await
+        .map_err(|e| Error::Unknown(format!("Pipeline closed: {}", e)))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use backupforge_storage::LocalStorage;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::TempDir;
+
+    async fn local_storage(temp_dir: &TempDir) -> Arc<dyn StorageBackend> {
+        Arc::new(LocalStorage::new(temp_dir.path()).await.unwrap())
+    }
+
+    /// High-latency backend recording how many chunk uploads overlap
+    struct SlowStorage {
+        inner: Arc<dyn StorageBackend>,
+        active: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl StorageBackend for SlowStorage {
+        async fn put_chunk(&self, chunk_id: &ChunkId, data: Vec<u8>) -> Result<()> {
+            let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(active, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            self.inner.put_chunk(chunk_id, data).await
+        }
+
+        async fn get_chunk(&self, chunk_id: &ChunkId) -> Result<Vec<u8>> {
+            self.inner.get_chunk(chunk_id).await
+        }
+
+        async fn chunk_exists(&self, chunk_id: &ChunkId) -> Result<bool> {
+            self.inner.chunk_exists(chunk_id).await
+        }
+
+        async fn delete_chunk(&self, chunk_id: &ChunkId) -> Result<()> {
+            self.inner.delete_chunk(chunk_id).await
+        }
+
+        async fn list_chunks(&self) -> Result<Vec<ChunkId>> {
+            self.inner.list_chunks().await
+        }
+
+        async fn put_metadata(&self, key: &str, data: Vec<u8>) -> Result<()> {
+            self.inner.put_metadata(key, data).await
+        }
+
+        async fn get_metadata(&self, key: &str) -> Result<Vec<u8>> {
+            self.inner.get_metadata(key).await
+        }
+
+        async fn delete_metadata(&self, key: &str) -> Result<()> {
+            self.inner.delete_metadata(key).await
+        }
+
+        async fn list_metadata(&self, prefix: &str) -> Result<Vec<String>> {
+            self.inner.list_metadata(prefix).await
+        }
+
+        async fn stats(&self) -> Result<backupforge_storage::StorageStats> {
+            self.inner.stats().await
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_pipeline_overlaps_uploads_within_limit() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Arc::new(SlowStorage {
+            inner: local_storage(&temp_dir).await,
+            active: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        });
+        let config = BackupConfig {
+            chunking_strategy: ChunkingStrategy::Fixed { size: 1024 },
+            cpu_workers: 2,
+            upload_workers: 3,
+            ..BackupConfig::default()
+        };
+        let engine = BackupEngine::new(config, storage.clone());
+
+        let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i / 1024) as u8).collect();
+        let chunk_ids = engine.process_data(data.clone()).await.unwrap();
+        assert_eq!(chunk_ids.len(), 64);
+
+        let peak = storage.peak.load(Ordering::SeqCst);
+        assert!(peak > 1 && peak <= 3, "peak concurrent uploads {}", peak);
+
+        // Parallelism must not reorder the chunks of a file
+        assert_eq!(engine.restore_data(&chunk_ids).await.unwrap(), data);
+    }
 
     #[tokio::test]
     async fn test_process_data_no_encryption() {
+        let temp_dir = TempDir::new().unwrap();
         let config = BackupConfig::default();
-        let engine = BackupEngine::new(config);
+        let engine = BackupEngine::new(config, local_storage(&temp_dir).await);
 
         let data = b"Hello, World!".repeat(1000).to_vec();
         let chunk_ids = engine.process_data(data).await.unwrap();
@@ -194,10 +906,11 @@ mod tests {
 
     #[tokio::test]
     async fn test_process_data_with_encryption() {
+        let temp_dir = TempDir::new().unwrap();
         let mut config = BackupConfig::default();
         config.encryption_key = Some(EncryptionKey::generate());
 
-        let engine = BackupEngine::new(config);
+        let engine = BackupEngine::new(config, local_storage(&temp_dir).await);
 
         let data = b"Secret data!".repeat(1000).to_vec();
         let chunk_ids = engine.process_data(data).await.unwrap();
@@ -207,8 +920,9 @@ mod tests {
 
     #[tokio::test]
     async fn test_deduplication() {
+        let temp_dir = TempDir::new().unwrap();
         let config = BackupConfig::default();
-        let engine = BackupEngine::new(config);
+        let engine = BackupEngine::new(config, local_storage(&temp_dir).await);
 
         let data = b"Same data".repeat(1000).to_vec();
 
@@ -222,4 +936,334 @@ mod tests {
         let stats = engine.dedup_stats().unwrap();
         assert!(stats.total_chunks > 0);
     }
+
+    #[tokio::test]
+    async fn test_process_data_persists_chunks() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = local_storage(&temp_dir).await;
+        let engine = BackupEngine::new(BackupConfig::default(), storage.clone());
+
+        let data = b"Persist me!".repeat(1000).to_vec();
+        let chunk_ids = engine.process_data(data.clone()).await.unwrap();
+
+        for chunk_id in &chunk_ids {
+            assert!(storage.chunk_exists(chunk_id).await.unwrap());
+        }
+
+        // Stored chunks are compressed, so they must differ from the input
+        let stored = storage.get_chunk(&chunk_ids[0]).await.unwrap();
+        assert_ne!(stored, data);
+
+        // A duplicate run must not write anything new
+        engine.process_data(data).await.unwrap();
+        assert_eq!(storage.list_chunks().await.unwrap().len(), chunk_ids.len());
+    }
+
+    #[tokio::test]
+    async fn test_restore_data_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let engine = BackupEngine::new(BackupConfig::default(), local_storage(&temp_dir).await);
+
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let chunk_ids = engine.process_data(data.clone()).await.unwrap();
+
+        let restored = engine.restore_data(&chunk_ids).await.unwrap();
+        assert_eq!(restored, data);
+    }
+
+    #[tokio::test]
+    async fn test_restore_data_roundtrip_with_encryption() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = EncryptionKey::generate();
+        let config = BackupConfig {
+            chunk_id_key: Some(key.chunk_id_key()),
+            encryption_key: Some(key),
+            ..BackupConfig::default()
+        };
+        let engine = BackupEngine::new(config, local_storage(&temp_dir).await);
+
+        let data = b"Secret data!".repeat(5000).to_vec();
+        let chunk_ids = engine.process_data(data.clone()).await.unwrap();
+
+        // Stored names must not be the plain content hash
+        let plain = Chunker::new(ChunkingStrategy::default())
+            .chunk_data(&data)
+            .unwrap();
+        assert_eq!(plain.len(), chunk_ids.len());
+        assert!(plain.iter().zip(&chunk_ids).all(|(p, id)| p.id != *id));
+
+        let restored = engine.restore_data(&chunk_ids).await.unwrap();
+        assert_eq!(restored, data);
+    }
+
+    #[tokio::test]
+    async fn test_backup_file_larger_than_buffer() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = BackupConfig {
+            chunking_strategy: ChunkingStrategy::ContentDefined {
+                min_size: 4 * 1024,
+                avg_size: 16 * 1024,
+                max_size: 64 * 1024,
+            },
+            ..BackupConfig::default()
+        };
+        let engine = BackupEngine::new(config, local_storage(&temp_dir).await);
+
+        // Many times the 64 KiB chunking window
+        let data: Vec<u8> = (0..8_000_000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect();
+        let file_path = temp_dir.path().join("large.bin");
+        fs::write(&file_path, &data).await.unwrap();
+
+        let file = engine.backup_file(&file_path).await.unwrap();
+        assert_eq!(file.size, data.len() as u64);
+        assert!(file.chunk_ids.len() > 100);
+
+        let target = temp_dir.path().join("restored.bin");
+        engine.restore_file(&file, &target).await.unwrap();
+        assert_eq!(fs::read(&target).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_sparse_file_stores_only_data() {
+        use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+        let temp_dir = TempDir::new().unwrap();
+        let engine = BackupEngine::new(BackupConfig::default(), local_storage(&temp_dir).await);
+
+        // 64 MiB with 8 KiB of data in the middle
+        let file_path = temp_dir.path().join("sparse.img");
+        let mut file = fs::File::create(&file_path).await.unwrap();
+        file.set_len(64 << 20).await.unwrap();
+        file.seek(std::io::SeekFrom::Start(32 << 20)).await.unwrap();
+        file.write_all(&[7; 8192]).await.unwrap();
+        file.sync_all().await.unwrap();
+        drop(file);
+
+        let backed_up = engine.backup_file(&file_path).await.unwrap();
+        assert_eq!(backed_up.size, 64 << 20);
+        // Skip the rest where the filesystem does not report holes
+        if backed_up.holes.is_empty() {
+            return;
+        }
+        assert_eq!(sparse::hole_bytes(&backed_up.holes), (64 << 20) - 8192);
+        assert_eq!(backed_up.chunk_ids.len(), 1);
+
+        let target = temp_dir.path().join("restored.img");
+        engine.restore_file(&backed_up, &target).await.unwrap();
+        assert!(fs::read(&target).await.unwrap() == fs::read(&file_path).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_restore_reads_chunk_headers() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = local_storage(&temp_dir).await;
+        let zstd_data = b"zstd chunk".repeat(1000);
+        let lz4_data = b"lz4 chunk".repeat(1000);
+
+        let zstd = BackupEngine::new(BackupConfig::default(), storage.clone());
+        let mut chunk_ids = zstd.process_data(zstd_data.clone()).await.unwrap();
+
+        // The repository default changes between backups
+        let config = BackupConfig {
+            compression: CompressionAlgorithm::Lz4,
+            ..BackupConfig::default()
+        };
+        let lz4 = BackupEngine::new(config, storage.clone());
+        chunk_ids.extend(lz4.process_data(lz4_data.clone()).await.unwrap());
+
+        let stored = storage.get_chunk(&chunk_ids[0]).await.unwrap();
+        let (header, _) = ChunkHeader::decode(&stored).unwrap();
+        assert_eq!(header.compression, CompressionAlgorithm::Zstd(0));
+        assert_eq!(header.key_id, None);
+
+        let restored = zstd.restore_data(&chunk_ids).await.unwrap();
+        assert_eq!(restored, [zstd_data, lz4_data].concat());
+
+        // An encrypted engine refuses plaintext chunks
+        let config = BackupConfig {
+            encryption_key: Some(EncryptionKey::generate()),
+            ..BackupConfig::default()
+        };
+        let encrypted = BackupEngine::new(config, storage);
+        assert!(encrypted.restore_data(&chunk_ids).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_restore_detects_corrupted_chunk() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = local_storage(&temp_dir).await;
+        let config = BackupConfig {
+            compression: CompressionAlgorithm::None,
+            ..BackupConfig::default()
+        };
+        let engine = BackupEngine::new(config, storage.clone());
+
+        let chunk_ids = engine.process_data(b"original".to_vec()).await.unwrap();
+        storage
+            .put_chunk(&chunk_ids[0], b"tampered".to_vec())
+            .await
+            .unwrap();
+
+        let result = engine.restore_data(&chunk_ids).await;
+        assert!(matches!(result, Err(Error::Corruption(_))));
+    }
+
+    #[tokio::test]
+    async fn test_create_snapshot_persists_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+        let engine = BackupEngine::new(BackupConfig::default(), local_storage(&temp_dir).await);
+
+        let file_path = temp_dir.path().join("file.txt");
+        fs::write(&file_path, b"manifest me").await.unwrap();
+        let file = engine.backup_file(&file_path).await.unwrap();
+
+        let snapshot = engine
+            .create_snapshot("test".to_string(), "/src".to_string(), vec![file], None)
+            .await
+            .unwrap();
+
+        let manifest = engine.load_snapshot(&snapshot.id).await.unwrap();
+        assert_eq!(manifest.snapshot.id, snapshot.id);
+        assert_eq!(manifest.files.len(), 1);
+        assert_eq!(manifest.files[0].size, 11);
+
+        let snapshots = engine.list_snapshots().await.unwrap();
+        assert_eq!(snapshots.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_forget_removes_snapshots_and_references() {
+        let temp_dir = TempDir::new().unwrap();
+        let engine = BackupEngine::new(BackupConfig::default(), local_storage(&temp_dir).await);
+
+        let file_path = temp_dir.path().join("file.txt");
+        fs::write(&file_path, b"kept twice").await.unwrap();
+        let mut ids = Vec::new();
+        for source in ["/a", "/a", "/b"] {
+            let file = engine.backup_file(&file_path).await.unwrap();
+            let snapshot = engine
+                .create_snapshot("test".to_string(), source.to_string(), vec![file], None)
+                .await
+                .unwrap();
+            ids.push(snapshot.id);
+        }
+        let chunk_id = engine.load_snapshot(&ids[0]).await.unwrap().files[0].chunk_ids[0].clone();
+        assert_eq!(engine.dedup_store().index().get_ref_count(&chunk_id), 3);
+
+        // Each source keeps its newest snapshot
+        let policy = RetentionPolicy {
+            keep_last: Some(1),
+            ..RetentionPolicy::default()
+        };
+        let decisions = engine.forget(&policy, None, true).await.unwrap();
+        let removed: Vec<_> = decisions.iter().filter(|d| !d.keep()).collect();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].snapshot.id, ids[0]);
+        assert_eq!(engine.list_snapshots().await.unwrap().len(), 3);
+
+        engine.forget(&policy, None, false).await.unwrap();
+        assert_eq!(engine.list_snapshots().await.unwrap().len(), 2);
+        assert!(engine.load_snapshot(&ids[0]).await.is_err());
+        assert_eq!(engine.dedup_store().index().get_ref_count(&chunk_id), 2);
+
+        // Limited to one source, the other is left alone
+        let decisions = engine.forget(&policy, Some("/b"), false).await.unwrap();
+        assert_eq!(decisions.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_prune_removes_unreferenced_chunks() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = local_storage(&temp_dir).await;
+        let engine = BackupEngine::new(BackupConfig::default(), storage.clone());
+
+        let mut snapshots = Vec::new();
+        for (name, content) in [("old.txt", "only in the old snapshot"), ("new.txt", "kept")] {
+            let file_path = temp_dir.path().join(name);
+            fs::write(&file_path, content).await.unwrap();
+            let file = engine.backup_file(&file_path).await.unwrap();
+            let snapshot = engine
+                .create_snapshot(name.to_string(), "/src".to_string(), vec![file], None)
+                .await
+                .unwrap();
+            snapshots.push(snapshot);
+        }
+        // Left behind by an interrupted backup
+        let orphans = engine.process_data(b"never snapshotted".to_vec()).await.unwrap();
+        storage.flush().await.unwrap();
+
+        engine.delete_snapshot(&snapshots[0].id).await.unwrap();
+        assert_eq!(storage.list_chunks().await.unwrap().len(), 3);
+
+        // A grace period reaching past the earliest time is refused
+        assert!(matches!(
+            engine.prune(Duration::MAX, true).await,
+            Err(Error::InvalidConfig(_))
+        ));
+
+        // Within the grace period nothing goes
+        let report = engine.prune(Duration::hours(1), false).await.unwrap();
+        assert_eq!(report.sweep.removed_chunks, 0);
+        assert_eq!(report.sweep.recent_chunks, 2);
+
+        let dry_run = engine.prune(Duration::zero(), true).await.unwrap();
+        assert_eq!(dry_run.sweep.removed_chunks, 2);
+        assert_eq!(storage.list_chunks().await.unwrap().len(), 3);
+
+        let report = engine.prune(Duration::zero(), false).await.unwrap();
+        assert_eq!(report.snapshots, 1);
+        assert_eq!(report.sweep.removed_chunks, 2);
+        assert_eq!(report.sweep.reclaimed_bytes, dry_run.sweep.reclaimed_bytes);
+        assert_eq!(storage.list_chunks().await.unwrap(), snapshots[1].chunk_ids);
+        assert!(!engine.dedup_store().is_duplicate(&orphans[0]));
+
+        let target = temp_dir.path().join("restored.txt");
+        let manifest = engine.load_snapshot(&snapshots[1].id).await.unwrap();
+        engine.restore_file(&manifest.files[0], &target).await.unwrap();
+        assert_eq!(fs::read(&target).await.unwrap(), b"kept");
+    }
+
+    #[tokio::test]
+    async fn test_dedup_index_survives_restart() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = local_storage(&temp_dir).await;
+        let data = b"Persisted dedup".repeat(1000).to_vec();
+
+        let file_path = temp_dir.path().join("file.bin");
+        fs::write(&file_path, &data).await.unwrap();
+
+        let engine = BackupEngine::open(BackupConfig::default(), storage.clone())
+            .await
+            .unwrap();
+        let file = engine.backup_file(&file_path).await.unwrap();
+        engine
+            .create_snapshot(
+                "first".to_string(),
+                "/src".to_string(),
+                vec![file.clone()],
+                None,
+            )
+            .await
+            .unwrap();
+
+        let reopened = BackupEngine::open(BackupConfig::default(), storage.clone())
+            .await
+            .unwrap();
+        for chunk_id in &file.chunk_ids {
+            assert!(reopened.dedup_store().is_duplicate(chunk_id));
+        }
+
+        // Losing the index entirely rebuilds it from the manifests
+        for key in storage.list_metadata(crate::dedup::INDEX_PREFIX).await.unwrap() {
+            storage.delete_metadata(&key).await.unwrap();
+        }
+        let rebuilt = BackupEngine::open(BackupConfig::default(), storage)
+            .await
+            .unwrap();
+        for chunk_id in &file.chunk_ids {
+            assert!(rebuilt.dedup_store().is_duplicate(chunk_id));
+        }
+    }
 }
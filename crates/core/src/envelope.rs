@@ -0,0 +1,130 @@
+use backupforge_common::{Error, Result};
+
+use crate::compression::CompressionAlgorithm;
+
+/// Marks a stored chunk as starting with a `ChunkHeader`
+pub const CHUNK_MAGIC: &[u8; 4] = b"BFCK";
+
+/// Chunk header format written by this release
+pub const CHUNK_FORMAT_VERSION: u8 = 1;
+
+const FLAG_ENCRYPTED: u8 = 0x01;
+
+/// Plaintext header in front of every stored chunk, recording how the payload
+/// after it was produced so it can be read back whatever the current settings
+///
+/// Layout (20 bytes, little endian):
+/// magic (4) | version (1) | compression (1) | flags (1) | reserved (1) |
+/// key id (8) | uncompressed length (4)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkHeader {
+    pub compression: CompressionAlgorithm,
+    /// ID of the master key the payload is encrypted with, if encrypted
+    pub key_id: Option<[u8; 8]>,
+    pub uncompressed_len: u32,
+}
+
+impl ChunkHeader {
+    pub const SIZE: usize = 20;
+
+    /// Prefix `payload` with this header
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(Self::SIZE + payload.len());
+        out.extend_from_slice(CHUNK_MAGIC);
+        out.push(CHUNK_FORMAT_VERSION);
+        out.push(compression_code(self.compression));
+        out.push(if self.key_id.is_some() { FLAG_ENCRYPTED } else { 0 });
+        out.push(0);
+        out.extend_from_slice(&self.key_id.unwrap_or_default());
+        out.extend_from_slice(&self.uncompressed_len.to_le_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    /// Split stored chunk data into its header and payload
+    pub fn decode(data: &[u8]) -> Result<(ChunkHeader, &[u8])> {
+        if !data.starts_with(CHUNK_MAGIC) {
+            return Err(Error::Corruption("Chunk has no header".to_string()));
+        }
+        if data.len() < Self::SIZE {
+            return Err(Error::Corruption("Truncated chunk header".to_string()));
+        }
+
+        let version = data[4];
+        if version != CHUNK_FORMAT_VERSION {
+            return Err(Error::Corruption(format!(
+                "Unsupported chunk format version {}",
+                version
+            )));
+        }
+
+        let compression = compression_from_code(data[5])?;
+        let key_id = if data[6] & FLAG_ENCRYPTED != 0 {
+            let mut id = [0u8; 8];
+            id.copy_from_slice(&data[8..16]);
+            Some(id)
+        } else {
+            None
+        };
+        let uncompressed_len = u32::from_le_bytes([data[16], data[17], data[18], data[19]]);
+
+        let header = ChunkHeader {
+            compression,
+            key_id,
+            uncompressed_len,
+        };
+        Ok((header, &data[Self::SIZE..]))
+    }
+}
+
+fn compression_code(algorithm: CompressionAlgorithm) -> u8 {
+    match algorithm {
+        CompressionAlgorithm::None => 0,
+        CompressionAlgorithm::Zstd(_) => 1,
+        CompressionAlgorithm::Lz4 => 2,
+    }
+}
+
+/// The zstd level only matters when compressing, so it is not stored
+fn compression_from_code(code: u8) -> Result<CompressionAlgorithm> {
+    match code {
+        0 => Ok(CompressionAlgorithm::None),
+        1 => Ok(CompressionAlgorithm::Zstd(0)),
+        2 => Ok(CompressionAlgorithm::Lz4),
+        other => Err(Error::Corruption(format!(
+            "Unknown chunk compression {}",
+            other
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = ChunkHeader {
+            compression: CompressionAlgorithm::Lz4,
+            key_id: Some([1, 2, 3, 4, 5, 6, 7, 8]),
+            uncompressed_len: 123_456,
+        };
+
+        let encoded = header.encode(b"payload");
+        assert_eq!(encoded.len(), ChunkHeader::SIZE + 7);
+
+        let (decoded, payload) = ChunkHeader::decode(&encoded).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(payload, b"payload");
+
+        assert!(matches!(
+            ChunkHeader::decode(b"headerless data"),
+            Err(Error::Corruption(_))
+        ));
+
+        assert!(ChunkHeader::decode(&encoded[..10]).is_err());
+        let mut bad_version = encoded.clone();
+        bad_version[4] = 99;
+        assert!(ChunkHeader::decode(&bad_version).is_err());
+    }
+}
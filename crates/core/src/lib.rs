@@ -1,11 +1,30 @@
+pub mod check;
 pub mod chunker;
 pub mod dedup;
 pub mod compression;
 pub mod encryption;
 pub mod engine;
+pub mod envelope;
+pub mod lock;
+pub mod manifest;
+pub mod metadata;
+pub mod pipeline;
+pub mod repair;
+pub mod repository;
+pub mod retention;
+pub mod sparse;
 
-pub use chunker::{Chunker, ChunkingStrategy};
+pub use check::{CheckOptions, CheckProblem, CheckReport, ProblemKind, ReadData};
+pub use chunker::{ChunkIdHash, Chunker, ChunkingStrategy};
 pub use dedup::{DedupIndex, DedupStore};
 pub use compression::{Compressor, CompressionAlgorithm};
-pub use encryption::{Encryptor, EncryptionKey};
-pub use engine::BackupEngine;
+pub use encryption::{Encryptor, EncryptionKey, WrappedKey};
+pub use engine::{BackupConfig, BackupEngine, PruneReport};
+pub use envelope::ChunkHeader;
+pub use lock::{LockInfo, LockKind, RepositoryLock};
+pub use manifest::{ManifestStore, SnapshotManifest};
+pub use metadata::RestoreOptions;
+pub use pipeline::OrderedTasks;
+pub use repair::{DamagedFile, RepairOptions, RepairReport};
+pub use repository::{Credential, EncryptionConfig, KeySlot, Repository, RepositoryConfig};
+pub use retention::RetentionDecision;
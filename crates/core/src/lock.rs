@@ -0,0 +1,434 @@
+use backupforge_common::{Error, Result};
+use backupforge_storage::StorageBackend;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+/// Metadata key prefix of lock objects
+pub const LOCKS_PREFIX: &str = "locks/";
+
+/// How often a held lock is refreshed
+const REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// Age of the last refresh after which a lock is considered abandoned
+const STALE_AFTER_MINUTES: i64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockKind {
+    /// Held by operations that only add data, any number at once
+    Shared,
+    /// Held by operations that remove data, excluding every other lock
+    Exclusive,
+}
+
+impl LockKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LockKind::Shared => "shared",
+            LockKind::Exclusive => "exclusive",
+        }
+    }
+}
+
+/// Contents of a lock object
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockInfo {
+    pub kind: LockKind,
+    /// What the holder is doing, such as "backup" or "prune"
+    pub operation: String,
+    pub hostname: String,
+    pub pid: u32,
+    pub created_at: DateTime<Utc>,
+    pub refreshed_at: DateTime<Utc>,
+}
+
+impl LockInfo {
+    fn new(kind: LockKind, operation: &str) -> Self {
+        let now = Utc::now();
+        Self {
+            kind,
+            operation: operation.to_string(),
+            hostname: hostname(),
+            pid: std::process::id(),
+            created_at: now,
+            refreshed_at: now,
+        }
+    }
+
+    /// Whether the holder stopped refreshing the lock, or is a process on
+    /// this host that no longer runs
+    pub fn is_stale(&self) -> bool {
+        if Utc::now() - self.refreshed_at > Duration::minutes(STALE_AFTER_MINUTES) {
+            return true;
+        }
+        self.hostname == hostname() && !process_exists(self.pid)
+    }
+
+    fn conflicts_with(&self, kind: LockKind) -> bool {
+        kind == LockKind::Exclusive || self.kind == LockKind::Exclusive
+    }
+}
+
+/// A lock held on a repository, refreshed in the background until released
+///
+/// Dropping the lock without `release` removes it on a best-effort basis.
+/// The lock is lost when it is removed or cannot be refreshed before other
+/// processes would consider it stale; holders must stop once `lost` returns.
+pub struct RepositoryLock {
+    storage: Arc<dyn StorageBackend>,
+    key: String,
+    refresher: JoinHandle<()>,
+    /// Why the lock was lost, once it is
+    lost: watch::Receiver<Option<String>>,
+    released: bool,
+}
+
+impl RepositoryLock {
+    /// Take a lock for `operation`, failing if a live lock conflicts
+    ///
+    /// The lock is written before conflicts are checked a second time, so
+    /// of two processes racing for conflicting locks at least one backs off.
+    pub async fn acquire(
+        storage: Arc<dyn StorageBackend>,
+        kind: LockKind,
+        operation: &str,
+    ) -> Result<Self> {
+        check_conflicts(storage.as_ref(), kind, None).await?;
+
+        let key = format!("{}{}", LOCKS_PREFIX, Uuid::new_v4());
+        let info = LockInfo::new(kind, operation);
+        storage.put_metadata(&key, encode(&info)?).await?;
+
+        if let Err(e) = check_conflicts(storage.as_ref(), kind, Some(&key)).await {
+            storage.delete_metadata(&key).await?;
+            return Err(e);
+        }
+
+        let (lost_tx, lost) = watch::channel(None);
+        let refresher = tokio::spawn(refresh(storage.clone(), key.clone(), info, lost_tx));
+        tracing::debug!("Acquired {:?} lock {}", kind, key);
+
+        Ok(Self {
+            storage,
+            key,
+            refresher,
+            lost,
+            released: false,
+        })
+    }
+
+    /// Wait until the lock is lost, returning the error to abort with
+    pub async fn lost(&self) -> Error {
+        let mut lost = self.lost.clone();
+        let reason = lost.wait_for(Option::is_some).await.ok().and_then(|r| r.clone());
+        match reason {
+            Some(reason) => Error::RepositoryLocked(reason),
+            // The refresher only stops without a reason once released
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Remove the lock
+    pub async fn release(mut self) -> Result<()> {
+        self.released = true;
+        self.refresher.abort();
+        self.storage.delete_metadata(&self.key).await
+    }
+}
+
+impl Drop for RepositoryLock {
+    fn drop(&mut self) {
+        self.refresher.abort();
+        if self.released {
+            return;
+        }
+
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let storage = self.storage.clone();
+            let key = std::mem::take(&mut self.key);
+            runtime.spawn(async move {
+                if let Err(e) = storage.delete_metadata(&key).await {
+                    tracing::warn!("Failed to remove lock {}: {}", key, e);
+                }
+            });
+        }
+    }
+}
+
+/// Every lock object with its contents, `None` when unreadable
+async fn read_locks(storage: &dyn StorageBackend) -> Result<Vec<(String, Option<LockInfo>)>> {
+    let mut locks = Vec::new();
+
+    for key in storage.list_metadata(LOCKS_PREFIX).await? {
+        let data = match storage.get_metadata(&key).await {
+            Ok(data) => data,
+            // Released since it was listed
+            Err(Error::MetadataNotFound(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        let info = serde_json::from_slice(&data).ok();
+        locks.push((key, info));
+    }
+
+    Ok(locks)
+}
+
+async fn check_conflicts(
+    storage: &dyn StorageBackend,
+    kind: LockKind,
+    own_key: Option<&str>,
+) -> Result<()> {
+    for (key, info) in read_locks(storage).await? {
+        if Some(key.as_str()) == own_key {
+            continue;
+        }
+        let Some(info) = info else {
+            tracing::warn!("Ignoring unreadable lock {}", key);
+            continue;
+        };
+        if info.is_stale() {
+            tracing::warn!(
+                "Ignoring stale lock {} of PID {} on {}",
+                key,
+                info.pid,
+                info.hostname
+            );
+            continue;
+        }
+
+        if info.conflicts_with(kind) {
+            return Err(Error::RepositoryLocked(format!(
+                "{} holds a{} lock (PID {} on {}, since {}); if it is no longer running, \
+                 remove the lock with `backupforge unlock`",
+                info.operation,
+                if info.kind == LockKind::Exclusive {
+                    "n exclusive"
+                } else {
+                    " shared"
+                },
+                info.pid,
+                info.hostname,
+                info.created_at.format("%Y-%m-%d %H:%M:%S")
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Locks currently held on the repository, stale ones included
+pub async fn list_locks(storage: &dyn StorageBackend) -> Result<Vec<LockInfo>> {
+    Ok(read_locks(storage)
+        .await?
+        .into_iter()
+        .filter_map(|(_, info)| info)
+        .collect())
+}
+
+/// Remove stale and unreadable locks, or every lock if `all`, returning
+/// the readable ones removed
+pub async fn remove_locks(storage: &dyn StorageBackend, all: bool) -> Result<Vec<LockInfo>> {
+    let mut removed = Vec::new();
+
+    for (key, info) in read_locks(storage).await? {
+        if all || info.as_ref().is_none_or(LockInfo::is_stale) {
+            storage.delete_metadata(&key).await?;
+            removed.extend(info);
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Refresh the lock until it is removed or has gone unrefreshed for so long
+/// that it would turn stale before the next attempt, then report it lost
+async fn refresh(
+    storage: Arc<dyn StorageBackend>,
+    key: String,
+    mut info: LockInfo,
+    lost: watch::Sender<Option<String>>,
+) {
+    let give_up_after = Duration::minutes(STALE_AFTER_MINUTES)
+        - Duration::from_std(REFRESH_INTERVAL).unwrap_or_default();
+
+    loop {
+        tokio::time::sleep(REFRESH_INTERVAL).await;
+
+        // Writing a removed lock back would hide that it was taken away
+        let result = match storage.get_metadata(&key).await {
+            Ok(_) => {
+                let now = Utc::now();
+                let previous = std::mem::replace(&mut info.refreshed_at, now);
+                let result = match encode(&info) {
+                    Ok(data) => storage.put_metadata(&key, data).await,
+                    Err(e) => Err(e),
+                };
+                if result.is_err() {
+                    info.refreshed_at = previous;
+                }
+                result
+            }
+            Err(Error::MetadataNotFound(_)) => {
+                tracing::warn!("Lock {} was removed", key);
+                let _ = lost.send(Some(format!(
+                    "Lock {} was removed by another process",
+                    key
+                )));
+                return;
+            }
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            tracing::warn!("Failed to refresh lock {}: {}", key, e);
+            if Utc::now() - info.refreshed_at >= give_up_after {
+                let _ = lost.send(Some(format!(
+                    "Lock {} could not be refreshed since {}, so other processes may \
+                     consider it stale: {}",
+                    key,
+                    info.refreshed_at.format("%Y-%m-%d %H:%M:%S"),
+                    e
+                )));
+                return;
+            }
+        }
+    }
+}
+
+fn encode(info: &LockInfo) -> Result<Vec<u8>> {
+    serde_json::to_vec(info)
+        .map_err(|e| Error::Serialization(format!("Failed to encode lock: {}", e)))
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: gethostname writes at most `buf.len()` bytes into `buf`
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if result != 0 {
+        return "unknown".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
+
+#[cfg(unix)]
+fn process_exists(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks whether the process exists
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // The process exists but belongs to another user
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_exists(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backupforge_storage::LocalStorage;
+    use tempfile::TempDir;
+
+    async fn storage(temp_dir: &TempDir) -> Arc<dyn StorageBackend> {
+        Arc::new(LocalStorage::new(temp_dir.path()).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_shared_and_exclusive_locks() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = storage(&temp_dir).await;
+
+        let first = RepositoryLock::acquire(storage.clone(), LockKind::Shared, "backup")
+            .await
+            .unwrap();
+        let second = RepositoryLock::acquire(storage.clone(), LockKind::Shared, "backup")
+            .await
+            .unwrap();
+        assert_eq!(list_locks(storage.as_ref()).await.unwrap().len(), 2);
+
+        let err = RepositoryLock::acquire(storage.clone(), LockKind::Exclusive, "prune")
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, Error::RepositoryLocked(_)), "{}", err);
+        // The failed attempt leaves nothing behind
+        assert_eq!(list_locks(storage.as_ref()).await.unwrap().len(), 2);
+
+        first.release().await.unwrap();
+        second.release().await.unwrap();
+        let exclusive = RepositoryLock::acquire(storage.clone(), LockKind::Exclusive, "prune")
+            .await
+            .unwrap();
+        assert!(
+            RepositoryLock::acquire(storage.clone(), LockKind::Shared, "backup")
+                .await
+                .is_err()
+        );
+        exclusive.release().await.unwrap();
+        assert!(list_locks(storage.as_ref()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stale_locks_are_ignored_and_removed() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = storage(&temp_dir).await;
+
+        // Abandoned long ago, and held by a process that no longer exists
+        let mut old = LockInfo::new(LockKind::Exclusive, "prune");
+        old.refreshed_at = Utc::now() - Duration::hours(2);
+        let mut dead = LockInfo::new(LockKind::Exclusive, "prune");
+        dead.pid = u32::MAX - 1;
+        for info in [&old, &dead] {
+            let key = format!("{}{}", LOCKS_PREFIX, Uuid::new_v4());
+            storage.put_metadata(&key, encode(info).unwrap()).await.unwrap();
+        }
+        storage
+            .put_metadata(&format!("{}garbage", LOCKS_PREFIX), b"{".to_vec())
+            .await
+            .unwrap();
+
+        let live = RepositoryLock::acquire(storage.clone(), LockKind::Shared, "backup")
+            .await
+            .unwrap();
+
+        let removed = remove_locks(storage.as_ref(), false).await.unwrap();
+        assert_eq!(removed.len(), 2);
+        assert_eq!(list_locks(storage.as_ref()).await.unwrap().len(), 1);
+
+        // Removing everything also clears live locks
+        assert_eq!(remove_locks(storage.as_ref(), true).await.unwrap().len(), 1);
+        drop(live);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_removed_lock_is_lost() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = storage(&temp_dir).await;
+
+        let lock = RepositoryLock::acquire(storage.clone(), LockKind::Exclusive, "prune")
+            .await
+            .unwrap();
+        remove_locks(storage.as_ref(), true).await.unwrap();
+
+        let err = lock.lost().await;
+        assert!(matches!(err, Error::RepositoryLocked(_)), "{}", err);
+        // The next refresh noticed and did not write the lock back
+        assert!(list_locks(storage.as_ref()).await.unwrap().is_empty());
+    }
+}
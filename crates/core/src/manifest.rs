@@ -0,0 +1,182 @@
+use backupforge_common::{
+    types::{FileMetadata, Snapshot, SnapshotId},
+    Error, Result,
+};
+use backupforge_storage::StorageBackend;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Metadata key prefix under which snapshot manifests are stored
+pub const SNAPSHOTS_PREFIX: &str = "snapshots/";
+
+/// A snapshot header together with every file it contains
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub snapshot: Snapshot,
+    pub files: Vec<FileMetadata>,
+}
+
+impl SnapshotManifest {
+    /// Metadata key for a snapshot's manifest
+    pub fn key(id: &SnapshotId) -> String {
+        format!("{}{}", SNAPSHOTS_PREFIX, id.0)
+    }
+}
+
+/// Header-only view of a manifest, so listing skips building the file list
+#[derive(Deserialize)]
+struct ManifestHeader {
+    snapshot: Snapshot,
+}
+
+/// Reads and writes snapshot manifests through a storage backend
+#[derive(Clone)]
+pub struct ManifestStore {
+    storage: Arc<dyn StorageBackend>,
+}
+
+impl ManifestStore {
+    pub fn new(storage: Arc<dyn StorageBackend>) -> Self {
+        Self { storage }
+    }
+
+    /// Persist a manifest, replacing any previous version
+    pub async fn save(&self, manifest: &SnapshotManifest) -> Result<()> {
+        let data = serde_json::to_vec(manifest)
+            .map_err(|e| Error::Serialization(format!("Failed to encode manifest: {}", e)))?;
+
+        self.storage
+            .put_metadata(&SnapshotManifest::key(&manifest.snapshot.id), data)
+            .await
+    }
+
+    /// Load the full manifest of a snapshot
+    pub async fn load(&self, id: &SnapshotId) -> Result<SnapshotManifest> {
+        let data = match self.storage.get_metadata(&SnapshotManifest::key(id)).await {
+            Ok(data) => data,
+            Err(Error::MetadataNotFound(_)) => {
+                return Err(Error::SnapshotNotFound(id.0.to_string()))
+            }
+            Err(e) => return Err(e),
+        };
+
+        serde_json::from_slice(&data).map_err(|e| {
+            Error::Serialization(format!("Failed to decode manifest {}: {}", id.0, e))
+        })
+    }
+
+    /// Delete the manifest of a snapshot
+    pub async fn delete(&self, id: &SnapshotId) -> Result<()> {
+        self.storage
+            .delete_metadata(&SnapshotManifest::key(id))
+            .await
+    }
+
+    /// List all snapshots, oldest first
+    pub async fn list(&self) -> Result<Vec<Snapshot>> {
+        let mut snapshots = Vec::new();
+
+        for key in self.storage.list_metadata(SNAPSHOTS_PREFIX).await? {
+            let data = self.storage.get_metadata(&key).await?;
+            let header: ManifestHeader = serde_json::from_slice(&data).map_err(|e| {
+                Error::Serialization(format!("Failed to decode manifest {}: {}", key, e))
+            })?;
+            snapshots.push(header.snapshot);
+        }
+
+        snapshots.sort_by_key(|s| s.created_at);
+        Ok(snapshots)
+    }
+
+    /// Resolve "latest", a full snapshot ID or a unique ID prefix
+    pub async fn find(&self, id: &str) -> Result<Snapshot> {
+        let snapshots = self.list().await?;
+
+        if id == "latest" {
+            return snapshots
+                .into_iter()
+                .last()
+                .ok_or_else(|| Error::SnapshotNotFound("latest".to_string()));
+        }
+
+        let mut matches = snapshots
+            .into_iter()
+            .filter(|s| s.id.0.to_string().starts_with(id));
+
+        match (matches.next(), matches.next()) {
+            (Some(snapshot), None) => Ok(snapshot),
+            (Some(_), Some(_)) => Err(Error::SnapshotNotFound(format!(
+                "{} is ambiguous, use a longer prefix",
+                id
+            ))),
+            (None, _) => Err(Error::SnapshotNotFound(id.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backupforge_common::types::NodeKind;
+    use backupforge_storage::LocalStorage;
+    use chrono::{Duration, Utc};
+    use tempfile::TempDir;
+
+    fn manifest(name: &str, age_hours: i64) -> SnapshotManifest {
+        SnapshotManifest {
+            snapshot: Snapshot {
+                id: SnapshotId::new(),
+                name: name.to_string(),
+                created_at: Utc::now() - Duration::hours(age_hours),
+                source_path: "/data".to_string(),
+                total_size: 5,
+                compressed_size: 5,
+                file_count: 1,
+                chunk_ids: Vec::new(),
+                parent_snapshot: None,
+                tags: Vec::new(),
+                stats: None,
+            },
+            files: vec![FileMetadata {
+                path: "/data/file".to_string(),
+                size: 5,
+                modified: Utc::now(),
+                permissions: 0o644,
+                is_directory: false,
+                chunk_ids: Vec::new(),
+                kind: NodeKind::File,
+                holes: Vec::new(),
+                inode: None,
+                ctime: None,
+                unix: None,
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_manifest_roundtrip_and_listing() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = Arc::new(LocalStorage::new(temp_dir.path()).await.unwrap());
+        let store = ManifestStore::new(storage);
+
+        let older = manifest("older", 2);
+        let newer = manifest("newer", 1);
+        store.save(&newer).await.unwrap();
+        store.save(&older).await.unwrap();
+
+        let loaded = store.load(&older.snapshot.id).await.unwrap();
+        assert_eq!(loaded.files.len(), 1);
+        assert_eq!(loaded.files[0].path, "/data/file");
+
+        let listed = store.list().await.unwrap();
+        let names: Vec<_> = listed.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["older", "newer"]);
+
+        assert_eq!(store.find("latest").await.unwrap().name, "newer");
+        let prefix = &older.snapshot.id.0.to_string()[..8];
+        assert_eq!(store.find(prefix).await.unwrap().name, "older");
+
+        let missing = store.load(&SnapshotId::new()).await;
+        assert!(matches!(missing, Err(Error::SnapshotNotFound(_))));
+    }
+}
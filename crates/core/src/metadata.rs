@@ -0,0 +1,544 @@
+use backupforge_common::{
+    types::{FileMetadata, NodeKind, UnixMetadata},
+    Error, Result,
+};
+use std::path::Path;
+
+/// How file metadata is re-applied on restore
+#[derive(Debug, Clone)]
+pub struct RestoreOptions {
+    /// Restore owners and groups; without root this only succeeds for files
+    /// owned by the restoring user, and other failures are skipped
+    pub restore_ownership: bool,
+}
+
+impl Default for RestoreOptions {
+    fn default() -> Self {
+        Self {
+            restore_ownership: true,
+        }
+    }
+}
+
+/// Read the POSIX metadata of `path`, whose stat result is `metadata`
+///
+/// Attributes that cannot be read are logged and left out rather than
+/// failing the backup.
+#[cfg(unix)]
+pub fn capture(path: &Path, metadata: &std::fs::Metadata) -> Option<UnixMetadata> {
+    use chrono::DateTime;
+    use std::os::unix::fs::MetadataExt;
+
+    let mut unix = UnixMetadata {
+        mode: metadata.mode(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        user: names::user(metadata.uid()),
+        group: names::group(metadata.gid()),
+        accessed: DateTime::from_timestamp(metadata.atime(), metadata.atime_nsec() as u32)
+            .unwrap_or_default(),
+        xattrs: Vec::new(),
+        acl_access: Vec::new(),
+        acl_default: Vec::new(),
+    };
+
+    if let Err(e) = xattrs::capture(path, &mut unix) {
+        tracing::warn!("Failed to read xattrs of {}: {}", path.display(), e);
+    }
+
+    Some(unix)
+}
+
+#[cfg(not(unix))]
+pub fn capture(_path: &Path, _metadata: &std::fs::Metadata) -> Option<UnixMetadata> {
+    None
+}
+
+/// Classify the entry at `path`, whose `lstat` result is `metadata`
+#[cfg(unix)]
+pub fn node_kind(path: &Path, metadata: &std::fs::Metadata) -> std::io::Result<NodeKind> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let file_type = metadata.file_type();
+    Ok(if file_type.is_dir() {
+        NodeKind::Directory
+    } else if file_type.is_symlink() {
+        NodeKind::Symlink {
+            target: std::fs::read_link(path)?.to_string_lossy().into_owned(),
+        }
+    } else if file_type.is_char_device() {
+        NodeKind::CharDevice {
+            rdev: metadata.rdev(),
+        }
+    } else if file_type.is_block_device() {
+        NodeKind::BlockDevice {
+            rdev: metadata.rdev(),
+        }
+    } else if file_type.is_fifo() {
+        NodeKind::Fifo
+    } else if file_type.is_socket() {
+        NodeKind::Socket
+    } else {
+        NodeKind::File
+    })
+}
+
+#[cfg(not(unix))]
+pub fn node_kind(path: &Path, metadata: &std::fs::Metadata) -> std::io::Result<NodeKind> {
+    let file_type = metadata.file_type();
+    Ok(if file_type.is_dir() {
+        NodeKind::Directory
+    } else if file_type.is_symlink() {
+        NodeKind::Symlink {
+            target: std::fs::read_link(path)?.to_string_lossy().into_owned(),
+        }
+    } else {
+        NodeKind::File
+    })
+}
+
+/// Device and inode of a regular file with other hard links, shared by all
+/// of its names
+#[cfg(unix)]
+pub fn hard_link_key(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    (metadata.is_file() && metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+pub fn hard_link_key(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Create the device node, FIFO or socket recorded by `file` at `path`
+///
+/// Device nodes need root; without it they are skipped with a warning and
+/// `false` is returned.
+#[cfg(unix)]
+pub fn create_node(path: &Path, file: &FileMetadata) -> Result<bool> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let (file_type, rdev) = match file.kind {
+        NodeKind::CharDevice { rdev } => (libc::S_IFCHR, rdev),
+        NodeKind::BlockDevice { rdev } => (libc::S_IFBLK, rdev),
+        NodeKind::Fifo => (libc::S_IFIFO, 0),
+        NodeKind::Socket => (libc::S_IFSOCK, 0),
+        ref kind => {
+            return Err(Error::Unknown(format!(
+                "Cannot create {:?} as a special file: {}",
+                kind, file.path
+            )))
+        }
+    };
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, e)))?;
+    let mode = file_type | (file.permissions & 0o7777) as libc::mode_t;
+
+    // SAFETY: `c_path` is a valid NUL-terminated string for the whole call
+    if unsafe { libc::mknod(c_path.as_ptr(), mode, rdev as libc::dev_t) } != 0 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            tracing::warn!("Cannot create {}: {}", path.display(), e);
+            return Ok(false);
+        }
+        return Err(e.into());
+    }
+
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+pub fn create_node(path: &Path, file: &FileMetadata) -> Result<bool> {
+    tracing::warn!("Skipping {:?} {}: unsupported here", file.kind, path.display());
+    Ok(false)
+}
+
+/// Re-apply the recorded metadata of `file` to the restored `path`
+///
+/// Ownership goes first since changing it clears setuid bits, and timestamps
+/// last since every other change touches them.
+#[cfg(unix)]
+pub fn apply(path: &Path, file: &FileMetadata, options: &RestoreOptions) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let Some(unix) = &file.unix else {
+        return set_modified(path, file);
+    };
+    let is_symlink = std::fs::symlink_metadata(path)?.file_type().is_symlink();
+
+    if options.restore_ownership {
+        let uid = unix
+            .user
+            .as_deref()
+            .and_then(names::uid)
+            .unwrap_or(unix.uid);
+        let gid = unix
+            .group
+            .as_deref()
+            .and_then(names::gid)
+            .unwrap_or(unix.gid);
+
+        match std::os::unix::fs::lchown(path, Some(uid), Some(gid)) {
+            Ok(()) => {}
+            // Expected when restoring someone else's files without root
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                tracing::debug!("Cannot restore owner of {}: {}", path.display(), e);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    // Symlink permissions are meaningless, and chmod would follow the link
+    if !is_symlink {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(unix.mode & 0o7777))?;
+    }
+
+    xattrs::apply(path, unix);
+
+    let accessed = filetime::FileTime::from_unix_time(
+        unix.accessed.timestamp(),
+        unix.accessed.timestamp_subsec_nanos(),
+    );
+    filetime::set_symlink_file_times(path, accessed, file_time(file))?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn apply(path: &Path, file: &FileMetadata, _options: &RestoreOptions) -> Result<()> {
+    set_modified(path, file)
+}
+
+/// Restore only the modification time, for entries without POSIX metadata
+fn set_modified(path: &Path, file: &FileMetadata) -> Result<()> {
+    filetime::set_file_mtime(path, file_time(file))?;
+    Ok(())
+}
+
+fn file_time(file: &FileMetadata) -> filetime::FileTime {
+    filetime::FileTime::from_unix_time(
+        file.modified.timestamp(),
+        file.modified.timestamp_subsec_nanos(),
+    )
+}
+
+/// User and group name lookups, cached since every file repeats them
+#[cfg(unix)]
+mod names {
+    use std::collections::HashMap;
+    use std::sync::{Mutex, OnceLock};
+
+    type Cache<K, V> = OnceLock<Mutex<HashMap<K, Option<V>>>>;
+
+    static USERS: Cache<u32, String> = OnceLock::new();
+    static GROUPS: Cache<u32, String> = OnceLock::new();
+    static UIDS: Cache<String, u32> = OnceLock::new();
+    static GIDS: Cache<String, u32> = OnceLock::new();
+
+    fn cached<K, V>(cache: &Cache<K, V>, key: K, lookup: impl FnOnce(&K) -> Option<V>) -> Option<V>
+    where
+        K: std::hash::Hash + Eq,
+        V: Clone,
+    {
+        let mut map = cache.get_or_init(Default::default).lock().unwrap();
+        map.entry(key).or_insert_with_key(|key| lookup(key)).clone()
+    }
+
+    pub fn user(uid: u32) -> Option<String> {
+        cached(&USERS, uid, |uid| {
+            uzers::get_user_by_uid(*uid).map(|u| u.name().to_string_lossy().into_owned())
+        })
+    }
+
+    pub fn group(gid: u32) -> Option<String> {
+        cached(&GROUPS, gid, |gid| {
+            uzers::get_group_by_gid(*gid).map(|g| g.name().to_string_lossy().into_owned())
+        })
+    }
+
+    pub fn uid(name: &str) -> Option<u32> {
+        cached(&UIDS, name.to_string(), |name| {
+            uzers::get_user_by_name(name).map(|u| u.uid())
+        })
+    }
+
+    pub fn gid(name: &str) -> Option<u32> {
+        cached(&GIDS, name.to_string(), |name| {
+            uzers::get_group_by_name(name).map(|g| g.gid())
+        })
+    }
+}
+
+#[cfg(unix)]
+mod xattrs {
+    use backupforge_common::types::{ExtendedAttribute, UnixMetadata};
+    use std::io;
+    use std::path::Path;
+
+    /// ACLs are kept apart from other attributes, in a portable text form
+    #[cfg(target_os = "linux")]
+    const ACL_ACCESS: &str = "system.posix_acl_access";
+    #[cfg(target_os = "linux")]
+    const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+    pub fn capture(path: &Path, unix: &mut UnixMetadata) -> io::Result<()> {
+        if !xattr::SUPPORTED_PLATFORM {
+            return Ok(());
+        }
+
+        let names = match xattr::list(path) {
+            Ok(names) => names,
+            // The filesystem has no xattrs at all
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        for name in names {
+            let name = name.to_string_lossy().into_owned();
+            // Removed since it was listed
+            let Some(value) = xattr::get(path, &name)? else {
+                continue;
+            };
+
+            #[cfg(target_os = "linux")]
+            match name.as_str() {
+                ACL_ACCESS => {
+                    unix.acl_access = super::acl::decode(&value)?;
+                    continue;
+                }
+                ACL_DEFAULT => {
+                    unix.acl_default = super::acl::decode(&value)?;
+                    continue;
+                }
+                _ => {}
+            }
+
+            unix.xattrs.push(ExtendedAttribute { name, value });
+        }
+
+        Ok(())
+    }
+
+    /// Failures are logged: attributes such as `security.*` may need
+    /// privileges the restoring user lacks
+    pub fn apply(path: &Path, unix: &UnixMetadata) {
+        let mut attributes: Vec<(&str, Vec<u8>)> = unix
+            .xattrs
+            .iter()
+            .map(|a| (a.name.as_str(), a.value.clone()))
+            .collect();
+
+        #[cfg(target_os = "linux")]
+        for (name, entries) in [
+            (ACL_ACCESS, &unix.acl_access),
+            (ACL_DEFAULT, &unix.acl_default),
+        ] {
+            if entries.is_empty() {
+                continue;
+            }
+            match super::acl::encode(entries) {
+                Ok(value) => attributes.push((name, value)),
+                Err(e) => tracing::warn!("Invalid ACL for {}: {}", path.display(), e),
+            }
+        }
+
+        for (name, value) in attributes {
+            if let Err(e) = xattr::set(path, name, &value) {
+                tracing::warn!("Failed to restore {} on {}: {}", name, path.display(), e);
+            }
+        }
+    }
+}
+
+/// Conversion between the kernel's binary ACL xattrs and `getfacl -n` text
+///
+/// The binary form is a little endian u32 version (2) followed by entries of
+/// u16 tag, u16 permissions and u32 qualifier.
+#[cfg(target_os = "linux")]
+mod acl {
+    use std::io;
+
+    const VERSION: u32 = 2;
+    const UNDEFINED_ID: u32 = u32::MAX;
+    const TAGS: [(u16, &str, bool); 6] = [
+        (0x01, "user", false),
+        (0x02, "user", true),
+        (0x04, "group", false),
+        (0x08, "group", true),
+        (0x10, "mask", false),
+        (0x20, "other", false),
+    ];
+
+    fn invalid(message: String) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    }
+
+    pub fn decode(value: &[u8]) -> io::Result<Vec<String>> {
+        if value.len() < 4 || !value[4..].chunks_exact(8).remainder().is_empty() {
+            return Err(invalid(format!("ACL of {} bytes", value.len())));
+        }
+        let version = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+        if version != VERSION {
+            return Err(invalid(format!("ACL version {}", version)));
+        }
+
+        value[4..]
+            .chunks_exact(8)
+            .map(|entry| {
+                let tag = u16::from_le_bytes([entry[0], entry[1]]);
+                let perm = u16::from_le_bytes([entry[2], entry[3]]);
+                let id = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+
+                let (_, name, qualified) = TAGS
+                    .iter()
+                    .find(|(t, _, _)| *t == tag)
+                    .ok_or_else(|| invalid(format!("ACL tag {:#x}", tag)))?;
+                let qualifier = if *qualified {
+                    id.to_string()
+                } else {
+                    String::new()
+                };
+
+                Ok(format!("{}:{}:{}", name, qualifier, perms_text(perm)))
+            })
+            .collect()
+    }
+
+    pub fn encode(entries: &[String]) -> io::Result<Vec<u8>> {
+        let mut value = VERSION.to_le_bytes().to_vec();
+
+        for entry in entries {
+            let mut parts = entry.splitn(3, ':');
+            let (Some(name), Some(qualifier), Some(perms)) =
+                (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(invalid(format!("ACL entry {:?}", entry)));
+            };
+
+            let qualified = !qualifier.is_empty();
+            let (tag, _, _) = TAGS
+                .iter()
+                .find(|(_, n, q)| *n == name && *q == qualified)
+                .ok_or_else(|| invalid(format!("ACL entry {:?}", entry)))?;
+            let id = if qualified {
+                qualifier
+                    .parse()
+                    .map_err(|_| invalid(format!("ACL qualifier {:?}", qualifier)))?
+            } else {
+                UNDEFINED_ID
+            };
+
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&perms_bits(perms)?.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+
+        Ok(value)
+    }
+
+    fn perms_text(perm: u16) -> String {
+        [(4, 'r'), (2, 'w'), (1, 'x')]
+            .iter()
+            .map(|(bit, c)| if perm & bit != 0 { *c } else { '-' })
+            .collect()
+    }
+
+    fn perms_bits(text: &str) -> io::Result<u16> {
+        let bits: Vec<bool> = text.chars().map(|c| c != '-').collect();
+        if bits.len() != 3 {
+            return Err(invalid(format!("ACL permissions {:?}", text)));
+        }
+        Ok((bits[0] as u16) << 2 | (bits[1] as u16) << 1 | bits[2] as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_acl_text_roundtrip() {
+        let entries: Vec<String> = [
+            "user::rw-",
+            "user:1000:r-x",
+            "group::r--",
+            "mask::r-x",
+            "other::---",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+        let binary = acl::encode(&entries).unwrap();
+        assert_eq!(binary.len(), 4 + 8 * entries.len());
+        assert_eq!(acl::decode(&binary).unwrap(), entries);
+
+        assert!(acl::encode(&["bogus::rwx".to_string()]).is_err());
+        assert!(acl::decode(&binary[..7]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_capture_and_apply() {
+        use backupforge_common::types::ExtendedAttribute;
+        use chrono::{TimeZone, Utc};
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source");
+        std::fs::write(&source, b"data").unwrap();
+        std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        let metadata = std::fs::symlink_metadata(&source).unwrap();
+        let mut unix = capture(&source, &metadata).unwrap();
+        assert_eq!(unix.mode & 0o7777, 0o640);
+        assert_eq!(unix.uid, uzers::get_effective_uid());
+
+        // Not every filesystem used for tests supports user xattrs
+        let xattrs = xattr::set(&source, "user.backupforge", b"value").is_ok();
+        if xattrs {
+            unix = capture(&source, &metadata).unwrap();
+            assert!(unix.xattrs.contains(&ExtendedAttribute {
+                name: "user.backupforge".to_string(),
+                value: b"value".to_vec(),
+            }));
+        }
+
+        let modified = Utc.timestamp_opt(1_600_000_000, 123_456_789).unwrap();
+        unix.accessed = Utc.timestamp_opt(1_500_000_000, 5).unwrap();
+        let file = FileMetadata {
+            path: source.to_string_lossy().to_string(),
+            size: 4,
+            modified,
+            permissions: 0o640,
+            is_directory: false,
+            chunk_ids: Vec::new(),
+            kind: NodeKind::File,
+            holes: Vec::new(),
+            inode: None,
+            ctime: None,
+            unix: Some(unix.clone()),
+        };
+
+        let target = temp_dir.path().join("target");
+        std::fs::write(&target, b"data").unwrap();
+        apply(&target, &file, &RestoreOptions::default()).unwrap();
+
+        let restored = std::fs::symlink_metadata(&target).unwrap();
+        let restored_unix = capture(&target, &restored).unwrap();
+        assert_eq!(restored_unix.mode, unix.mode);
+        assert_eq!(restored_unix.accessed, unix.accessed);
+        assert_eq!(
+            chrono::DateTime::<Utc>::from(restored.modified().unwrap()),
+            modified
+        );
+        if xattrs {
+            assert_eq!(restored_unix.xattrs, unix.xattrs);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use backupforge_common::{Error, Result};
+use std::collections::VecDeque;
+use tokio::task::JoinHandle;
+
+/// Spawned tasks whose results are collected in submission order
+///
+/// Dropping the queue aborts every task still running, so an error part way
+/// through a backup does not leave work going on in the background.
+pub struct OrderedTasks<T> {
+    tasks: VecDeque<JoinHandle<Result<T>>>,
+}
+
+impl<T> OrderedTasks<T> {
+    pub fn new() -> Self {
+        Self {
+            tasks: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    pub fn push(&mut self, task: JoinHandle<Result<T>>) {
+        self.tasks.push_back(task);
+    }
+
+    /// Wait for the oldest task; `None` once the queue is empty
+    pub async fn next(&mut self) -> Option<Result<T>> {
+        let task = self.tasks.pop_front()?;
+
+        Some(match task.await {
+            Ok(result) => result,
+            Err(e) => Err(Error::Unknown(format!("Backup task failed: {}", e))),
+        })
+    }
+}
+
+impl<T> Default for OrderedTasks<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OrderedTasks<T> {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_results_in_submission_order() {
+        let mut tasks = OrderedTasks::new();
+        for i in 0..5u64 {
+            // Later tasks finish first
+            tasks.push(tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50 - i * 10)).await;
+                Ok(i)
+            }));
+        }
+
+        let mut results = Vec::new();
+        while let Some(result) = tasks.next().await {
+            results.push(result.unwrap());
+        }
+        assert_eq!(results, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_drop_aborts_pending_tasks() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<()>(1);
+        let mut tasks = OrderedTasks::new();
+        tasks.push(tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            let _ = tx.send(()).await;
+            Ok(())
+        }));
+
+        drop(tasks);
+        // The sender is dropped with the aborted task, closing the channel
+        assert!(rx.recv().await.is_none());
+    }
+}
@@ -0,0 +1,309 @@
+use backupforge_common::{
+    types::{ChunkId, FileMetadata, NodeKind, SnapshotId},
+    Error, Result,
+};
+use backupforge_storage::RebuildStats;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::{
+    check::{CheckOptions, ProblemKind, ReadData},
+    engine::BackupEngine,
+    manifest::{SnapshotManifest, SNAPSHOTS_PREFIX},
+};
+
+/// Tag added to snapshots whose manifests a repair rewrote
+pub const REPAIRED_TAG: &str = "repaired";
+
+#[derive(Debug, Clone, Default)]
+pub struct RepairOptions {
+    /// Read back every referenced chunk, so damaged chunks are found as well
+    /// as missing ones
+    pub read_data: bool,
+    /// Upload lost chunks again from source files unchanged since the backup
+    pub reupload: bool,
+    /// Only report what would be repaired
+    pub dry_run: bool,
+}
+
+/// A file whose chunks were lost
+#[derive(Debug, Clone, Serialize)]
+pub struct DamagedFile {
+    pub snapshot: SnapshotId,
+    pub path: String,
+    /// Missing or damaged chunks of the file; 0 for a hard link to a
+    /// dropped file
+    pub lost_chunks: usize,
+    /// Whether the lost chunks were uploaded again from the source, so the
+    /// file stays in the snapshot
+    pub recovered: bool,
+}
+
+/// Outcome of a repair
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RepairReport {
+    pub index: RebuildStats,
+    pub missing_chunks: usize,
+    pub damaged_chunks: usize,
+    /// Manifests that could not be decoded and were removed, by metadata key
+    pub removed_manifests: Vec<String>,
+    /// Snapshots whose manifests were rewritten
+    pub repaired_snapshots: usize,
+    pub files: Vec<DamagedFile>,
+}
+
+impl RepairReport {
+    /// Files dropped from their snapshots
+    pub fn dropped_files(&self) -> impl Iterator<Item = &DamagedFile> {
+        self.files.iter().filter(|f| !f.recovered)
+    }
+}
+
+/// Rebuild the storage index, find lost chunks, then rewrite every manifest
+/// referencing them: files whose chunks can be uploaded again stay, the
+/// others are dropped. The dedup index is rebuilt at the end.
+///
+/// Manifests that cannot be decoded are removed, since nothing in them can
+/// be restored and they would stop every prune. Temporary files abandoned by
+/// interrupted writes are removed first.
+pub(crate) async fn run(engine: &BackupEngine, options: &RepairOptions) -> Result<RepairReport> {
+    let storage = engine.storage();
+    let dry_run = options.dry_run;
+    let mut report = RepairReport::default();
+
+    storage.flush().await?;
+    if !dry_run {
+        storage.remove_stale_temp_files().await?;
+    }
+    report.index = storage.rebuild_index(dry_run).await?;
+
+    let check = engine
+        .check(&CheckOptions {
+            read_data: if options.read_data {
+                ReadData::All
+            } else {
+                ReadData::None
+            },
+            snapshots: Vec::new(),
+        })
+        .await?;
+    let mut lost = HashSet::new();
+    let mut damaged = Vec::new();
+    for problem in check.problems {
+        let chunk_id = ChunkId(problem.subject);
+        match problem.kind {
+            ProblemKind::MissingChunk => report.missing_chunks += 1,
+            ProblemKind::DamagedChunk => {
+                report.damaged_chunks += 1;
+                damaged.push(chunk_id.clone());
+            }
+            _ => continue,
+        }
+        lost.insert(chunk_id);
+    }
+
+    let mut manifests = Vec::new();
+    for key in storage.list_metadata(SNAPSHOTS_PREFIX).await? {
+        let data = storage.get_metadata(&key).await?;
+        match serde_json::from_slice::<SnapshotManifest>(&data) {
+            Ok(manifest) => manifests.push(manifest),
+            Err(e) => {
+                tracing::warn!("Removing unreadable manifest {}: {}", key, e);
+                if !dry_run {
+                    storage.delete_metadata(&key).await?;
+                }
+                report.removed_manifests.push(key);
+            }
+        }
+    }
+
+    if lost.is_empty() {
+        if !dry_run && !report.removed_manifests.is_empty() {
+            engine.dedup_store().rebuild().await?;
+        }
+        return Ok(report);
+    }
+
+    if !dry_run {
+        // Damaged copies must go so they can be stored again, and the dedup
+        // index must forget lost chunks so uploads are not skipped
+        for chunk_id in &damaged {
+            storage.delete_chunk(chunk_id).await?;
+        }
+        engine.dedup_store().rebuild().await?;
+    }
+
+    for mut manifest in manifests {
+        let id = manifest.snapshot.id.clone();
+        let mut dropped = HashSet::new();
+        let mut touched = false;
+
+        for file in &manifest.files {
+            let lost_chunks = file.chunk_ids.iter().filter(|c| lost.contains(*c)).count();
+            if lost_chunks == 0 {
+                continue;
+            }
+            touched = true;
+
+            let recovered = options.reupload && reupload(engine, file, dry_run).await?;
+            if !recovered {
+                dropped.insert(file.path.clone());
+            }
+            report.files.push(DamagedFile {
+                snapshot: id.clone(),
+                path: file.path.clone(),
+                lost_chunks,
+                recovered,
+            });
+        }
+        if !touched {
+            continue;
+        }
+
+        // Hard links restore by linking to their target, which is gone
+        for file in &manifest.files {
+            if let NodeKind::HardLink { target } = &file.kind {
+                if dropped.contains(target) {
+                    report.files.push(DamagedFile {
+                        snapshot: id.clone(),
+                        path: file.path.clone(),
+                        lost_chunks: 0,
+                        recovered: false,
+                    });
+                    dropped.insert(file.path.clone());
+                }
+            }
+        }
+
+        manifest.files.retain(|f| !dropped.contains(&f.path));
+        let snapshot = &mut manifest.snapshot;
+        snapshot.file_count = manifest.files.len() as u64;
+        snapshot.total_size = manifest.files.iter().map(|f| f.size).sum();
+        snapshot.chunk_ids = manifest
+            .files
+            .iter()
+            .flat_map(|f| f.chunk_ids.clone())
+            .collect();
+        if !snapshot.tags.iter().any(|t| t == REPAIRED_TAG) {
+            snapshot.tags.push(REPAIRED_TAG.to_string());
+        }
+
+        if !dry_run {
+            engine.manifests().save(&manifest).await?;
+            tracing::info!(
+                "Repaired snapshot {}, dropping {} files",
+                id.0,
+                dropped.len()
+            );
+        }
+        report.repaired_snapshots += 1;
+    }
+
+    if !dry_run {
+        storage.flush().await?;
+        engine.dedup_store().rebuild().await?;
+    }
+
+    Ok(report)
+}
+
+/// Store the chunks of `file` again if its source is unchanged since the
+/// backup, returning whether it now has every chunk
+///
+/// A dry run only checks that the source is unchanged.
+async fn reupload(engine: &BackupEngine, file: &FileMetadata, dry_run: bool) -> Result<bool> {
+    let path = Path::new(&file.path);
+    match engine.stat(path).await {
+        Ok(current) if file.is_unchanged(&current) => {}
+        // Changed, gone or unreadable
+        Ok(_) | Err(Error::Io(_)) => return Ok(false),
+        Err(e) => return Err(e),
+    }
+    if dry_run {
+        return Ok(true);
+    }
+
+    match engine.backup_file(path).await {
+        // Unchanged contents cut into the same chunks
+        Ok(stored) => Ok(stored.chunk_ids == file.chunk_ids),
+        Err(e) => {
+            tracing::warn!("Could not read {} again: {}", file.path, e);
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::BackupConfig;
+    use backupforge_storage::{LocalStorage, StorageBackend};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_repair_recovers_or_drops_damaged_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage: Arc<dyn StorageBackend> =
+            Arc::new(LocalStorage::new(temp_dir.path().join("repo")).await.unwrap());
+        let engine = BackupEngine::new(BackupConfig::default(), storage.clone());
+
+        let mut files = Vec::new();
+        for (name, content) in [("a.txt", "recoverable"), ("b.txt", "lost"), ("c.txt", "intact")] {
+            let path = temp_dir.path().join(name);
+            tokio::fs::write(&path, content).await.unwrap();
+            files.push(engine.backup_file(&path).await.unwrap());
+        }
+        let snapshot = engine
+            .create_snapshot("test".to_string(), "/src".to_string(), files.clone(), None)
+            .await
+            .unwrap();
+
+        // Lose the chunks of a.txt and b.txt; only a.txt still has its source
+        storage.delete_chunk(&files[0].chunk_ids[0]).await.unwrap();
+        storage.delete_chunk(&files[1].chunk_ids[0]).await.unwrap();
+        tokio::fs::remove_file(&files[1].path).await.unwrap();
+        let garbage = format!("{}{}", SNAPSHOTS_PREFIX, SnapshotId::new().0);
+        storage.put_metadata(&garbage, b"{".to_vec()).await.unwrap();
+
+        let mut options = RepairOptions {
+            reupload: true,
+            dry_run: true,
+            ..RepairOptions::default()
+        };
+        let report = engine.repair(&options).await.unwrap();
+        assert_eq!(report.missing_chunks, 2);
+        assert_eq!(report.removed_manifests, [garbage.as_str()]);
+        let outcome: Vec<(&str, bool)> = report
+            .files
+            .iter()
+            .map(|f| (f.path.as_str(), f.recovered))
+            .collect();
+        assert_eq!(
+            outcome,
+            [(files[0].path.as_str(), true), (files[1].path.as_str(), false)]
+        );
+        // A dry run changes nothing
+        assert!(storage.get_metadata(&garbage).await.is_ok());
+        assert!(!storage.chunk_exists(&files[0].chunk_ids[0]).await.unwrap());
+        assert_eq!(engine.load_snapshot(&snapshot.id).await.unwrap().files.len(), 3);
+
+        options.dry_run = false;
+        let report = engine.repair(&options).await.unwrap();
+        assert_eq!(report.repaired_snapshots, 1);
+        assert_eq!(report.dropped_files().count(), 1);
+        assert!(storage.get_metadata(&garbage).await.is_err());
+        assert!(storage.chunk_exists(&files[0].chunk_ids[0]).await.unwrap());
+
+        let manifest = engine.load_snapshot(&snapshot.id).await.unwrap();
+        let paths: Vec<&str> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, [files[0].path.as_str(), files[2].path.as_str()]);
+        assert_eq!(manifest.snapshot.file_count, 2);
+        assert_eq!(manifest.snapshot.tags, [REPAIRED_TAG]);
+
+        let report = engine.check(&CheckOptions::default()).await.unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(engine.dedup_store().index().len(), 2);
+    }
+}
@@ -0,0 +1,524 @@
+use backupforge_common::{Error, Result};
+use backupforge_storage::{packed::DEFAULT_PACK_SIZE, PackedStorage, StorageBackend};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    chunker::{ChunkIdHash, ChunkingStrategy},
+    compression::CompressionAlgorithm,
+    encryption::{EncryptionKey, WrappedKey},
+    engine::BackupConfig,
+};
+
+/// Metadata key of the repository config
+pub const CONFIG_KEY: &str = "config";
+
+/// Repository format version written by this release
+pub const REPOSITORY_VERSION: u32 = 1;
+
+/// Repository-wide settings, written once by `init`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RepositoryConfig {
+    pub version: u32,
+    pub id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub chunking: ChunkingStrategy,
+    pub compression: CompressionAlgorithm,
+    /// Repositories created before keyed IDs existed have no field and use plain BLAKE3
+    #[serde(default)]
+    pub chunk_id_hash: ChunkIdHash,
+    /// Target size of pack files, 0 to store every chunk as its own object
+    #[serde(default = "default_pack_size")]
+    pub pack_size: u64,
+    /// Key slots unlocking the master key, if encrypted
+    pub encryption: Option<EncryptionConfig>,
+}
+
+impl RepositoryConfig {
+    pub fn new(
+        chunking: ChunkingStrategy,
+        compression: CompressionAlgorithm,
+        encryption: Option<WrappedKey>,
+    ) -> Self {
+        Self {
+            version: REPOSITORY_VERSION,
+            id: Uuid::new_v4(),
+            created_at: Utc::now(),
+            chunking,
+            compression,
+            // Unkeyed IDs of encrypted chunks would reveal which known files are stored
+            chunk_id_hash: if encryption.is_some() {
+                ChunkIdHash::Blake3Keyed
+            } else {
+                ChunkIdHash::Blake3
+            },
+            pack_size: DEFAULT_PACK_SIZE,
+            encryption: encryption.map(|key| EncryptionConfig {
+                key_slots: vec![KeySlot::new("initial", key)],
+            }),
+        }
+    }
+}
+
+fn default_pack_size() -> u64 {
+    DEFAULT_PACK_SIZE
+}
+
+/// Encryption settings of a repository
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    /// Every slot wraps the same master key, so chunks never need re-encrypting
+    pub key_slots: Vec<KeySlot>,
+}
+
+/// One passphrase or key file able to unlock the master key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeySlot {
+    pub id: String,
+    pub label: String,
+    pub created_at: DateTime<Utc>,
+    pub key: WrappedKey,
+}
+
+impl KeySlot {
+    pub fn new(label: &str, key: WrappedKey) -> Self {
+        Self {
+            id: Uuid::new_v4().simple().to_string()[..8].to_string(),
+            label: label.to_string(),
+            created_at: Utc::now(),
+            key,
+        }
+    }
+
+    /// "passphrase" or "keyfile"
+    pub fn kind(&self) -> &'static str {
+        if self.key.is_passphrase() {
+            "passphrase"
+        } else {
+            "keyfile"
+        }
+    }
+}
+
+/// A secret presented to unlock or protect a key slot
+pub enum Credential {
+    Passphrase(String),
+    /// Raw contents of a key file
+    KeyFile(EncryptionKey),
+}
+
+impl Credential {
+    /// Wrap `master` so this credential can unlock it
+    pub fn wrap(&self, master: &EncryptionKey) -> Result<WrappedKey> {
+        match self {
+            Credential::Passphrase(passphrase) => master.wrap(passphrase),
+            Credential::KeyFile(key) => master.wrap_with_key(key),
+        }
+    }
+
+    fn unwrap(&self, key: &WrappedKey) -> Result<EncryptionKey> {
+        match self {
+            Credential::Passphrase(passphrase) => key.unwrap_key(passphrase),
+            Credential::KeyFile(kek) => key.unwrap_with_key(kek),
+        }
+    }
+
+    fn matches_kind(&self, slot: &KeySlot) -> bool {
+        matches!(self, Credential::Passphrase(_)) == slot.key.is_passphrase()
+    }
+}
+
+/// An initialized backup repository on a storage backend
+#[derive(Clone)]
+pub struct Repository {
+    storage: Arc<dyn StorageBackend>,
+    config: RepositoryConfig,
+}
+
+impl Repository {
+    /// Write the config of a new repository; fails if one already exists
+    pub async fn init(storage: Arc<dyn StorageBackend>, config: RepositoryConfig) -> Result<Self> {
+        match storage.get_metadata(CONFIG_KEY).await {
+            Ok(_) => {
+                return Err(Error::InvalidConfig(
+                    "Repository is already initialized".to_string(),
+                ))
+            }
+            Err(Error::MetadataNotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+        config.chunking.validate()?;
+
+        let storage = Arc::new(PackedStorage::open(storage, config.pack_size).await?);
+        let repository = Self { storage, config };
+        repository.save_config().await?;
+
+        Ok(repository)
+    }
+
+    /// Open an existing repository, checking its format version
+    pub async fn open(storage: Arc<dyn StorageBackend>) -> Result<Self> {
+        let data = match storage.get_metadata(CONFIG_KEY).await {
+            Ok(data) => data,
+            Err(Error::MetadataNotFound(_)) => {
+                return Err(Error::RepositoryNotInitialized(
+                    "no config found, run `backupforge init` first".to_string(),
+                ))
+            }
+            Err(e) => return Err(e),
+        };
+
+        let config: RepositoryConfig = serde_json::from_slice(&data)
+            .map_err(|e| Error::InvalidConfig(format!("Unreadable repository config: {}", e)))?;
+
+        if config.version != REPOSITORY_VERSION {
+            return Err(Error::InvalidConfig(format!(
+                "Repository format version {} is not supported (expected {})",
+                config.version, REPOSITORY_VERSION
+            )));
+        }
+        config.chunking.validate()?;
+
+        let storage = Arc::new(PackedStorage::open(storage, config.pack_size).await?);
+        Ok(Self { storage, config })
+    }
+
+    pub fn config(&self) -> &RepositoryConfig {
+        &self.config
+    }
+
+    /// Storage that packs chunks per the repository config
+    pub fn storage(&self) -> Arc<dyn StorageBackend> {
+        self.storage.clone()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.config.encryption.is_some()
+    }
+
+    /// Key slots of an encrypted repository, empty otherwise
+    pub fn key_slots(&self) -> &[KeySlot] {
+        self.config
+            .encryption
+            .as_ref()
+            .map(|e| e.key_slots.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Look up a key slot by full ID or unique prefix
+    pub fn key_slot(&self, id: &str) -> Result<&KeySlot> {
+        self.slot_index(id).map(|index| &self.key_slots()[index])
+    }
+
+    /// Unwrap the master key of an encrypted repository
+    pub fn unlock(&self, credential: &Credential) -> Result<EncryptionKey> {
+        self.unlock_slot(credential).map(|(_, key)| key)
+    }
+
+    /// Unwrap the master key, also returning the slot that opened it
+    pub fn unlock_slot(&self, credential: &Credential) -> Result<(&KeySlot, EncryptionKey)> {
+        if !self.is_encrypted() {
+            return Err(Error::InvalidConfig("Repository is not encrypted".to_string()));
+        }
+
+        for slot in self.key_slots() {
+            if !credential.matches_kind(slot) {
+                continue;
+            }
+            if let Ok(key) = credential.unwrap(&slot.key) {
+                return Ok((slot, key));
+            }
+        }
+
+        Err(Error::AuthenticationFailed(
+            "No key slot matches the given passphrase or key file".to_string(),
+        ))
+    }
+
+    /// Add a slot letting `credential` unlock the master key
+    pub async fn add_key(
+        &mut self,
+        master: &EncryptionKey,
+        credential: &Credential,
+        label: &str,
+    ) -> Result<KeySlot> {
+        let slot = KeySlot::new(label, credential.wrap(master)?);
+        self.encryption_mut()?.key_slots.push(slot.clone());
+        self.save_config().await?;
+
+        Ok(slot)
+    }
+
+    /// Re-wrap the master key of an existing slot under a new credential
+    pub async fn change_key(
+        &mut self,
+        id: &str,
+        master: &EncryptionKey,
+        credential: &Credential,
+    ) -> Result<()> {
+        let index = self.slot_index(id)?;
+        let key = credential.wrap(master)?;
+        self.encryption_mut()?.key_slots[index].key = key;
+        self.save_config().await
+    }
+
+    /// Remove a key slot; the last one can never be removed
+    pub async fn remove_key(&mut self, id: &str) -> Result<KeySlot> {
+        let index = self.slot_index(id)?;
+        let encryption = self.encryption_mut()?;
+        if encryption.key_slots.len() == 1 {
+            return Err(Error::InvalidConfig(
+                "Refusing to remove the last key slot".to_string(),
+            ));
+        }
+
+        let slot = encryption.key_slots.remove(index);
+        self.save_config().await?;
+
+        Ok(slot)
+    }
+
+    /// Resolve a full slot ID or a unique prefix
+    fn slot_index(&self, id: &str) -> Result<usize> {
+        let mut matches = self
+            .key_slots()
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| !id.is_empty() && slot.id.starts_with(id));
+
+        match (matches.next(), matches.next()) {
+            (Some((index, _)), None) => Ok(index),
+            (Some(_), Some(_)) => Err(Error::InvalidConfig(format!(
+                "Key slot {} is ambiguous",
+                id
+            ))),
+            (None, _) => Err(Error::InvalidConfig(format!("No key slot {}", id))),
+        }
+    }
+
+    fn encryption_mut(&mut self) -> Result<&mut EncryptionConfig> {
+        self.config
+            .encryption
+            .as_mut()
+            .ok_or_else(|| Error::InvalidConfig("Repository is not encrypted".to_string()))
+    }
+
+    async fn save_config(&self) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.config)
+            .map_err(|e| Error::Serialization(format!("Failed to encode config: {}", e)))?;
+        self.storage.put_metadata(CONFIG_KEY, data).await
+    }
+
+    /// Re-read the stored config and describe what is wrong with it
+    pub async fn check_config(&self) -> Vec<String> {
+        let data = match self.storage.get_metadata(CONFIG_KEY).await {
+            Ok(data) => data,
+            Err(e) => return vec![e.to_string()],
+        };
+        let stored: RepositoryConfig = match serde_json::from_slice(&data) {
+            Ok(config) => config,
+            Err(e) => return vec![format!("Unreadable repository config: {}", e)],
+        };
+
+        let mut problems = Vec::new();
+        if stored.version != REPOSITORY_VERSION {
+            problems.push(format!("Unsupported format version {}", stored.version));
+        }
+        if stored.id != self.config.id {
+            problems.push(format!(
+                "Repository ID changed from {} to {} while open",
+                self.config.id, stored.id
+            ));
+        }
+        if let Some(encryption) = &stored.encryption {
+            if encryption.key_slots.is_empty() {
+                problems.push("Encrypted repository has no key slots".to_string());
+            }
+            let mut ids = std::collections::HashSet::new();
+            for slot in &encryption.key_slots {
+                if !ids.insert(&slot.id) {
+                    problems.push(format!("Key slot ID {} is used twice", slot.id));
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Key for chunk IDs in this repository, if they are keyed
+    pub fn chunk_id_key(&self, encryption_key: Option<&EncryptionKey>) -> Option<[u8; 32]> {
+        match self.config.chunk_id_hash {
+            ChunkIdHash::Blake3 => None,
+            ChunkIdHash::Blake3Keyed => encryption_key.map(EncryptionKey::chunk_id_key),
+        }
+    }
+
+    /// Engine settings matching this repository
+    pub fn backup_config(&self, encryption_key: Option<EncryptionKey>) -> BackupConfig {
+        BackupConfig {
+            chunking_strategy: self.config.chunking.clone(),
+            compression: self.config.compression,
+            chunk_id_key: self.chunk_id_key(encryption_key.as_ref()),
+            encryption_key,
+            ..BackupConfig::default()
+        }
+    }
+
+    /// Check that `config` is usable with this repository
+    pub fn validate(&self, config: &BackupConfig) -> Result<()> {
+        match (self.is_encrypted(), config.encryption_key.is_some()) {
+            (true, false) => Err(Error::AuthenticationFailed(
+                "Repository is encrypted, a key is required".to_string(),
+            )),
+            (false, true) => Err(Error::InvalidConfig(
+                "Repository is not encrypted but an encryption key was given".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backupforge_storage::LocalStorage;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_init_and_open() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage: Arc<dyn StorageBackend> =
+            Arc::new(LocalStorage::new(temp_dir.path()).await.unwrap());
+
+        let result = Repository::open(storage.clone()).await;
+        assert!(matches!(result, Err(Error::RepositoryNotInitialized(_))));
+
+        let key = EncryptionKey::generate();
+        let config = RepositoryConfig::new(
+            ChunkingStrategy::default(),
+            CompressionAlgorithm::default(),
+            Some(key.wrap("passphrase").unwrap()),
+        );
+        let repo = Repository::init(storage.clone(), config).await.unwrap();
+
+        let again = RepositoryConfig::new(
+            ChunkingStrategy::default(),
+            CompressionAlgorithm::default(),
+            None,
+        );
+        assert!(Repository::init(storage.clone(), again).await.is_err());
+
+        let opened = Repository::open(storage).await.unwrap();
+        assert_eq!(opened.config().id, repo.config().id);
+        assert_eq!(opened.config().chunking, ChunkingStrategy::default());
+
+        let unlocked = opened.unlock(&passphrase("passphrase")).unwrap();
+        assert_eq!(unlocked.as_bytes(), key.as_bytes());
+        assert!(opened.unlock(&passphrase("wrong")).is_err());
+
+        assert!(opened.validate(&opened.backup_config(None)).is_err());
+        let backup_config = opened.backup_config(Some(unlocked));
+        assert!(opened.validate(&backup_config).is_ok());
+        assert_eq!(opened.config().chunk_id_hash, ChunkIdHash::Blake3Keyed);
+        assert_eq!(backup_config.chunk_id_key, Some(key.chunk_id_key()));
+    }
+
+    fn passphrase(s: &str) -> Credential {
+        Credential::Passphrase(s.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_key_slots() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage: Arc<dyn StorageBackend> =
+            Arc::new(LocalStorage::new(temp_dir.path()).await.unwrap());
+
+        let master = EncryptionKey::generate();
+        let config = RepositoryConfig::new(
+            ChunkingStrategy::default(),
+            CompressionAlgorithm::default(),
+            Some(master.wrap("first").unwrap()),
+        );
+        let mut repo = Repository::init(storage.clone(), config).await.unwrap();
+        let first = repo.key_slots()[0].id.clone();
+
+        let key_file = Credential::KeyFile(EncryptionKey::generate());
+        let added = repo.add_key(&master, &key_file, "escrow").await.unwrap();
+        assert_eq!(added.kind(), "keyfile");
+        repo.add_key(&master, &passphrase("second"), "ops").await.unwrap();
+
+        // Every slot opens the same master key, including after a reopen
+        let mut repo = Repository::open(storage.clone()).await.unwrap();
+        assert_eq!(repo.key_slots().len(), 3);
+        for credential in [passphrase("first"), passphrase("second"), key_file] {
+            assert_eq!(repo.unlock(&credential).unwrap().as_bytes(), master.as_bytes());
+        }
+
+        repo.change_key(&first, &master, &passphrase("renamed"))
+            .await
+            .unwrap();
+        assert!(repo.unlock(&passphrase("first")).is_err());
+        let (slot, _) = repo.unlock_slot(&passphrase("renamed")).unwrap();
+        assert_eq!(slot.id, first);
+
+        repo.remove_key(&added.id).await.unwrap();
+        repo.remove_key(&first[..4]).await.unwrap();
+        assert!(repo.remove_key("").await.is_err());
+        let last = repo.key_slots()[0].id.clone();
+        assert!(matches!(
+            repo.remove_key(&last).await,
+            Err(Error::InvalidConfig(_))
+        ));
+
+        let repo = Repository::open(storage).await.unwrap();
+        assert_eq!(repo.key_slots().len(), 1);
+        assert!(repo.unlock(&passphrase("second")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_open_rejects_unknown_version() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage: Arc<dyn StorageBackend> =
+            Arc::new(LocalStorage::new(temp_dir.path()).await.unwrap());
+
+        let mut config = RepositoryConfig::new(
+            ChunkingStrategy::default(),
+            CompressionAlgorithm::default(),
+            None,
+        );
+        config.version = REPOSITORY_VERSION + 1;
+        storage
+            .put_metadata(CONFIG_KEY, serde_json::to_vec(&config).unwrap())
+            .await
+            .unwrap();
+
+        let result = Repository::open(storage).await;
+        assert!(matches!(result, Err(Error::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn test_open_rejects_invalid_chunking() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage: Arc<dyn StorageBackend> =
+            Arc::new(LocalStorage::new(temp_dir.path()).await.unwrap());
+
+        let config = RepositoryConfig::new(
+            ChunkingStrategy::ContentDefined {
+                min_size: 4096,
+                avg_size: 2048,
+                max_size: 1024,
+            },
+            CompressionAlgorithm::default(),
+            None,
+        );
+        storage
+            .put_metadata(CONFIG_KEY, serde_json::to_vec(&config).unwrap())
+            .await
+            .unwrap();
+
+        let result = Repository::open(storage).await;
+        assert!(matches!(result, Err(Error::InvalidConfig(_))));
+    }
+}
@@ -0,0 +1,232 @@
+use backupforge_common::{
+    types::{RetentionPolicy, Snapshot},
+    Error, Result,
+};
+use chrono::{DateTime, Datelike, Duration, Utc};
+
+/// What a retention policy decided for one snapshot
+#[derive(Debug, Clone)]
+pub struct RetentionDecision {
+    pub snapshot: Snapshot,
+    /// Rules that keep the snapshot; empty when it is to be removed
+    pub reasons: Vec<String>,
+}
+
+impl RetentionDecision {
+    pub fn keep(&self) -> bool {
+        !self.reasons.is_empty()
+    }
+}
+
+/// A periodic rule: keeps the newest snapshot of each of `remaining` periods
+struct Period {
+    name: &'static str,
+    remaining: u32,
+    last: Option<i64>,
+    key: fn(&DateTime<Utc>) -> i64,
+}
+
+/// Decide which of one source's `snapshots` `policy` keeps, newest first
+///
+/// An empty policy is refused, since it would remove every snapshot.
+pub fn apply_policy(
+    policy: &RetentionPolicy,
+    mut snapshots: Vec<Snapshot>,
+) -> Result<Vec<RetentionDecision>> {
+    if policy.is_empty() {
+        return Err(Error::InvalidConfig(
+            "Retention policy has no rules, refusing to remove every snapshot".to_string(),
+        ));
+    }
+
+    snapshots.sort_by_key(|s| std::cmp::Reverse(s.created_at));
+    let within = policy.keep_within_hours.and_then(|hours| {
+        let newest = snapshots.first()?.created_at;
+        // A window reaching past the earliest representable time keeps all
+        Some(
+            i64::try_from(hours)
+                .ok()
+                .and_then(Duration::try_hours)
+                .and_then(|window| newest.checked_sub_signed(window))
+                .unwrap_or(DateTime::<Utc>::MIN_UTC),
+        )
+    });
+
+    let mut periods: Vec<Period> = [
+        (policy.keep_hourly, "hourly", hour_key as fn(&DateTime<Utc>) -> i64),
+        (policy.keep_daily, "daily", day_key),
+        (policy.keep_weekly, "weekly", week_key),
+        (policy.keep_monthly, "monthly", month_key),
+        (policy.keep_yearly, "yearly", year_key),
+    ]
+    .into_iter()
+    .filter_map(|(count, name, key)| {
+        count.map(|remaining| Period {
+            name,
+            remaining,
+            last: None,
+            key,
+        })
+    })
+    .collect();
+
+    let decisions = snapshots
+        .into_iter()
+        .enumerate()
+        .map(|(i, snapshot)| {
+            let mut reasons = Vec::new();
+
+            if let Some(last) = policy.keep_last {
+                if (i as u64) < u64::from(last) {
+                    reasons.push(format!("last {}", last));
+                }
+            }
+
+            for period in &mut periods {
+                let key = (period.key)(&snapshot.created_at);
+                if period.remaining > 0 && period.last != Some(key) {
+                    period.remaining -= 1;
+                    period.last = Some(key);
+                    reasons.push(period.name.to_string());
+                }
+            }
+
+            if let (Some(since), Some(hours)) = (within, policy.keep_within_hours) {
+                if snapshot.created_at >= since {
+                    reasons.push(format!("within {}h", hours));
+                }
+            }
+
+            for tag in &policy.keep_tags {
+                if snapshot.tags.contains(tag) {
+                    reasons.push(format!("tagged {}", tag));
+                }
+            }
+
+            RetentionDecision { snapshot, reasons }
+        })
+        .collect();
+
+    Ok(decisions)
+}
+
+fn hour_key(time: &DateTime<Utc>) -> i64 {
+    time.timestamp().div_euclid(3600)
+}
+
+fn day_key(time: &DateTime<Utc>) -> i64 {
+    i64::from(time.num_days_from_ce())
+}
+
+fn week_key(time: &DateTime<Utc>) -> i64 {
+    let week = time.iso_week();
+    i64::from(week.year()) * 100 + i64::from(week.week())
+}
+
+fn month_key(time: &DateTime<Utc>) -> i64 {
+    i64::from(time.year()) * 12 + i64::from(time.month0())
+}
+
+fn year_key(time: &DateTime<Utc>) -> i64 {
+    i64::from(time.year())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backupforge_common::types::SnapshotId;
+    use chrono::TimeZone;
+
+    fn snapshot(created_at: DateTime<Utc>, tags: &[&str]) -> Snapshot {
+        Snapshot {
+            id: SnapshotId::new(),
+            name: created_at.to_rfc3339(),
+            created_at,
+            source_path: "/data".to_string(),
+            total_size: 0,
+            compressed_size: 0,
+            file_count: 0,
+            chunk_ids: Vec::new(),
+            parent_snapshot: None,
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            stats: None,
+        }
+    }
+
+    /// Names of the kept snapshots, newest first
+    fn kept(policy: &RetentionPolicy, snapshots: &[Snapshot]) -> Vec<String> {
+        apply_policy(policy, snapshots.to_vec())
+            .unwrap()
+            .into_iter()
+            .filter(RetentionDecision::keep)
+            .map(|d| d.snapshot.created_at.format("%Y-%m-%d %H:%M").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_grandfather_father_son() {
+        // Two snapshots a day at 01:00 and 13:00 for 60 days from 2024-01-01
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 1, 0, 0).unwrap();
+        let snapshots: Vec<Snapshot> = (0..120)
+            .map(|i| snapshot(start + Duration::hours(12 * i), &[]))
+            .collect();
+
+        let policy = RetentionPolicy {
+            keep_last: Some(1),
+            keep_daily: Some(3),
+            keep_weekly: Some(2),
+            keep_monthly: Some(2),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(
+            kept(&policy, &snapshots),
+            [
+                // Newest, and the newest of each of the last three days
+                "2024-02-29 13:00",
+                "2024-02-28 13:00",
+                "2024-02-27 13:00",
+                // Newest of the previous week, as the current week's is kept
+                "2024-02-25 13:00",
+                // Newest of January
+                "2024-01-31 13:00",
+            ]
+        );
+
+        let decisions = apply_policy(&policy, snapshots).unwrap();
+        assert_eq!(decisions[0].reasons, ["last 1", "daily", "weekly", "monthly"]);
+        assert_eq!(decisions.iter().filter(|d| d.keep()).count(), 5);
+    }
+
+    #[test]
+    fn test_keep_within_and_tags() {
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap();
+        let snapshots = vec![
+            snapshot(start, &["release"]),
+            snapshot(start + Duration::days(10), &[]),
+            snapshot(start + Duration::days(19), &[]),
+            snapshot(start + Duration::days(20), &[]),
+        ];
+
+        let policy = RetentionPolicy {
+            keep_within_hours: Some(48),
+            keep_tags: vec!["release".to_string()],
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(
+            kept(&policy, &snapshots),
+            ["2024-06-21 00:00", "2024-06-20 00:00", "2024-06-01 00:00"]
+        );
+
+        let policy = RetentionPolicy {
+            keep_within_hours: Some(u64::MAX),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(&policy, &snapshots).len(), 4);
+    }
+
+    #[test]
+    fn test_empty_policy_is_refused() {
+        let snapshots = vec![snapshot(Utc::now(), &[])];
+        assert!(apply_policy(&RetentionPolicy::default(), snapshots).is_err());
+    }
+}
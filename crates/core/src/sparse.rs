@@ -0,0 +1,274 @@
+use backupforge_common::types::FileExtent;
+use std::collections::VecDeque;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt, AsyncWriteExt, ReadBuf};
+
+/// Find the holes of the first `size` bytes of `file` with `SEEK_HOLE`
+///
+/// Files with every block allocated are not probed. Filesystems without hole
+/// support report none, and so does any platform without `SEEK_HOLE`.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub fn find_holes(file: &std::fs::File, size: u64) -> io::Result<Vec<FileExtent>> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    if file.metadata()?.blocks() * 512 >= size {
+        return Ok(Vec::new());
+    }
+
+    let fd = file.as_raw_fd();
+    let seek = |offset: u64, whence| -> io::Result<u64> {
+        // SAFETY: lseek only repositions `fd`, which `file` keeps open
+        let position = unsafe { libc::lseek(fd, offset as libc::off_t, whence) };
+        if position < 0 {
+            let e = io::Error::last_os_error();
+            // No data past `offset`: the rest of the file is a hole
+            if e.raw_os_error() == Some(libc::ENXIO) {
+                return Ok(size);
+            }
+            return Err(e);
+        }
+        Ok((position as u64).min(size))
+    };
+
+    let mut holes = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let hole = match seek(offset, libc::SEEK_HOLE) {
+            Ok(hole) => hole,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        if hole >= size {
+            break;
+        }
+
+        let data = seek(hole, libc::SEEK_DATA)?;
+        holes.push(FileExtent {
+            offset: hole,
+            length: data - hole,
+        });
+        offset = data;
+    }
+
+    Ok(holes)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+pub fn find_holes(_file: &std::fs::File, _size: u64) -> io::Result<Vec<FileExtent>> {
+    Ok(Vec::new())
+}
+
+/// Total length of `holes`
+pub fn hole_bytes(holes: &[FileExtent]) -> u64 {
+    holes.iter().map(|h| h.length).sum()
+}
+
+/// The ranges of the first `size` bytes that lie outside `holes`
+pub fn data_extents(holes: &[FileExtent], size: u64) -> Vec<FileExtent> {
+    let mut extents = Vec::new();
+    let mut offset = 0;
+
+    for hole in holes {
+        if hole.offset > offset {
+            extents.push(FileExtent {
+                offset,
+                length: hole.offset - offset,
+            });
+        }
+        offset = offset.max(hole.offset + hole.length);
+    }
+    if size > offset {
+        extents.push(FileExtent {
+            offset,
+            length: size - offset,
+        });
+    }
+
+    extents
+}
+
+/// Reads only the given extents of a file, back to back
+pub struct ExtentReader {
+    file: File,
+    extents: VecDeque<FileExtent>,
+    remaining: u64,
+    seeking: bool,
+}
+
+impl ExtentReader {
+    pub fn new(file: File, extents: Vec<FileExtent>) -> Self {
+        Self {
+            file,
+            extents: extents.into(),
+            remaining: 0,
+            seeking: false,
+        }
+    }
+}
+
+impl AsyncRead for ExtentReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.seeking {
+                ready!(Pin::new(&mut this.file).poll_complete(cx))?;
+                this.seeking = false;
+            }
+
+            if this.remaining == 0 {
+                let Some(extent) = this.extents.pop_front() else {
+                    return Poll::Ready(Ok(()));
+                };
+                Pin::new(&mut this.file).start_seek(SeekFrom::Start(extent.offset))?;
+                this.remaining = extent.length;
+                this.seeking = true;
+                continue;
+            }
+
+            let limit = buf.remaining().min(this.remaining as usize);
+            let mut limited = buf.take(limit);
+            ready!(Pin::new(&mut this.file).poll_read(cx, &mut limited))?;
+            let read = limited.filled().len();
+
+            // The file shrank since its holes were found; stop at its end
+            if read == 0 {
+                this.remaining = 0;
+                this.extents.clear();
+                return Poll::Ready(Ok(()));
+            }
+
+            // SAFETY: `limited` wrote `read` initialized bytes into `buf`'s
+            // unfilled region
+            unsafe { buf.assume_init(read) };
+            buf.advance(read);
+            this.remaining -= read as u64;
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+/// Writes a file's data back around its holes, which are left unallocated
+pub struct SparseWriter<'a> {
+    file: &'a mut File,
+    holes: std::iter::Peekable<std::slice::Iter<'a, FileExtent>>,
+    position: u64,
+}
+
+impl<'a> SparseWriter<'a> {
+    pub fn new(file: &'a mut File, holes: &'a [FileExtent]) -> Self {
+        Self {
+            file,
+            holes: holes.iter().peekable(),
+            position: 0,
+        }
+    }
+
+    /// Write the next bytes of data, skipping over any holes first
+    pub async fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            self.skip_holes().await?;
+
+            let until_hole = self
+                .holes
+                .peek()
+                .map_or(u64::MAX, |hole| hole.offset - self.position);
+            let len = (data.len() as u64).min(until_hole) as usize;
+            self.file.write_all(&data[..len]).await?;
+            self.position += len as u64;
+            data = &data[len..];
+        }
+
+        Ok(())
+    }
+
+    /// Extend the file over trailing holes to its full `size`
+    pub async fn finish(self, size: u64) -> io::Result<()> {
+        self.file.flush().await?;
+        self.file.set_len(size).await
+    }
+
+    async fn skip_holes(&mut self) -> io::Result<()> {
+        let start = self.position;
+        while let Some(hole) = self.holes.next_if(|hole| hole.offset <= self.position) {
+            self.position = self.position.max(hole.offset + hole.length);
+        }
+        if self.position != start {
+            self.file.seek(SeekFrom::Start(self.position)).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use tokio::io::AsyncReadExt;
+
+    fn extent(offset: u64, length: u64) -> FileExtent {
+        FileExtent { offset, length }
+    }
+
+    #[test]
+    fn test_data_extents() {
+        let holes = [extent(0, 10), extent(20, 5)];
+        assert_eq!(data_extents(&holes, 40), vec![extent(10, 10), extent(25, 15)]);
+        assert_eq!(data_extents(&holes, 25), vec![extent(10, 10)]);
+        assert_eq!(data_extents(&[], 7), vec![extent(0, 7)]);
+        assert_eq!(hole_bytes(&holes), 15);
+    }
+
+    #[tokio::test]
+    async fn test_sparse_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source");
+        let target = temp_dir.path().join("target");
+
+        // Data at 1 MiB and 3 MiB, with holes before, between and after
+        let size = 8 << 20;
+        {
+            let mut file = File::create(&source).await.unwrap();
+            file.set_len(size).await.unwrap();
+            file.seek(SeekFrom::Start(1 << 20)).await.unwrap();
+            file.write_all(&[1; 4096]).await.unwrap();
+            file.seek(SeekFrom::Start(3 << 20)).await.unwrap();
+            file.write_all(&[2; 4096]).await.unwrap();
+            file.sync_all().await.unwrap();
+        }
+
+        let holes = find_holes(&std::fs::File::open(&source).unwrap(), size).unwrap();
+        let mut data = Vec::new();
+        ExtentReader::new(File::open(&source).await.unwrap(), data_extents(&holes, size))
+            .read_to_end(&mut data)
+            .await
+            .unwrap();
+        assert_eq!(data.len() as u64, size - hole_bytes(&holes));
+
+        // Write in odd pieces so writes straddle the holes
+        let mut file = File::create(&target).await.unwrap();
+        let mut writer = SparseWriter::new(&mut file, &holes);
+        for piece in data.chunks(1000) {
+            writer.write(piece).await.unwrap();
+        }
+        writer.finish(size).await.unwrap();
+        file.sync_all().await.unwrap();
+        drop(file);
+
+        // Compared without assert_eq, which would print megabytes on failure
+        assert!(std::fs::read(&target).unwrap() == std::fs::read(&source).unwrap());
+        // Filesystems without hole support report none, leaving nothing to check
+        if !holes.is_empty() {
+            let restored = find_holes(&std::fs::File::open(&target).unwrap(), size).unwrap();
+            assert_eq!(hole_bytes(&restored), hole_bytes(&holes));
+        }
+    }
+}
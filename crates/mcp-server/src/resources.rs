@@ -90,8 +90,9 @@ async fn read_storage_stats(agent: &Option<BackupAgent>) -> Result<Vec<Value>> {
                 "new_chunks": stats.new_chunks,
                 "reused_chunks": stats.reused_chunks,
                 "compressed_bytes": stats.compressed_bytes,
+                "stored_bytes": stats.stored_bytes,
                 "deduplication_ratio": if stats.total_bytes > 0 {
-                    ((stats.total_bytes - stats.compressed_bytes) as f64 / stats.total_bytes as f64) * 100.0
+                    (stats.total_bytes.saturating_sub(stats.stored_bytes) as f64 / stats.total_bytes as f64) * 100.0
                 } else {
                     0.0
                 }
@@ -1,6 +1,7 @@
 use anyhow::{anyhow, Result};
 use backupforge_agent::BackupAgent;
 use backupforge_common::types::{BackupJob, BackupSource};
+use backupforge_core::{CheckOptions, ReadData};
 use serde_json::{json, Value};
 use std::path::PathBuf;
 use uuid::Uuid;
@@ -20,7 +21,7 @@ pub fn get_tools_list() -> Vec<Value> {
                     "excludes": {
                         "type": "array",
                         "items": { "type": "string" },
-                        "description": "List of patterns to exclude from backup",
+                        "description": "Gitignore-style patterns to exclude from backup",
                         "default": []
                     },
                     "encryption": {
@@ -129,6 +130,11 @@ pub fn get_tools_list() -> Vec<Value> {
                     "snapshot_id": {
                         "type": "string",
                         "description": "The UUID of the snapshot to verify"
+                    },
+                    "read_data": {
+                        "type": "boolean",
+                        "description": "Download and verify every chunk, not only check that they exist",
+                        "default": true
                     }
                 },
                 "required": ["snapshot_id"]
@@ -200,6 +206,7 @@ async fn backup_directory(arguments: &Value, agent: &Option<BackupAgent>) -> Res
         source: BackupSource::LocalPath {
             path: source_path.to_string(),
             excludes,
+            exclude_options: Default::default(),
         },
         destination: "/var/lib/backupforge/storage".to_string(),
         schedule: None,
@@ -207,18 +214,19 @@ async fn backup_directory(arguments: &Value, agent: &Option<BackupAgent>) -> Res
         enabled: true,
         encryption_enabled: encryption,
         compression_level,
+        retention: None,
     };
 
     match agent.run_job(&job).await {
         Ok(snapshot) => Ok(vec![json!({
             "type": "text",
             "text": format!(
-                "✅ Backup completed successfully!\n\nSnapshot ID: {}\nFiles: {}\nTotal Size: {} bytes\nCompressed: {} bytes ({:.1}% ratio)\nCreated: {}",
+                "✅ Backup completed successfully!\n\nSnapshot ID: {}\nFiles: {}\nTotal Size: {} bytes\nNew Data: {} bytes ({} bytes compressed)\nCreated: {}",
                 snapshot.id.0,
                 snapshot.file_count,
                 snapshot.total_size,
+                snapshot.stats.as_ref().map_or(0, |s| s.new_bytes),
                 snapshot.compressed_size,
-                (snapshot.compressed_size as f64 / snapshot.total_size as f64) * 100.0,
                 snapshot.created_at
             )
         })]),
@@ -278,7 +286,7 @@ async fn get_storage_stats(arguments: &Value, agent: &Option<BackupAgent>) -> Re
                 stats.reused_chunks,
                 stats.compressed_bytes,
                 if stats.total_bytes > 0 {
-                    ((stats.total_bytes - stats.compressed_bytes) as f64 / stats.total_bytes as f64) * 100.0
+                    (stats.total_bytes.saturating_sub(stats.stored_bytes) as f64 / stats.total_bytes as f64) * 100.0
                 } else {
                     0.0
                 }
@@ -305,14 +313,35 @@ async fn create_backup_job(arguments: &Value, agent: &Option<BackupAgent>) -> Re
 }
 
 async fn verify_backup(arguments: &Value, agent: &Option<BackupAgent>) -> Result<Vec<Value>> {
-    let _agent = agent.as_ref().ok_or_else(|| anyhow!("Agent not initialized"))?;
+    let agent = agent.as_ref().ok_or_else(|| anyhow!("Agent not initialized"))?;
     let snapshot_id = arguments["snapshot_id"]
         .as_str()
         .ok_or_else(|| anyhow!("snapshot_id required"))?;
+    let read_data = arguments["read_data"].as_bool().unwrap_or(true);
+
+    let snapshot = agent.find_snapshot(snapshot_id).await?;
+    let options = CheckOptions {
+        read_data: if read_data { ReadData::All } else { ReadData::None },
+        snapshots: vec![snapshot.id.clone()],
+    };
+    let report = agent.check(&options).await?;
+
+    let summary = if report.is_ok() {
+        format!(
+            "✅ Snapshot {} is intact: {} chunks referenced, {} read and verified",
+            snapshot.id.0, report.referenced_chunks, report.chunks_read
+        )
+    } else {
+        format!(
+            "❌ Snapshot {} has {} problems",
+            snapshot.id.0,
+            report.problems.len()
+        )
+    };
 
     Ok(vec![json!({
         "type": "text",
-        "text": format!("Verification for snapshot {}\n\nThis feature will be available in the next release.", snapshot_id)
+        "text": format!("{}\n\n{}", summary, serde_json::to_string_pretty(&report)?)
     })])
 }
 
@@ -37,6 +37,7 @@ pub fn create_router(state: AppState) -> Router {
 
         // Storage
         .route("/api/storage/stats", get(handlers::storage::get_stats))
+        .route("/api/storage/prune", post(handlers::storage::prune))
 
         // Tenants (multi-tenancy)
         .route("/api/tenants", get(handlers::tenants::list_tenants))
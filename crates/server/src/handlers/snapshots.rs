@@ -3,7 +3,7 @@ use axum::{
     http::StatusCode,
     Json,
 };
-use backupforge_common::types::Snapshot;
+use backupforge_common::{types::Snapshot, Error};
 use serde::Serialize;
 
 use crate::state::AppState;
@@ -14,21 +14,62 @@ pub struct SnapshotsListResponse {
 }
 
 pub async fn list_snapshots(
-    State(_state): State<AppState>,
+    State(state): State<AppState>,
 ) -> Result<Json<SnapshotsListResponse>, StatusCode> {
-    Ok(Json(SnapshotsListResponse { snapshots: vec![] }))
+    let agent_lock = state.agent.read().await;
+
+    if let Some(ref agent) = *agent_lock {
+        let snapshots = agent
+            .list_snapshots()
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        Ok(Json(SnapshotsListResponse { snapshots }))
+    } else {
+        Err(StatusCode::SERVICE_UNAVAILABLE)
+    }
 }
 
 pub async fn get_snapshot(
-    State(_state): State<AppState>,
+    State(state): State<AppState>,
     Path(id): Path<String>,
 ) -> Result<Json<Snapshot>, StatusCode> {
-    Err(StatusCode::NOT_FOUND)
+    let agent_lock = state.agent.read().await;
+
+    if let Some(ref agent) = *agent_lock {
+        let snapshot = agent.find_snapshot(&id).await.map_err(|e| match e {
+            Error::SnapshotNotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+
+        Ok(Json(snapshot))
+    } else {
+        Err(StatusCode::SERVICE_UNAVAILABLE)
+    }
 }
 
+/// Remove a snapshot; its chunks are reclaimed by the next prune
 pub async fn delete_snapshot(
-    State(_state): State<AppState>,
+    State(state): State<AppState>,
     Path(id): Path<String>,
 ) -> Result<StatusCode, StatusCode> {
-    Ok(StatusCode::NO_CONTENT)
+    let agent_lock = state.agent.read().await;
+
+    if let Some(ref agent) = *agent_lock {
+        let snapshot = agent.find_snapshot(&id).await.map_err(|e| match e {
+            Error::SnapshotNotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+        agent
+            .delete_snapshot(&snapshot.id)
+            .await
+            .map_err(|e| match e {
+                Error::RepositoryLocked(_) => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            })?;
+
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(StatusCode::SERVICE_UNAVAILABLE)
+    }
 }
@@ -1,5 +1,7 @@
 use axum::{extract::State, http::StatusCode, Json};
-use backupforge_storage::StorageStats;
+use backupforge_common::Error;
+use backupforge_storage::{StorageStats, SweepStats};
+use serde::{Deserialize, Serialize};
 
 use crate::state::AppState;
 
@@ -20,3 +22,50 @@ pub async fn get_stats(
         Err(StatusCode::SERVICE_UNAVAILABLE)
     }
 }
+
+#[derive(Deserialize)]
+pub struct PruneRequest {
+    /// Data written this recently is left alone
+    #[serde(default = "default_grace_period_hours")]
+    grace_period_hours: u32,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+fn default_grace_period_hours() -> u32 {
+    24
+}
+
+#[derive(Serialize)]
+pub struct PruneResponse {
+    snapshots: usize,
+    referenced_chunks: usize,
+    #[serde(flatten)]
+    sweep: SweepStats,
+}
+
+pub async fn prune(
+    State(state): State<AppState>,
+    Json(request): Json<PruneRequest>,
+) -> Result<Json<PruneResponse>, StatusCode> {
+    let agent_lock = state.agent.read().await;
+
+    if let Some(ref agent) = *agent_lock {
+        let grace_period = chrono::Duration::hours(i64::from(request.grace_period_hours));
+        let report = agent
+            .prune(grace_period, request.dry_run)
+            .await
+            .map_err(|e| match e {
+                Error::RepositoryLocked(_) => StatusCode::CONFLICT,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            })?;
+
+        Ok(Json(PruneResponse {
+            snapshots: report.snapshots,
+            referenced_chunks: report.referenced_chunks,
+            sweep: report.sweep,
+        }))
+    } else {
+        Err(StatusCode::SERVICE_UNAVAILABLE)
+    }
+}
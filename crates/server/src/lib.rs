@@ -5,4 +5,4 @@ pub mod handlers;
 pub mod state;
 
 pub use api::create_router;
-pub use state::AppState;
+pub use state::{AppState, ServerConfig};
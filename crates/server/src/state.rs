@@ -15,8 +15,14 @@ impl AppState {
     pub async fn new(config: ServerConfig) -> anyhow::Result<Self> {
         let agent = if let Some(ref storage_config) = config.default_storage {
             let backup_config = BackupConfig::default();
-            let agent = BackupAgent::new(backup_config, storage_config.clone()).await?;
-            Some(agent)
+            match BackupAgent::new(backup_config, storage_config.clone()).await {
+                Ok(agent) => Some(agent),
+                Err(e) => {
+                    // Serve the API anyway; repository endpoints report unavailable
+                    tracing::warn!("Default repository unavailable: {}", e);
+                    None
+                }
+            }
         } else {
             None
         };
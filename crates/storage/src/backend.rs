@@ -1,6 +1,8 @@
 use async_trait::async_trait;
 use backupforge_common::{types::ChunkId, Error, Result};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 
 /// Storage backend configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,9 +34,29 @@ pub trait StorageBackend: Send + Sync {
     /// Retrieve a chunk
     async fn get_chunk(&self, chunk_id: &ChunkId) -> Result<Vec<u8>>;
 
+    /// Retrieve `length` bytes of a chunk starting at `offset`
+    async fn get_chunk_range(
+        &self,
+        chunk_id: &ChunkId,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>> {
+        let data = self.get_chunk(chunk_id).await?;
+        slice_range(&data, chunk_id, offset, length).map(<[u8]>::to_vec)
+    }
+
     /// Check if a chunk exists
     async fn chunk_exists(&self, chunk_id: &ChunkId) -> Result<bool>;
 
+    /// Size and age of a stored chunk
+    async fn chunk_info(&self, chunk_id: &ChunkId) -> Result<ChunkInfo> {
+        let data = self.get_chunk(chunk_id).await?;
+        Ok(ChunkInfo {
+            size: data.len() as u64,
+            modified: None,
+        })
+    }
+
     /// Delete a chunk
     async fn delete_chunk(&self, chunk_id: &ChunkId) -> Result<()>;
 
@@ -47,8 +69,108 @@ pub trait StorageBackend: Send + Sync {
     /// Retrieve metadata
     async fn get_metadata(&self, key: &str) -> Result<Vec<u8>>;
 
+    /// Delete metadata (succeeds if the key does not exist)
+    async fn delete_metadata(&self, key: &str) -> Result<()>;
+
+    /// List metadata keys starting with `prefix` ("/" separates key segments)
+    async fn list_metadata(&self, prefix: &str) -> Result<Vec<String>>;
+
     /// Get storage statistics
     async fn stats(&self) -> Result<StorageStats>;
+
+    /// Make every chunk accepted so far durable; a no-op for backends that
+    /// write chunks immediately
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Delete every chunk outside `keep` stored before `cutoff`, or only
+    /// count them when `dry_run`
+    ///
+    /// Chunks of unknown age are kept, since they may belong to a backup
+    /// still in progress.
+    async fn sweep(
+        &self,
+        keep: &HashSet<ChunkId>,
+        cutoff: DateTime<Utc>,
+        dry_run: bool,
+    ) -> Result<SweepStats> {
+        let mut stats = SweepStats::default();
+
+        for chunk_id in self.list_chunks().await? {
+            if keep.contains(&chunk_id) {
+                continue;
+            }
+
+            let info = match self.chunk_info(&chunk_id).await {
+                Ok(info) => info,
+                // Deleted since it was listed
+                Err(Error::ChunkNotFound(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            if !info.is_older_than(cutoff) {
+                stats.recent_chunks += 1;
+                continue;
+            }
+
+            if !dry_run {
+                self.delete_chunk(&chunk_id).await?;
+            }
+            stats.removed_chunks += 1;
+            stats.reclaimed_bytes += info.size;
+        }
+
+        Ok(stats)
+    }
+
+    /// Load again whatever index the backend keeps of its chunks, picking up
+    /// objects other processes stored or deleted since it was loaded
+    async fn reload_index(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Chunks the backend still indexes although the objects holding them
+    /// are gone; always empty for backends that keep no index
+    async fn lost_chunks(&self) -> Result<Vec<ChunkId>> {
+        Ok(Vec::new())
+    }
+
+    /// Delete temporary objects abandoned by interrupted writes, returning
+    /// how many were removed; a no-op for backends that write atomically
+    async fn remove_stale_temp_files(&self) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Rebuild whatever index the backend keeps of its chunks from the
+    /// stored objects themselves; a dry run only replaces it in memory
+    ///
+    /// Accepted chunks must be flushed first.
+    async fn rebuild_index(&self, _dry_run: bool) -> Result<RebuildStats> {
+        Ok(RebuildStats {
+            loose_chunks: self.list_chunks().await?.len() as u64,
+            ..RebuildStats::default()
+        })
+    }
+}
+
+/// Bounds-checked `data[offset..offset + length]`
+pub(crate) fn slice_range<'a>(
+    data: &'a [u8],
+    chunk_id: &ChunkId,
+    offset: u64,
+    length: u64,
+) -> Result<&'a [u8]> {
+    let end = offset.checked_add(length).filter(|end| *end <= data.len() as u64);
+    match end {
+        Some(end) => Ok(&data[offset as usize..end as usize]),
+        None => Err(Error::Corruption(format!(
+            "Range {}+{} is beyond the end of {} ({} bytes)",
+            offset,
+            length,
+            chunk_id.0,
+            data.len()
+        ))),
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -57,3 +179,41 @@ pub struct StorageStats {
     pub total_bytes: u64,
     pub available_bytes: Option<u64>,
 }
+
+/// Size and modification time of a stored object
+#[derive(Debug, Clone)]
+pub struct ChunkInfo {
+    pub size: u64,
+    /// `None` when the backend cannot tell
+    pub modified: Option<DateTime<Utc>>,
+}
+
+impl ChunkInfo {
+    pub fn is_older_than(&self, cutoff: DateTime<Utc>) -> bool {
+        self.modified.is_some_and(|modified| modified < cutoff)
+    }
+}
+
+/// What a sweep removed, or would remove in a dry run
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SweepStats {
+    pub removed_chunks: u64,
+    /// Storage freed, including pack space given back by repacking
+    pub reclaimed_bytes: u64,
+    /// Packs rewritten without their unreferenced chunks
+    pub repacked_packs: u64,
+    /// Unreferenced chunks kept because they are newer than the cutoff
+    pub recent_chunks: u64,
+}
+
+/// What an index rebuild found in storage
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RebuildStats {
+    pub packs: u64,
+    /// Chunks found in the trailers of those packs
+    pub packed_chunks: u64,
+    /// Chunks stored outside packs
+    pub loose_chunks: u64,
+    /// Packs whose trailer is unreadable; their chunks are lost
+    pub damaged_packs: u64,
+}
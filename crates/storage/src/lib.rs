@@ -2,8 +2,12 @@ pub mod backend;
 pub mod local;
 pub mod s3;
 pub mod manager;
+pub mod packed;
 
-pub use backend::{StorageBackend, StorageConfig};
+pub use backend::{
+    ChunkInfo, RebuildStats, StorageBackend, StorageConfig, StorageStats, SweepStats,
+};
 pub use local::LocalStorage;
 pub use s3::S3Storage;
 pub use manager::StorageManager;
+pub use packed::{PackIndex, PackedStorage};
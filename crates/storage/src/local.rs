@@ -1,12 +1,34 @@
 use async_trait::async_trait;
-use backupforge_common::{types::ChunkId, Error, Result};
+use backupforge_common::{hash::hash_data, types::ChunkId, Error, Result};
+use chrono::{DateTime, Utc};
+use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 use tokio::fs;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use uuid::Uuid;
 
-use crate::backend::{StorageBackend, StorageStats};
+use crate::backend::{ChunkInfo, StorageBackend, StorageStats};
+
+/// Starts every chunk file, followed by the length (u64 LE) and BLAKE3 hash
+/// of the contents
+const CHUNK_MAGIC: &[u8; 8] = b"BFCHUNK1";
+
+/// Size of the magic, length and hash that start a chunk file
+const HEADER_LEN: u64 = 8 + 8 + 32;
+
+/// Suffix of files being written, renamed into place once complete
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// Age after which a temporary file is assumed to be left by a crash
+const STALE_TEMP_AGE: Duration = Duration::from_secs(60 * 60);
 
 /// Local filesystem storage backend
+///
+/// Files are written under a temporary name and renamed into place once
+/// synced, so a crash never leaves a partial file under a real name. Chunk
+/// files start with a header recording their length and hash, checked on
+/// every read.
 pub struct LocalStorage {
     base_path: PathBuf,
     chunks_path: PathBuf,
@@ -42,35 +64,253 @@ impl LocalStorage {
     fn metadata_path(&self, key: &str) -> PathBuf {
         self.metadata_path.join(key)
     }
+
+    /// Open a chunk file and check its length against its header
+    async fn open_chunk(&self, chunk_id: &ChunkId) -> Result<ChunkFile> {
+        let mut file = match fs::File::open(self.chunk_path(chunk_id)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::ChunkNotFound(chunk_id.0.clone()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let file_len = file.metadata().await?.len();
+        let mut header = None;
+        if file_len >= HEADER_LEN {
+            let mut bytes = [0u8; HEADER_LEN as usize];
+            file.read_exact(&mut bytes).await?;
+            header = Header::decode(&bytes);
+        }
+        let Some(header) = header else {
+            return Err(Error::Corruption(format!(
+                "Chunk {} has no valid header",
+                chunk_id.0
+            )));
+        };
+
+        let len = file_len - HEADER_LEN;
+        if header.length != len {
+            return Err(Error::Corruption(format!(
+                "Chunk {} holds {} bytes but was written with {}",
+                chunk_id.0, len, header.length
+            )));
+        }
+
+        Ok(ChunkFile {
+            file,
+            len,
+            hash: header.hash,
+        })
+    }
 }
 
-#[async_trait]
-impl StorageBackend for LocalStorage {
-    async fn put_chunk(&self, chunk_id: &ChunkId, data: Vec<u8>) -> Result<()> {
-        let path = self.chunk_path(chunk_id);
+/// An open chunk file; its contents follow the header
+struct ChunkFile {
+    file: fs::File,
+    len: u64,
+    /// Hash of the contents
+    hash: [u8; 32],
+}
 
-        // Create parent directory if needed
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent).await?;
+/// Length and hash of a chunk file's contents
+struct Header {
+    length: u64,
+    hash: [u8; 32],
+}
+
+impl Header {
+    fn new(data: &[u8]) -> Self {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&hash_data(data));
+        Self {
+            length: data.len() as u64,
+            hash,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+        header.extend_from_slice(CHUNK_MAGIC);
+        header.extend_from_slice(&self.length.to_le_bytes());
+        header.extend_from_slice(&self.hash);
+        header
+    }
+
+    /// `None` when `bytes` do not start with the magic
+    fn decode(bytes: &[u8; HEADER_LEN as usize]) -> Option<Self> {
+        if !bytes.starts_with(CHUNK_MAGIC) {
+            return None;
         }
 
-        let mut file = fs::File::create(&path).await?;
-        file.write_all(&data).await?;
+        let mut length = [0u8; 8];
+        length.copy_from_slice(&bytes[8..16]);
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&bytes[16..]);
+        Some(Self {
+            length: u64::from_le_bytes(length),
+            hash,
+        })
+    }
+}
+
+/// Write `parts` to `path` so a crash leaves either the complete file or
+/// none: write and sync a temporary file beside it, rename it into place,
+/// then sync the directory so the rename itself is durable
+async fn write_atomic(path: &Path, parts: &[&[u8]]) -> Result<()> {
+    let parent = path
+        .parent()
+        .ok_or_else(|| Error::Storage(format!("{} has no parent directory", path.display())))?;
+    fs::create_dir_all(parent).await?;
+
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(format!(".{}{}", Uuid::new_v4(), TEMP_SUFFIX));
+    let temp_path = parent.join(temp_name);
+
+    let written = async {
+        let mut file = fs::File::create(&temp_path).await?;
+        for part in parts {
+            file.write_all(part).await?;
+        }
         file.sync_all().await?;
+        fs::rename(&temp_path, path).await
+    }
+    .await;
+    if let Err(e) = written {
+        // A full disk would otherwise keep the space until the next open
+        let _ = fs::remove_file(&temp_path).await;
+        return Err(e.into());
+    }
 
-        Ok(())
+    sync_dir(parent).await
+}
+
+#[cfg(unix)]
+async fn sync_dir(dir: &Path) -> Result<()> {
+    fs::File::open(dir).await?.sync_all().await?;
+    Ok(())
+}
+
+/// Directories cannot be opened for syncing on other platforms
+#[cfg(not(unix))]
+async fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn is_temp_file(name: &std::ffi::OsStr) -> bool {
+    name.to_str()
+        .is_some_and(|name| name.ends_with(TEMP_SUFFIX))
+}
+
+/// Delete temporary files below `root` old enough that no running write
+/// can still own them, returning how many were removed
+///
+/// Entries that cannot be read are logged and skipped, so one bad file does
+/// not stop the cleanup.
+async fn remove_temp_files_below(root: &Path) -> u64 {
+    let mut removed = 0;
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::warn!("Cannot list {}: {}", dir.display(), e);
+                continue;
+            }
+        };
+
+        loop {
+            let entry = match entries.next_entry().await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) => {
+                    tracing::warn!("Cannot list {}: {}", dir.display(), e);
+                    break;
+                }
+            };
+            let metadata = match entry.metadata().await {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    tracing::warn!("Cannot read {}: {}", entry.path().display(), e);
+                    continue;
+                }
+            };
+            if metadata.is_dir() {
+                pending.push(entry.path());
+                continue;
+            }
+            if !is_temp_file(&entry.file_name()) {
+                continue;
+            }
+
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+            if age.is_some_and(|age| age > STALE_TEMP_AGE) {
+                tracing::info!(
+                    "Removing leftover temporary file {}",
+                    entry.path().display()
+                );
+                match fs::remove_file(entry.path()).await {
+                    Ok(()) => removed += 1,
+                    // Removed by another process cleaning up
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => tracing::warn!("Cannot remove {}: {}", entry.path().display(), e),
+                }
+            }
+        }
+    }
+
+    removed
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn put_chunk(&self, chunk_id: &ChunkId, data: Vec<u8>) -> Result<()> {
+        let header = Header::new(&data).encode();
+        write_atomic(&self.chunk_path(chunk_id), &[&header, &data]).await
     }
 
     async fn get_chunk(&self, chunk_id: &ChunkId) -> Result<Vec<u8>> {
-        let path = self.chunk_path(chunk_id);
+        let mut chunk = self.open_chunk(chunk_id).await?;
 
-        if !path.exists() {
-            return Err(Error::ChunkNotFound(chunk_id.0.clone()));
+        let mut data = vec![0u8; chunk.len as usize];
+        chunk.file.seek(SeekFrom::Start(HEADER_LEN)).await?;
+        chunk.file.read_exact(&mut data).await?;
+
+        if chunk.hash[..] != hash_data(&data)[..] {
+            return Err(Error::Corruption(format!(
+                "Chunk {} does not match the hash it was written with",
+                chunk_id.0
+            )));
         }
 
-        let mut file = fs::File::open(&path).await?;
-        let mut data = Vec::new();
-        file.read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    async fn get_chunk_range(
+        &self,
+        chunk_id: &ChunkId,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>> {
+        // Only the length can be checked without reading the whole file
+        let mut chunk = self.open_chunk(chunk_id).await?;
+        if offset.checked_add(length).is_none_or(|end| end > chunk.len) {
+            return Err(Error::Corruption(format!(
+                "Range {}+{} is beyond the end of {} ({} bytes)",
+                offset, length, chunk_id.0, chunk.len
+            )));
+        }
+
+        let mut data = vec![0u8; length as usize];
+        chunk
+            .file
+            .seek(SeekFrom::Start(HEADER_LEN + offset))
+            .await?;
+        chunk.file.read_exact(&mut data).await?;
 
         Ok(data)
     }
@@ -80,6 +320,16 @@ impl StorageBackend for LocalStorage {
         Ok(path.exists())
     }
 
+    async fn chunk_info(&self, chunk_id: &ChunkId) -> Result<ChunkInfo> {
+        let chunk = self.open_chunk(chunk_id).await?;
+        let metadata = chunk.file.metadata().await?;
+
+        Ok(ChunkInfo {
+            size: chunk.len,
+            modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+        })
+    }
+
     async fn delete_chunk(&self, chunk_id: &ChunkId) -> Result<()> {
         let path = self.chunk_path(chunk_id);
 
@@ -103,10 +353,16 @@ impl StorageBackend for LocalStorage {
                 let mut subdir_entries = fs::read_dir(&path).await?;
 
                 while let Some(subentry) = subdir_entries.next_entry().await? {
-                    if let Some(filename) = subentry.file_name().to_str() {
+                    let name = subentry.file_name();
+                    if is_temp_file(&name) {
+                        continue;
+                    }
+                    if let Some(filename) = name.to_str() {
                         chunks.push(ChunkId(filename.to_string()));
                     }
                 }
+            } else if is_temp_file(&entry.file_name()) {
+                continue;
             } else if let Some(filename) = entry.file_name().to_str() {
                 chunks.push(ChunkId(filename.to_string()));
             }
@@ -116,20 +372,15 @@ impl StorageBackend for LocalStorage {
     }
 
     async fn put_metadata(&self, key: &str, data: Vec<u8>) -> Result<()> {
-        let path = self.metadata_path(key);
-
-        let mut file = fs::File::create(&path).await?;
-        file.write_all(&data).await?;
-        file.sync_all().await?;
-
-        Ok(())
+        // Keys may contain "/" to group related metadata
+        write_atomic(&self.metadata_path(key), &[&data]).await
     }
 
     async fn get_metadata(&self, key: &str) -> Result<Vec<u8>> {
         let path = self.metadata_path(key);
 
         if !path.exists() {
-            return Err(Error::Unknown(format!("Metadata not found: {}", key)));
+            return Err(Error::MetadataNotFound(key.to_string()));
         }
 
         let mut file = fs::File::open(&path).await?;
@@ -139,6 +390,52 @@ impl StorageBackend for LocalStorage {
         Ok(data)
     }
 
+    async fn delete_metadata(&self, key: &str) -> Result<()> {
+        let path = self.metadata_path(key);
+
+        if path.exists() {
+            fs::remove_file(&path).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn list_metadata(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut pending = vec![self.metadata_path.clone()];
+
+        while let Some(dir) = pending.pop() {
+            let mut entries = fs::read_dir(&dir).await?;
+
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+
+                if entry.file_type().await?.is_dir() {
+                    pending.push(path);
+                    continue;
+                }
+                if is_temp_file(&entry.file_name()) {
+                    continue;
+                }
+
+                if let Ok(relative) = path.strip_prefix(&self.metadata_path) {
+                    let key = relative
+                        .components()
+                        .map(|c| c.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/");
+
+                    if key.starts_with(prefix) {
+                        keys.push(key);
+                    }
+                }
+            }
+        }
+
+        keys.sort();
+        Ok(keys)
+    }
+
     async fn stats(&self) -> Result<StorageStats> {
         let chunks = self.list_chunks().await?;
         let mut total_bytes = 0u64;
@@ -156,6 +453,11 @@ impl StorageBackend for LocalStorage {
             available_bytes: None, // Could implement by checking filesystem
         })
     }
+
+    async fn remove_stale_temp_files(&self) -> Result<u64> {
+        Ok(remove_temp_files_below(&self.chunks_path).await
+            + remove_temp_files_below(&self.metadata_path).await)
+    }
 }
 
 #[cfg(test)]
@@ -203,4 +505,113 @@ mod tests {
 
         assert_eq!(data, retrieved);
     }
+
+    #[tokio::test]
+    async fn test_list_metadata() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LocalStorage::new(temp_dir.path()).await.unwrap();
+
+        storage.put_metadata("config", b"{}".to_vec()).await.unwrap();
+        storage.put_metadata("snapshots/b", b"2".to_vec()).await.unwrap();
+        storage.put_metadata("snapshots/a", b"1".to_vec()).await.unwrap();
+
+        let keys = storage.list_metadata("snapshots/").await.unwrap();
+        assert_eq!(keys, vec!["snapshots/a", "snapshots/b"]);
+
+        let all = storage.list_metadata("").await.unwrap();
+        assert_eq!(all.len(), 3);
+
+        let missing = storage.get_metadata("snapshots/c").await;
+        assert!(matches!(missing, Err(Error::MetadataNotFound(_))));
+
+        storage.delete_metadata("snapshots/a").await.unwrap();
+        storage.delete_metadata("snapshots/a").await.unwrap();
+        let keys = storage.list_metadata("snapshots/").await.unwrap();
+        assert_eq!(keys, vec!["snapshots/b"]);
+    }
+
+    #[tokio::test]
+    async fn test_torn_and_damaged_chunks_are_detected() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LocalStorage::new(temp_dir.path()).await.unwrap();
+
+        let chunk_id = ChunkId("abcdef".to_string());
+        let data = b"some chunk contents".to_vec();
+        storage.put_chunk(&chunk_id, data.clone()).await.unwrap();
+        assert_eq!(
+            storage.chunk_info(&chunk_id).await.unwrap().size,
+            data.len() as u64
+        );
+        assert_eq!(
+            storage.get_chunk_range(&chunk_id, 5, 5).await.unwrap(),
+            b"chunk"
+        );
+        let range = storage.get_chunk_range(&chunk_id, 10, 100).await;
+        assert!(matches!(range, Err(Error::Corruption(_))));
+
+        // Cut short, as by a write that never completed
+        let path = storage.chunk_path(&chunk_id);
+        let stored = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &stored[..stored.len() - 3]).unwrap();
+        let torn = storage.get_chunk(&chunk_id).await;
+        assert!(matches!(torn, Err(Error::Corruption(_))));
+        let torn = storage.get_chunk_range(&chunk_id, 0, 1).await;
+        assert!(matches!(torn, Err(Error::Corruption(_))));
+
+        let mut flipped = stored.clone();
+        *flipped.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&path, &flipped).unwrap();
+        let damaged = storage.get_chunk(&chunk_id).await;
+        assert!(matches!(damaged, Err(Error::Corruption(_))));
+
+        // Without a header, or with a damaged one, nothing can be verified
+        std::fs::write(&path, &data).unwrap();
+        let headerless = storage.get_chunk(&chunk_id).await;
+        assert!(matches!(headerless, Err(Error::Corruption(_))));
+        let mut bad_magic = stored.clone();
+        bad_magic[0] ^= 0xff;
+        std::fs::write(&path, &bad_magic).unwrap();
+        let damaged = storage.get_chunk(&chunk_id).await;
+        assert!(matches!(damaged, Err(Error::Corruption(_))));
+    }
+
+    #[tokio::test]
+    async fn test_stale_temp_files_are_removed() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LocalStorage::new(temp_dir.path()).await.unwrap();
+        storage
+            .put_chunk(&ChunkId("abcdef".to_string()), b"data".to_vec())
+            .await
+            .unwrap();
+        storage
+            .put_metadata("snapshots/a", b"{}".to_vec())
+            .await
+            .unwrap();
+
+        let chunk_dir = storage.chunk_path(&ChunkId("abcdef".to_string()));
+        let chunk_dir = chunk_dir.parent().unwrap();
+        let stale = chunk_dir.join(format!("abcdef.{}{}", Uuid::new_v4(), TEMP_SUFFIX));
+        let fresh =
+            storage.metadata_path(&format!("snapshots/b.{}{}", Uuid::new_v4(), TEMP_SUFFIX));
+        std::fs::write(&stale, b"partial").unwrap();
+        std::fs::write(&fresh, b"partial").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&stale)
+            .unwrap()
+            .set_modified(SystemTime::now() - 2 * STALE_TEMP_AGE)
+            .unwrap();
+
+        // Files being written are never listed
+        assert_eq!(storage.list_chunks().await.unwrap().len(), 1);
+        assert_eq!(storage.list_metadata("").await.unwrap(), ["snapshots/a"]);
+
+        // Opening leaves them alone; only the abandoned one is cleaned up, as
+        // the other may belong to a running write
+        let storage = LocalStorage::new(temp_dir.path()).await.unwrap();
+        assert!(stale.exists());
+        assert_eq!(storage.remove_stale_temp_files().await.unwrap(), 1);
+        assert!(!stale.exists());
+        assert!(fresh.exists());
+    }
 }
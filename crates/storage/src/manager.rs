@@ -44,6 +44,11 @@ impl StorageManager {
         Ok(Self { backend })
     }
 
+    /// Get the underlying storage backend
+    pub fn backend(&self) -> Arc<dyn StorageBackend> {
+        self.backend.clone()
+    }
+
     pub async fn put_chunk(&self, chunk_id: &ChunkId, data: Vec<u8>) -> Result<()> {
         self.backend.put_chunk(chunk_id, data).await
     }
@@ -52,6 +57,15 @@ impl StorageManager {
         self.backend.get_chunk(chunk_id).await
     }
 
+    pub async fn get_chunk_range(
+        &self,
+        chunk_id: &ChunkId,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>> {
+        self.backend.get_chunk_range(chunk_id, offset, length).await
+    }
+
     pub async fn chunk_exists(&self, chunk_id: &ChunkId) -> Result<bool> {
         self.backend.chunk_exists(chunk_id).await
     }
@@ -72,7 +86,19 @@ impl StorageManager {
         self.backend.get_metadata(key).await
     }
 
+    pub async fn delete_metadata(&self, key: &str) -> Result<()> {
+        self.backend.delete_metadata(key).await
+    }
+
+    pub async fn list_metadata(&self, prefix: &str) -> Result<Vec<String>> {
+        self.backend.list_metadata(prefix).await
+    }
+
     pub async fn stats(&self) -> Result<StorageStats> {
         self.backend.stats().await
     }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.backend.flush().await
+    }
 }
@@ -0,0 +1,1041 @@
+use async_trait::async_trait;
+use backupforge_common::{hash::hash_data_hex, types::ChunkId, Error, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use uuid::Uuid;
+
+use crate::backend::{ChunkInfo, RebuildStats, StorageBackend, StorageStats, SweepStats};
+
+/// Metadata key prefix of the repository-wide pack index
+pub const PACK_INDEX_PREFIX: &str = "packs/";
+
+/// Default target size of a pack file
+pub const DEFAULT_PACK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Trailing magic of a pack file, after the index length
+const PACK_MAGIC: &[u8; 4] = b"BFPK";
+
+/// Index objects kept before they are merged into one
+const MAX_INDEX_FILES: usize = 32;
+
+/// Share of a pack taken by unreferenced chunks before a sweep rewrites it
+const REPACK_UNUSED_PERCENT: u64 = 20;
+
+/// Where a chunk lives inside a pack
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackEntry {
+    pub id: ChunkId,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Contents of one pack, also written at the end of the pack itself so a
+/// pack can be understood without the repository index
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PackIndex {
+    pub entries: Vec<PackEntry>,
+}
+
+impl PackIndex {
+    /// Read the index from the trailer of a pack:
+    /// chunks | index JSON | index length (u32 LE) | magic
+    pub fn from_pack(data: &[u8]) -> Result<Self> {
+        if data.len() < 8 || !data.ends_with(PACK_MAGIC) {
+            return Err(Error::Corruption("Not a pack file".to_string()));
+        }
+
+        let len_at = data.len() - 8;
+        let start = trailer_start(&data[len_at..], len_at as u64)?;
+        Self::parse(&data[start as usize..len_at], start)
+    }
+
+    /// Parse the index of a pack whose chunk data takes `data_len` bytes
+    fn parse(json: &[u8], data_len: u64) -> Result<Self> {
+        let index: PackIndex = serde_json::from_slice(json)
+            .map_err(|e| Error::Corruption(format!("Unreadable pack index: {}", e)))?;
+
+        if index
+            .entries
+            .iter()
+            .any(|e| e.offset.checked_add(e.length).is_none_or(|end| end > data_len))
+        {
+            return Err(Error::Corruption(
+                "Pack index points past the chunk data".to_string(),
+            ));
+        }
+
+        Ok(index)
+    }
+
+    fn append_to(&self, pack: &mut Vec<u8>) -> Result<()> {
+        let json = serde_json::to_vec(self)
+            .map_err(|e| Error::Serialization(format!("Failed to encode pack index: {}", e)))?;
+        let len = u32::try_from(json.len())
+            .map_err(|_| Error::Storage("Pack index is too large".to_string()))?;
+
+        pack.extend_from_slice(&json);
+        pack.extend_from_slice(&len.to_le_bytes());
+        pack.extend_from_slice(PACK_MAGIC);
+        Ok(())
+    }
+}
+
+/// Where the index JSON of a pack starts, given the length and magic that end
+/// the pack at `len_at`
+fn trailer_start(tail: &[u8], len_at: u64) -> Result<u64> {
+    let len = u32::from_le_bytes([tail[0], tail[1], tail[2], tail[3]]);
+    len_at
+        .checked_sub(u64::from(len))
+        .ok_or_else(|| Error::Corruption("Pack index length is out of range".to_string()))
+}
+
+/// Read the index from the trailer of the object `id` of `size` bytes with
+/// ranged reads, `None` when the object is not a pack
+async fn read_trailer(
+    inner: &dyn StorageBackend,
+    id: &ChunkId,
+    size: u64,
+) -> Result<Option<PackIndex>> {
+    if size < 8 {
+        return Ok(None);
+    }
+    let tail = inner.get_chunk_range(id, size - 8, 8).await?;
+    if !tail.ends_with(PACK_MAGIC) {
+        return Ok(None);
+    }
+
+    let start = trailer_start(&tail, size - 8)?;
+    let json = inner.get_chunk_range(id, start, size - 8 - start).await?;
+    PackIndex::parse(&json, start).map(Some)
+}
+
+/// One object of the repository-wide index, listing the chunks of some packs
+#[derive(Default, Serialize, Deserialize)]
+struct IndexFile {
+    /// Index objects this one replaces, when written by a consolidation
+    #[serde(default)]
+    supersedes: Vec<String>,
+    packs: HashMap<String, Vec<PackEntry>>,
+}
+
+/// Chunks accepted but not yet durable in a written and indexed pack
+#[derive(Default)]
+struct Packer {
+    /// Chunks for the next pack, in order
+    open: Vec<(ChunkId, Arc<Vec<u8>>)>,
+    open_size: u64,
+    /// Every chunk not yet written in a pack, for reads in the meantime
+    pending: HashMap<ChunkId, Arc<Vec<u8>>>,
+}
+
+#[derive(Clone)]
+struct Location {
+    pack: ChunkId,
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Default)]
+struct Index {
+    chunks: HashMap<ChunkId, Location>,
+    packs: HashMap<String, Vec<PackEntry>>,
+    /// Live index object keys
+    files: Vec<String>,
+    /// Index objects that could not be read; the packs they list are unknown
+    unreadable: Vec<String>,
+    /// Chunks were removed, so the saved index must be rewritten
+    dirty: bool,
+    /// Packs left without chunks, deleted once the index no longer names them
+    empty_packs: Vec<ChunkId>,
+    /// Packs written since the index was last saved
+    unindexed: HashMap<String, Vec<PackEntry>>,
+}
+
+/// Storage layer batching chunks into pack files on an inner backend
+///
+/// Packs are stored as chunks of the inner backend, named by the hash of
+/// their contents. Chunks are read back with ranged reads; chunks written
+/// before packing was enabled are still read directly.
+pub struct PackedStorage {
+    inner: Arc<dyn StorageBackend>,
+    pack_size: u64,
+    packer: Mutex<Packer>,
+    index: RwLock<Index>,
+    /// Serializes index writes so a consolidation never races a flush
+    index_writes: tokio::sync::Mutex<()>,
+}
+
+impl PackedStorage {
+    /// Load the pack index of `inner`; a `pack_size` of 0 disables packing
+    pub async fn open(inner: Arc<dyn StorageBackend>, pack_size: u64) -> Result<Self> {
+        let index = Index::load(inner.as_ref()).await?;
+
+        Ok(Self {
+            inner,
+            pack_size,
+            packer: Mutex::new(Packer::default()),
+            index: RwLock::new(index),
+            index_writes: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Number of pack files in the index
+    pub fn pack_count(&self) -> usize {
+        self.index.read().unwrap().packs.len()
+    }
+
+    /// Write `chunks` as one pack, putting them back in the queue on failure
+    async fn write_pack(&self, chunks: Vec<(ChunkId, Arc<Vec<u8>>)>) -> Result<()> {
+        let size: usize = chunks.iter().map(|(_, data)| data.len()).sum();
+        let (pack_id, pack, index) = encode_pack(&chunks)?;
+
+        if let Err(e) = self.inner.put_chunk(&pack_id, pack).await {
+            let mut packer = self.packer.lock().unwrap();
+            packer.open_size += size as u64;
+            let rest = std::mem::replace(&mut packer.open, chunks);
+            packer.open.extend(rest);
+            return Err(e);
+        }
+
+        // Index before dropping the pending copies so reads always find the chunk
+        {
+            let mut current = self.index.write().unwrap();
+            current.add_pack(pack_id.0.clone(), index.entries.clone());
+            current.unindexed.insert(pack_id.0, index.entries);
+        }
+
+        let mut packer = self.packer.lock().unwrap();
+        for (id, _) in &chunks {
+            packer.pending.remove(id);
+        }
+
+        Ok(())
+    }
+
+    /// Save the packs written since the last index write as a new index object
+    async fn save_index(&self) -> Result<()> {
+        let packs = std::mem::take(&mut self.index.write().unwrap().unindexed);
+        if packs.is_empty() {
+            return Ok(());
+        }
+
+        let key = format!("{}{}", PACK_INDEX_PREFIX, Uuid::new_v4());
+        let file = IndexFile {
+            supersedes: Vec::new(),
+            packs,
+        };
+
+        if let Err(e) = self.put_index_file(&key, &file).await {
+            self.index.write().unwrap().unindexed.extend(file.packs);
+            return Err(e);
+        }
+
+        self.index.write().unwrap().files.push(key);
+        Ok(())
+    }
+
+    /// Replace every index object with a single one, then delete packs
+    /// that no longer hold any chunk
+    async fn consolidate(&self) -> Result<()> {
+        let (file, empty_packs) = {
+            let index = self.index.read().unwrap();
+            let file = IndexFile {
+                supersedes: index.files.clone(),
+                packs: index.packs.clone(),
+            };
+            (file, index.empty_packs.clone())
+        };
+
+        let key = format!("{}{}", PACK_INDEX_PREFIX, Uuid::new_v4());
+        self.put_index_file(&key, &file).await?;
+
+        {
+            let mut index = self.index.write().unwrap();
+            index.files = vec![key];
+            index.dirty = false;
+            index.empty_packs.retain(|p| !empty_packs.contains(p));
+        }
+
+        for old in &file.supersedes {
+            self.inner.delete_metadata(old).await?;
+        }
+        for pack in &empty_packs {
+            self.inner.delete_chunk(pack).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Replace the in-memory index with the stored one, so packs other
+    /// writers added or deleted since it was loaded are seen; packs written
+    /// here but not yet indexed are kept
+    ///
+    /// The caller must hold `index_writes`.
+    async fn replace_index(&self) -> Result<()> {
+        // Removals only this instance knows of must be saved first
+        if self.index.read().unwrap().dirty {
+            self.consolidate().await?;
+        }
+
+        let mut loaded = Index::load(self.inner.as_ref()).await?;
+        let mut index = self.index.write().unwrap();
+        for (pack, entries) in &index.unindexed {
+            loaded.add_pack(pack.clone(), entries.clone());
+        }
+        loaded.unindexed = std::mem::take(&mut index.unindexed);
+        *index = loaded;
+
+        Ok(())
+    }
+
+    /// Rewrite `pack` with only its `live` chunks, returning the new pack's size
+    async fn repack(&self, pack: &ChunkId, live: &[PackEntry]) -> Result<u64> {
+        let mut chunks = Vec::with_capacity(live.len());
+        for entry in live {
+            let data = self
+                .inner
+                .get_chunk_range(pack, entry.offset, entry.length)
+                .await?;
+            chunks.push((entry.id.clone(), Arc::new(data)));
+        }
+
+        let (pack_id, data, pack_index) = encode_pack(&chunks)?;
+        let size = data.len() as u64;
+        self.inner.put_chunk(&pack_id, data).await?;
+
+        // The old pack goes once the index no longer names it
+        {
+            let mut index = self.index.write().unwrap();
+            index.remove_pack(pack);
+            index.add_pack(pack_id.0.clone(), pack_index.entries.clone());
+            index.unindexed.insert(pack_id.0, pack_index.entries);
+        }
+
+        Ok(size)
+    }
+
+    async fn put_index_file(&self, key: &str, file: &IndexFile) -> Result<()> {
+        let data = serde_json::to_vec(file)
+            .map_err(|e| Error::Serialization(format!("Failed to encode pack index: {}", e)))?;
+        self.inner.put_metadata(key, data).await
+    }
+}
+
+/// Lay out `chunks` as a pack, returning its ID, contents and index
+fn encode_pack(chunks: &[(ChunkId, Arc<Vec<u8>>)]) -> Result<(ChunkId, Vec<u8>, PackIndex)> {
+    let size: usize = chunks.iter().map(|(_, data)| data.len()).sum();
+    let mut pack = Vec::with_capacity(size + 64 * chunks.len());
+    let mut index = PackIndex::default();
+
+    for (id, data) in chunks {
+        index.entries.push(PackEntry {
+            id: id.clone(),
+            offset: pack.len() as u64,
+            length: data.len() as u64,
+        });
+        pack.extend_from_slice(data);
+    }
+    index.append_to(&mut pack)?;
+
+    Ok((ChunkId(hash_data_hex(&pack)), pack, index))
+}
+
+/// Size of the pack `encode_pack` would write for `entries`
+fn repacked_size(entries: &[PackEntry]) -> Result<u64> {
+    let mut index = PackIndex::default();
+    let mut offset = 0;
+    for entry in entries {
+        index.entries.push(PackEntry {
+            id: entry.id.clone(),
+            offset,
+            length: entry.length,
+        });
+        offset += entry.length;
+    }
+
+    let mut trailer = Vec::new();
+    index.append_to(&mut trailer)?;
+    Ok(offset + trailer.len() as u64)
+}
+
+/// Read the live index objects of `inner`, along with the keys of those
+/// that cannot be decoded
+async fn load_index_files(
+    inner: &dyn StorageBackend,
+) -> Result<(Vec<(String, IndexFile)>, Vec<String>)> {
+    let mut files = HashMap::new();
+    let mut unreadable = Vec::new();
+    for key in inner.list_metadata(PACK_INDEX_PREFIX).await? {
+        let data = inner.get_metadata(&key).await?;
+        match serde_json::from_slice::<IndexFile>(&data) {
+            Ok(file) => {
+                files.insert(key, file);
+            }
+            Err(_) => unreadable.push(key),
+        }
+    }
+
+    // A consolidation that crashed before deleting its inputs leaves them behind
+    let superseded: HashSet<String> = files
+        .values()
+        .flat_map(|f| f.supersedes.iter().cloned())
+        .collect();
+
+    let files = files
+        .into_iter()
+        .filter(|(key, _)| !superseded.contains(key))
+        .collect();
+    unreadable.retain(|key| !superseded.contains(key));
+
+    Ok((files, unreadable))
+}
+
+impl Index {
+    /// Read every index object of `inner`
+    async fn load(inner: &dyn StorageBackend) -> Result<Self> {
+        let mut index = Self::default();
+        let (files, unreadable) = load_index_files(inner).await?;
+        for (key, file) in files {
+            for (pack, entries) in file.packs {
+                index.add_pack(pack, entries);
+            }
+            index.files.push(key);
+        }
+        for key in &unreadable {
+            tracing::warn!(
+                "Pack index {} is unreadable, run `backupforge repair` to rebuild it",
+                key
+            );
+        }
+        index.unreadable = unreadable;
+
+        Ok(index)
+    }
+
+    /// Forget `pack` and the chunks it holds, marking it for deletion
+    fn remove_pack(&mut self, pack: &ChunkId) {
+        for entry in self.packs.remove(&pack.0).unwrap_or_default() {
+            if self.chunks.get(&entry.id).is_some_and(|at| at.pack == *pack) {
+                self.chunks.remove(&entry.id);
+            }
+        }
+        self.empty_packs.push(pack.clone());
+        self.dirty = true;
+    }
+
+    fn add_pack(&mut self, pack: String, entries: Vec<PackEntry>) {
+        for entry in &entries {
+            self.chunks.insert(
+                entry.id.clone(),
+                Location {
+                    pack: ChunkId(pack.clone()),
+                    offset: entry.offset,
+                    length: entry.length,
+                },
+            );
+        }
+        self.packs.insert(pack, entries);
+    }
+}
+
+#[async_trait]
+impl StorageBackend for PackedStorage {
+    async fn put_chunk(&self, chunk_id: &ChunkId, data: Vec<u8>) -> Result<()> {
+        if self.pack_size == 0 {
+            return self.inner.put_chunk(chunk_id, data).await;
+        }
+
+        let full = {
+            let mut packer = self.packer.lock().unwrap();
+            if packer.pending.contains_key(chunk_id)
+                || self.index.read().unwrap().chunks.contains_key(chunk_id)
+            {
+                return Ok(());
+            }
+
+            let data = Arc::new(data);
+            packer.open_size += data.len() as u64;
+            packer.pending.insert(chunk_id.clone(), data.clone());
+            packer.open.push((chunk_id.clone(), data));
+
+            if packer.open_size >= self.pack_size {
+                packer.open_size = 0;
+                Some(std::mem::take(&mut packer.open))
+            } else {
+                None
+            }
+        };
+
+        match full {
+            Some(chunks) => self.write_pack(chunks).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn get_chunk(&self, chunk_id: &ChunkId) -> Result<Vec<u8>> {
+        if let Some(data) = self.packer.lock().unwrap().pending.get(chunk_id) {
+            return Ok(data.as_ref().clone());
+        }
+
+        let location = self.index.read().unwrap().chunks.get(chunk_id).cloned();
+        match location {
+            Some(at) => {
+                self.inner
+                    .get_chunk_range(&at.pack, at.offset, at.length)
+                    .await
+            }
+            // Stored before packing, or with packing disabled
+            None => self.inner.get_chunk(chunk_id).await,
+        }
+    }
+
+    async fn chunk_exists(&self, chunk_id: &ChunkId) -> Result<bool> {
+        if self.packer.lock().unwrap().pending.contains_key(chunk_id)
+            || self.index.read().unwrap().chunks.contains_key(chunk_id)
+        {
+            return Ok(true);
+        }
+
+        self.inner.chunk_exists(chunk_id).await
+    }
+
+    async fn chunk_info(&self, chunk_id: &ChunkId) -> Result<ChunkInfo> {
+        if let Some(data) = self.packer.lock().unwrap().pending.get(chunk_id) {
+            return Ok(ChunkInfo {
+                size: data.len() as u64,
+                modified: Some(Utc::now()),
+            });
+        }
+
+        let location = self.index.read().unwrap().chunks.get(chunk_id).cloned();
+        match location {
+            Some(at) => {
+                let pack = self.inner.chunk_info(&at.pack).await?;
+                Ok(ChunkInfo {
+                    size: at.length,
+                    modified: pack.modified,
+                })
+            }
+            None => self.inner.chunk_info(chunk_id).await,
+        }
+    }
+
+    /// Packed chunks are dropped from the index; their bytes stay in the pack
+    /// until every chunk in it is gone. Takes effect on disk at the next flush.
+    async fn delete_chunk(&self, chunk_id: &ChunkId) -> Result<()> {
+        {
+            let mut packer = self.packer.lock().unwrap();
+            if packer.pending.remove(chunk_id).is_some() {
+                let before = packer.open.len();
+                packer.open.retain(|(id, _)| id != chunk_id);
+                if packer.open.len() < before {
+                    packer.open_size = packer.open.iter().map(|(_, d)| d.len() as u64).sum();
+                }
+                return Ok(());
+            }
+        }
+
+        {
+            let mut index = self.index.write().unwrap();
+            if let Some(at) = index.chunks.remove(chunk_id) {
+                let entries = index.packs.entry(at.pack.0.clone()).or_default();
+                entries.retain(|e| e.id != *chunk_id);
+                if entries.is_empty() {
+                    index.packs.remove(&at.pack.0);
+                    index.empty_packs.push(at.pack);
+                }
+                index.dirty = true;
+                return Ok(());
+            }
+        }
+
+        self.inner.delete_chunk(chunk_id).await
+    }
+
+    async fn list_chunks(&self) -> Result<Vec<ChunkId>> {
+        let loose = self.inner.list_chunks().await?;
+
+        let index = self.index.read().unwrap();
+        let packer = self.packer.lock().unwrap();
+
+        let mut chunks: Vec<ChunkId> = index.chunks.keys().cloned().collect();
+        chunks.extend(packer.pending.keys().cloned());
+        chunks.extend(
+            loose
+                .into_iter()
+                .filter(|id| !index.packs.contains_key(&id.0) && !index.empty_packs.contains(id)),
+        );
+
+        Ok(chunks)
+    }
+
+    async fn put_metadata(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        self.inner.put_metadata(key, data).await
+    }
+
+    async fn get_metadata(&self, key: &str) -> Result<Vec<u8>> {
+        self.inner.get_metadata(key).await
+    }
+
+    async fn delete_metadata(&self, key: &str) -> Result<()> {
+        self.inner.delete_metadata(key).await
+    }
+
+    async fn list_metadata(&self, prefix: &str) -> Result<Vec<String>> {
+        self.inner.list_metadata(prefix).await
+    }
+
+    async fn stats(&self) -> Result<StorageStats> {
+        let mut stats = self.inner.stats().await?;
+
+        let index = self.index.read().unwrap();
+        let pending = self.packer.lock().unwrap().pending.len() as u64;
+        stats.total_chunks = stats.total_chunks.saturating_sub(index.packs.len() as u64)
+            + index.chunks.len() as u64
+            + pending;
+
+        Ok(stats)
+    }
+
+    /// Write the partly filled pack and save the index of every new pack
+    async fn flush(&self) -> Result<()> {
+        let _writes = self.index_writes.lock().await;
+
+        let open = {
+            let mut packer = self.packer.lock().unwrap();
+            packer.open_size = 0;
+            std::mem::take(&mut packer.open)
+        };
+        if !open.is_empty() {
+            self.write_pack(open).await?;
+        }
+
+        self.save_index().await?;
+
+        let needs_rewrite = {
+            let index = self.index.read().unwrap();
+            index.dirty || index.files.len() > MAX_INDEX_FILES
+        };
+        if needs_rewrite {
+            self.consolidate().await?;
+        }
+
+        self.inner.flush().await
+    }
+
+    async fn reload_index(&self) -> Result<()> {
+        let _writes = self.index_writes.lock().await;
+        self.replace_index().await
+    }
+
+    /// Chunks located in packs the inner backend no longer holds
+    async fn lost_chunks(&self) -> Result<Vec<ChunkId>> {
+        let stored: HashSet<ChunkId> = self.inner.list_chunks().await?.into_iter().collect();
+
+        let index = self.index.read().unwrap();
+        Ok(index
+            .chunks
+            .iter()
+            .filter(|(_, location)| !stored.contains(&location.pack))
+            .map(|(chunk_id, _)| chunk_id.clone())
+            .collect())
+    }
+
+    async fn remove_stale_temp_files(&self) -> Result<u64> {
+        self.inner.remove_stale_temp_files().await
+    }
+
+    /// Sweep whole packs: a pack older than `cutoff` is deleted once none of
+    /// its chunks are kept, and rewritten without the others once they take
+    /// up `REPACK_UNUSED_PERCENT` of it. Smaller leftovers stay indexed, so
+    /// later backups can still reuse them. Chunks stored outside packs are
+    /// swept one by one.
+    ///
+    /// Accepted chunks must be flushed first; those still pending are kept.
+    async fn sweep(
+        &self,
+        keep: &HashSet<ChunkId>,
+        cutoff: DateTime<Utc>,
+        dry_run: bool,
+    ) -> Result<SweepStats> {
+        let _writes = self.index_writes.lock().await;
+        self.replace_index().await?;
+
+        // Packs only an unreadable index names would look unreferenced
+        if let Some(key) = self.index.read().unwrap().unreadable.first() {
+            return Err(Error::Corruption(format!(
+                "Pack index {} is unreadable, run `backupforge repair` before pruning",
+                key
+            )));
+        }
+
+        let mut stats = SweepStats::default();
+        let packs: Vec<(String, Vec<PackEntry>)> = self
+            .index
+            .read()
+            .unwrap()
+            .packs
+            .iter()
+            .map(|(pack, entries)| (pack.clone(), entries.clone()))
+            .collect();
+
+        for (pack, entries) in packs {
+            let (live, unused): (Vec<PackEntry>, Vec<PackEntry>) =
+                entries.into_iter().partition(|e| keep.contains(&e.id));
+            if unused.is_empty() {
+                continue;
+            }
+
+            let pack = ChunkId(pack);
+            let info = match self.inner.chunk_info(&pack).await {
+                Ok(info) => info,
+                Err(Error::ChunkNotFound(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            if !info.is_older_than(cutoff) {
+                stats.recent_chunks += unused.len() as u64;
+                continue;
+            }
+
+            let unused_bytes: u64 = unused.iter().map(|e| e.length).sum();
+            if live.is_empty() {
+                if !dry_run {
+                    self.index.write().unwrap().remove_pack(&pack);
+                }
+                stats.reclaimed_bytes += info.size;
+            } else if unused_bytes * 100 >= info.size * REPACK_UNUSED_PERCENT {
+                let new_size = if dry_run {
+                    repacked_size(&live)?
+                } else {
+                    self.repack(&pack, &live).await?
+                };
+                stats.repacked_packs += 1;
+                stats.reclaimed_bytes += info.size.saturating_sub(new_size);
+            } else {
+                continue;
+            }
+            stats.removed_chunks += unused.len() as u64;
+        }
+
+        // Loose chunks, and packs no index names yet
+        for chunk_id in self.inner.list_chunks().await? {
+            if keep.contains(&chunk_id) {
+                continue;
+            }
+            {
+                let index = self.index.read().unwrap();
+                if index.packs.contains_key(&chunk_id.0) || index.empty_packs.contains(&chunk_id) {
+                    continue;
+                }
+            }
+
+            let info = match self.inner.chunk_info(&chunk_id).await {
+                Ok(info) => info,
+                Err(Error::ChunkNotFound(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            if !info.is_older_than(cutoff) {
+                stats.recent_chunks += 1;
+                continue;
+            }
+
+            if !dry_run {
+                self.inner.delete_chunk(&chunk_id).await?;
+            }
+            stats.removed_chunks += 1;
+            stats.reclaimed_bytes += info.size;
+        }
+
+        if !dry_run {
+            self.save_index().await?;
+            if self.index.read().unwrap().dirty {
+                self.consolidate().await?;
+            }
+            self.inner.flush().await?;
+        }
+
+        Ok(stats)
+    }
+
+    /// Index every pack found in the inner backend from its trailer, then
+    /// replace all index objects, unreadable ones included, with one listing
+    /// them. Objects that are not packs remain readable as loose chunks.
+    async fn rebuild_index(&self, dry_run: bool) -> Result<RebuildStats> {
+        let _writes = self.index_writes.lock().await;
+
+        // Listed first, so an index object written during the scan survives
+        let superseded = self.inner.list_metadata(PACK_INDEX_PREFIX).await?;
+        let empty_packs = self.index.read().unwrap().empty_packs.clone();
+
+        let mut stats = RebuildStats::default();
+        let mut rebuilt = Index::default();
+        for id in self.inner.list_chunks().await? {
+            if empty_packs.contains(&id) {
+                continue;
+            }
+            let info = match self.inner.chunk_info(&id).await {
+                Ok(info) => info,
+                Err(Error::ChunkNotFound(_)) => continue,
+                Err(e) => return Err(e),
+            };
+
+            match read_trailer(self.inner.as_ref(), &id, info.size).await {
+                Ok(Some(pack_index)) => {
+                    stats.packs += 1;
+                    stats.packed_chunks += pack_index.entries.len() as u64;
+                    rebuilt.add_pack(id.0, pack_index.entries);
+                }
+                Ok(None) => stats.loose_chunks += 1,
+                Err(Error::Corruption(e)) => {
+                    tracing::warn!("Pack {} is damaged: {}", id.0, e);
+                    stats.damaged_packs += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if dry_run {
+            // Nothing saved is replaced, so the saved objects stay live
+            let mut index = self.index.write().unwrap();
+            rebuilt.files = std::mem::take(&mut index.files);
+            rebuilt.unreadable = std::mem::take(&mut index.unreadable);
+            rebuilt.empty_packs = empty_packs;
+            rebuilt.unindexed = std::mem::take(&mut index.unindexed);
+            *index = rebuilt;
+            return Ok(stats);
+        }
+
+        let key = format!("{}{}", PACK_INDEX_PREFIX, Uuid::new_v4());
+        let file = IndexFile {
+            supersedes: superseded,
+            packs: rebuilt.packs.clone(),
+        };
+        self.put_index_file(&key, &file).await?;
+
+        rebuilt.files = vec![key];
+        *self.index.write().unwrap() = rebuilt;
+
+        for old in &file.supersedes {
+            self.inner.delete_metadata(old).await?;
+        }
+        for pack in &empty_packs {
+            self.inner.delete_chunk(pack).await?;
+        }
+        self.inner.flush().await?;
+
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LocalStorage;
+    use tempfile::TempDir;
+
+    fn chunk(i: u32) -> (ChunkId, Vec<u8>) {
+        let data = format!("chunk number {}", i).repeat(50).into_bytes();
+        (ChunkId(hash_data_hex(&data)), data)
+    }
+
+    #[tokio::test]
+    async fn test_chunks_are_packed_and_read_back() {
+        let temp_dir = TempDir::new().unwrap();
+        let inner: Arc<dyn StorageBackend> =
+            Arc::new(LocalStorage::new(temp_dir.path()).await.unwrap());
+        let packed = PackedStorage::open(inner.clone(), 4096).await.unwrap();
+
+        let chunks: Vec<_> = (0..40).map(chunk).collect();
+        for (id, data) in &chunks {
+            packed.put_chunk(id, data.clone()).await.unwrap();
+        }
+
+        // Readable before and after the final partial pack is written
+        assert_eq!(packed.get_chunk(&chunks[39].0).await.unwrap(), chunks[39].1);
+        packed.flush().await.unwrap();
+
+        let packs = inner.list_chunks().await.unwrap();
+        assert!(packs.len() < 15, "{} packs for 40 chunks", packs.len());
+        for pack in &packs {
+            let data = inner.get_chunk(pack).await.unwrap();
+            assert!(!PackIndex::from_pack(&data).unwrap().entries.is_empty());
+        }
+
+        // A fresh instance finds everything through the saved index
+        let reopened = PackedStorage::open(inner.clone(), 4096).await.unwrap();
+        assert_eq!(reopened.pack_count(), packs.len());
+        for (id, data) in &chunks {
+            assert!(reopened.chunk_exists(id).await.unwrap());
+            assert_eq!(&reopened.get_chunk(id).await.unwrap(), data);
+        }
+        assert_eq!(reopened.list_chunks().await.unwrap().len(), chunks.len());
+    }
+
+    #[tokio::test]
+    async fn test_delete_and_consolidate() {
+        let temp_dir = TempDir::new().unwrap();
+        let inner: Arc<dyn StorageBackend> =
+            Arc::new(LocalStorage::new(temp_dir.path()).await.unwrap());
+
+        // A chunk stored before packing was enabled stays readable
+        let (loose_id, loose_data) = chunk(1000);
+        inner
+            .put_chunk(&loose_id, loose_data.clone())
+            .await
+            .unwrap();
+
+        let packed = PackedStorage::open(inner.clone(), 1).await.unwrap();
+        let chunks: Vec<_> = (0..3).map(chunk).collect();
+        for (id, data) in &chunks {
+            packed.put_chunk(id, data.clone()).await.unwrap();
+            packed.flush().await.unwrap();
+        }
+        assert_eq!(
+            inner.list_metadata(PACK_INDEX_PREFIX).await.unwrap().len(),
+            3
+        );
+
+        packed.delete_chunk(&chunks[0].0).await.unwrap();
+        assert!(!packed.chunk_exists(&chunks[0].0).await.unwrap());
+        packed.flush().await.unwrap();
+
+        // One index object remains and the emptied pack is gone
+        assert_eq!(
+            inner.list_metadata(PACK_INDEX_PREFIX).await.unwrap().len(),
+            1
+        );
+        assert_eq!(inner.list_chunks().await.unwrap().len(), 3);
+
+        let reopened = PackedStorage::open(inner, 1).await.unwrap();
+        let mut listed = reopened.list_chunks().await.unwrap();
+        listed.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut expected = vec![chunks[1].0.clone(), chunks[2].0.clone(), loose_id.clone()];
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(listed, expected);
+        assert_eq!(reopened.get_chunk(&loose_id).await.unwrap(), loose_data);
+    }
+
+    #[tokio::test]
+    async fn test_sweep_deletes_and_repacks() {
+        let temp_dir = TempDir::new().unwrap();
+        let inner: Arc<dyn StorageBackend> =
+            Arc::new(LocalStorage::new(temp_dir.path()).await.unwrap());
+        let packed = PackedStorage::open(inner.clone(), DEFAULT_PACK_SIZE)
+            .await
+            .unwrap();
+
+        // Two packs of four chunks, and a chunk stored before packing
+        let chunks: Vec<_> = (0..8).map(chunk).collect();
+        for group in chunks.chunks(4) {
+            for (id, data) in group {
+                packed.put_chunk(id, data.clone()).await.unwrap();
+            }
+            packed.flush().await.unwrap();
+        }
+        let (loose_id, loose_data) = chunk(1000);
+        inner.put_chunk(&loose_id, loose_data).await.unwrap();
+
+        // Keep one chunk of the first pack and none of the second
+        let keep: HashSet<ChunkId> = [chunks[0].0.clone()].into();
+
+        // Everything is too new for a cutoff in the past
+        let past = Utc::now() - chrono::Duration::hours(1);
+        let stats = packed.sweep(&keep, past, false).await.unwrap();
+        assert_eq!(stats.removed_chunks, 0);
+        assert_eq!(stats.recent_chunks, 8);
+
+        let future = Utc::now() + chrono::Duration::hours(1);
+        let dry_run = packed.sweep(&keep, future, true).await.unwrap();
+        assert_eq!(packed.list_chunks().await.unwrap().len(), 9);
+
+        let stats = packed.sweep(&keep, future, false).await.unwrap();
+        assert_eq!(stats.removed_chunks, 8);
+        assert_eq!(stats.repacked_packs, 1);
+        assert_eq!(stats.reclaimed_bytes, dry_run.reclaimed_bytes);
+        assert!(stats.reclaimed_bytes > 0);
+
+        // Only the repacked pack remains, holding the kept chunk
+        assert_eq!(inner.list_chunks().await.unwrap().len(), 1);
+        let reopened = PackedStorage::open(inner, DEFAULT_PACK_SIZE).await.unwrap();
+        assert_eq!(reopened.list_chunks().await.unwrap(), vec![chunks[0].0.clone()]);
+        assert_eq!(reopened.get_chunk(&chunks[0].0).await.unwrap(), chunks[0].1);
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_index_from_pack_trailers() {
+        let temp_dir = TempDir::new().unwrap();
+        let inner: Arc<dyn StorageBackend> =
+            Arc::new(LocalStorage::new(temp_dir.path()).await.unwrap());
+        let packed = PackedStorage::open(inner.clone(), 4096).await.unwrap();
+
+        let chunks: Vec<_> = (0..20).map(chunk).collect();
+        for (id, data) in &chunks {
+            packed.put_chunk(id, data.clone()).await.unwrap();
+        }
+        packed.flush().await.unwrap();
+        let (loose_id, loose_data) = chunk(1000);
+        inner.put_chunk(&loose_id, loose_data).await.unwrap();
+
+        // Corrupt every index object
+        for key in inner.list_metadata(PACK_INDEX_PREFIX).await.unwrap() {
+            inner.put_metadata(&key, b"garbage".to_vec()).await.unwrap();
+        }
+
+        // Still opens, but nothing packed is found and sweeping is refused
+        let damaged = PackedStorage::open(inner.clone(), 4096).await.unwrap();
+        assert_eq!(damaged.pack_count(), 0);
+        assert!(damaged.get_chunk(&chunks[0].0).await.is_err());
+        let future = Utc::now() + chrono::Duration::hours(1);
+        assert!(damaged.sweep(&HashSet::new(), future, true).await.is_err());
+
+        let packs = inner.list_chunks().await.unwrap().len() as u64 - 1;
+        let dry_run = damaged.rebuild_index(true).await.unwrap();
+        assert_eq!(dry_run.packs, packs);
+        assert_eq!(dry_run.packed_chunks, 20);
+        assert_eq!(dry_run.loose_chunks, 1);
+        assert_eq!(damaged.get_chunk(&chunks[0].0).await.unwrap(), chunks[0].1);
+        // The corrupt objects are only replaced by a real rebuild
+        assert!(damaged.sweep(&HashSet::new(), future, true).await.is_err());
+
+        damaged.rebuild_index(false).await.unwrap();
+        assert_eq!(
+            inner.list_metadata(PACK_INDEX_PREFIX).await.unwrap().len(),
+            1
+        );
+        let reopened = PackedStorage::open(inner, 4096).await.unwrap();
+        assert_eq!(reopened.list_chunks().await.unwrap().len(), 21);
+        for (id, data) in &chunks {
+            assert_eq!(&reopened.get_chunk(id).await.unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_pack_trailer_rejects_garbage() {
+        assert!(PackIndex::from_pack(b"short").is_err());
+        assert!(PackIndex::from_pack(b"\xff\xff\xff\x7fBFPK").is_err());
+
+        let mut pack = b"abc".to_vec();
+        let index = PackIndex {
+            entries: vec![PackEntry {
+                id: ChunkId("x".to_string()),
+                offset: 0,
+                length: 3,
+            }],
+        };
+        index.append_to(&mut pack).unwrap();
+        assert_eq!(PackIndex::from_pack(&pack).unwrap().entries, index.entries);
+
+        let mut pack = b"abc".to_vec();
+        let index = PackIndex {
+            entries: vec![PackEntry {
+                id: ChunkId("x".to_string()),
+                offset: u64::MAX,
+                length: 2,
+            }],
+        };
+        index.append_to(&mut pack).unwrap();
+        assert!(PackIndex::from_pack(&pack).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use async_trait::async_trait;
 use backupforge_common::{types::ChunkId, Error, Result};
+use chrono::{DateTime, Utc};
 use rusoto_core::{Region, RusotoError};
 use rusoto_s3::{
     DeleteObjectRequest, GetObjectRequest, HeadObjectRequest, ListObjectsV2Request,
@@ -8,7 +9,7 @@ use rusoto_s3::{
 use std::str::FromStr;
 use tokio::io::AsyncReadExt;
 
-use crate::backend::{StorageBackend, StorageStats};
+use crate::backend::{ChunkInfo, StorageBackend, StorageStats};
 
 /// S3-compatible storage backend
 pub struct S3Storage {
@@ -91,8 +92,9 @@ impl StorageBackend for S3Storage {
             })?;
 
         let mut data = Vec::new();
-        if let Some(mut body) = result.body {
-            body.read_to_end(&mut data)
+        if let Some(body) = result.body {
+            body.into_async_read()
+                .read_to_end(&mut data)
                 .await
                 .map_err(|e| Error::Storage(format!("Failed to read S3 body: {}", e)))?;
         }
@@ -100,6 +102,54 @@ impl StorageBackend for S3Storage {
         Ok(data)
     }
 
+    async fn get_chunk_range(
+        &self,
+        chunk_id: &ChunkId,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>> {
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+
+        let request = GetObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.chunk_key(chunk_id),
+            range: Some(format!("bytes={}-{}", offset, offset + length - 1)),
+            ..Default::default()
+        };
+
+        let result = self
+            .client
+            .get_object(request)
+            .await
+            .map_err(|e| match e {
+                RusotoError::Service(_) => Error::ChunkNotFound(chunk_id.0.clone()),
+                _ => Error::Storage(format!("S3 ranged get failed: {}", e)),
+            })?;
+
+        let mut data = Vec::new();
+        if let Some(body) = result.body {
+            body.into_async_read()
+                .read_to_end(&mut data)
+                .await
+                .map_err(|e| Error::Storage(format!("Failed to read S3 body: {}", e)))?;
+        }
+
+        // Servers clamp ranges past the end instead of failing
+        if data.len() as u64 != length {
+            return Err(Error::Corruption(format!(
+                "Range {}+{} of {} returned {} bytes",
+                offset,
+                length,
+                chunk_id.0,
+                data.len()
+            )));
+        }
+
+        Ok(data)
+    }
+
     async fn chunk_exists(&self, chunk_id: &ChunkId) -> Result<bool> {
         let key = self.chunk_key(chunk_id);
 
@@ -116,6 +166,31 @@ impl StorageBackend for S3Storage {
         }
     }
 
+    async fn chunk_info(&self, chunk_id: &ChunkId) -> Result<ChunkInfo> {
+        let request = HeadObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.chunk_key(chunk_id),
+            ..Default::default()
+        };
+
+        let head = match self.client.head_object(request).await {
+            Ok(head) => head,
+            Err(RusotoError::Service(_)) => return Err(Error::ChunkNotFound(chunk_id.0.clone())),
+            Err(e) => return Err(Error::Storage(format!("S3 head failed: {}", e))),
+        };
+
+        // Last-Modified is an HTTP date
+        let modified = head
+            .last_modified
+            .and_then(|date| DateTime::parse_from_rfc2822(&date).ok())
+            .map(|date| date.with_timezone(&Utc));
+
+        Ok(ChunkInfo {
+            size: head.content_length.unwrap_or(0).max(0) as u64,
+            modified,
+        })
+    }
+
     async fn delete_chunk(&self, chunk_id: &ChunkId) -> Result<()> {
         let key = self.chunk_key(chunk_id);
 
@@ -203,11 +278,15 @@ impl StorageBackend for S3Storage {
             .client
             .get_object(request)
             .await
-            .map_err(|e| Error::Storage(format!("S3 get metadata failed: {}", e)))?;
+            .map_err(|e| match e {
+                RusotoError::Service(_) => Error::MetadataNotFound(key.to_string()),
+                _ => Error::Storage(format!("S3 get metadata failed: {}", e)),
+            })?;
 
         let mut data = Vec::new();
-        if let Some(mut body) = result.body {
-            body.read_to_end(&mut data)
+        if let Some(body) = result.body {
+            body.into_async_read()
+                .read_to_end(&mut data)
                 .await
                 .map_err(|e| Error::Storage(format!("Failed to read S3 metadata: {}", e)))?;
         }
@@ -215,6 +294,61 @@ impl StorageBackend for S3Storage {
         Ok(data)
     }
 
+    async fn delete_metadata(&self, key: &str) -> Result<()> {
+        let request = DeleteObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.metadata_key(key),
+            ..Default::default()
+        };
+
+        self.client
+            .delete_object(request)
+            .await
+            .map_err(|e| Error::Storage(format!("S3 delete metadata failed: {}", e)))?;
+
+        Ok(())
+    }
+
+    async fn list_metadata(&self, prefix: &str) -> Result<Vec<String>> {
+        let base = format!("{}/metadata/", self.prefix);
+        let mut keys = Vec::new();
+        let mut continuation_token = None;
+
+        loop {
+            let request = ListObjectsV2Request {
+                bucket: self.bucket.clone(),
+                prefix: Some(format!("{}{}", base, prefix)),
+                continuation_token,
+                ..Default::default()
+            };
+
+            let result = self
+                .client
+                .list_objects_v2(request)
+                .await
+                .map_err(|e| Error::Storage(format!("S3 list metadata failed: {}", e)))?;
+
+            if let Some(contents) = result.contents {
+                for object in contents {
+                    if let Some(key) = object.key {
+                        if let Some(metadata_key) = key.strip_prefix(&base) {
+                            keys.push(metadata_key.to_string());
+                        }
+                    }
+                }
+            }
+
+            if result.is_truncated == Some(true) {
+                continuation_token = result.next_continuation_token;
+            } else {
+                break;
+            }
+        }
+
+        keys.sort();
+        Ok(keys)
+    }
+
     async fn stats(&self) -> Result<StorageStats> {
         let chunks = self.list_chunks().await?;
 